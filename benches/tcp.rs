@@ -0,0 +1,88 @@
+//! TCP throughput benchmark
+//!
+//! Measures how many bytes per second Echo servers running on 1, 4, and 16
+//! worker threads send back to many simultaneous clients. Unlike the UDP
+//! benchmark, this starts its own servers (built by `cargo bench` along with
+//! the benchmark), so just run `cargo bench --bench tcp`.
+
+use std::{
+	io::{Read, Write},
+	net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+	process::{Command, Stdio},
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+	thread,
+	time::{Duration, Instant},
+};
+
+const THREADS: &[usize] = &[1, 4, 16];
+const CLIENTS: usize = 64;
+const CHUNK: usize = 16 * 1024;
+const DURATION: Duration = Duration::from_secs(5);
+
+fn main() {
+	for &threads in THREADS {
+		// Find a free port for the server, which is very unlikely to be taken
+		// again before the server binds it
+		let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+			.and_then(|listener| listener.local_addr())
+			.expect("couldn't find a free port")
+			.port();
+		let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+
+		let mut server = Command::new(env!("CARGO_BIN_EXE_simple-protocols"))
+			.args(["--log", "error", "--service", "echo", "--ip", "127.0.0.1"])
+			.args(["--port", &format!("echo={port}")])
+			.args(["--threads", &threads.to_string()])
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.expect("couldn't start the server");
+
+		let start = Instant::now();
+		while TcpStream::connect(addr).is_err() {
+			assert!(
+				start.elapsed() < Duration::from_secs(10),
+				"the server didn't start listening"
+			);
+			thread::sleep(Duration::from_millis(10));
+		}
+
+		let received = AtomicU64::new(0);
+		let done = AtomicBool::new(false);
+
+		let start = Instant::now();
+
+		thread::scope(|s| {
+			for _ in 0..CLIENTS {
+				s.spawn(|| {
+					let mut tcp = TcpStream::connect(addr).unwrap();
+					tcp.set_nodelay(true).unwrap();
+
+					let chunk = [b'A'; CHUNK];
+					let mut buf = [0; CHUNK];
+
+					while !done.load(Ordering::Relaxed) {
+						tcp.write_all(&chunk).unwrap();
+						tcp.read_exact(&mut buf).unwrap();
+						received.fetch_add(CHUNK as u64, Ordering::Relaxed);
+					}
+				});
+			}
+
+			thread::sleep(DURATION);
+			done.store(true, Ordering::Relaxed);
+		});
+
+		let elapsed = start.elapsed().as_secs_f64();
+		let received = received.into_inner();
+
+		server.kill().expect("couldn't stop the server");
+		server.wait().expect("couldn't stop the server");
+
+		println!(
+			"tcp echo ({CLIENTS} clients, threads: {threads}): {:.1} MiB/s ({received} bytes in \
+			 {elapsed:.1}s)",
+			received as f64 / elapsed / 1024.0 / 1024.0,
+		);
+	}
+}
@@ -0,0 +1,89 @@
+//! UDP throughput benchmark
+//!
+//! Measures how many Echo datagrams per second a running server answers.
+//! Start the server first (e.g. `cargo run --release -- --log error`), then run
+//! `cargo bench --bench udp`. The target address can be changed with the
+//! `SIMPLE_PROTOCOLS_BENCH_ADDR` environment variable (default `127.0.0.1:7`).
+
+use std::{
+	env,
+	io::ErrorKind,
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+	sync::atomic::{AtomicBool, AtomicU64, Ordering},
+	thread,
+	time::{Duration, Instant},
+};
+
+const CLIENTS: usize = 16;
+const IN_FLIGHT: usize = 16;
+const DURATION: Duration = Duration::from_secs(5);
+const PAYLOAD: &[u8] = b"Hello, World!";
+
+fn main() {
+	let addr: SocketAddr = env::var("SIMPLE_PROTOCOLS_BENCH_ADDR")
+		.as_deref()
+		.unwrap_or("127.0.0.1:7")
+		.parse()
+		.expect("invalid benchmark address");
+
+	let received = AtomicU64::new(0);
+	let lost = AtomicU64::new(0);
+	let done = AtomicBool::new(false);
+
+	let start = Instant::now();
+
+	thread::scope(|s| {
+		for _ in 0..CLIENTS {
+			s.spawn(|| {
+				let udp = UdpSocket::bind(if addr.is_ipv4() {
+					SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+				} else {
+					SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+				})
+				.unwrap();
+
+				udp.set_read_timeout(Some(Duration::from_millis(100)))
+					.unwrap();
+				udp.connect(addr).unwrap();
+
+				let mut buf = [0; 64];
+				let mut in_flight = 0;
+
+				while !done.load(Ordering::Relaxed) {
+					while in_flight < IN_FLIGHT {
+						udp.send(PAYLOAD).unwrap();
+						in_flight += 1;
+					}
+
+					match udp.recv(&mut buf) {
+						Ok(_) => {
+							received.fetch_add(1, Ordering::Relaxed);
+							in_flight -= 1;
+						}
+						Err(e)
+							if e.kind() == ErrorKind::WouldBlock
+								|| e.kind() == ErrorKind::TimedOut =>
+						{
+							lost.fetch_add(in_flight as u64, Ordering::Relaxed);
+							in_flight = 0;
+						}
+						Err(e) => panic!("UDP `recv` error: {e}"),
+					}
+				}
+			});
+		}
+
+		thread::sleep(DURATION);
+		done.store(true, Ordering::Relaxed);
+	});
+
+	let elapsed = start.elapsed().as_secs_f64();
+	let received = received.into_inner();
+
+	println!(
+		"udp echo ({CLIENTS} clients, {IN_FLIGHT} in flight each): {:.0} datagrams/s ({received} \
+		 answered, {} lost in {elapsed:.1}s)",
+		received as f64 / elapsed,
+		lost.into_inner(),
+	);
+}
@@ -1,14 +1,28 @@
 use std::{
+	cmp::Reverse,
+	collections::{BTreeMap, BTreeSet},
 	env,
 	error::Error,
 	fs,
 	path::{Path, PathBuf},
+	str,
+	time::UNIX_EPOCH,
 };
 
 use decancer::Options;
-use ignore::gitignore::Gitignore;
+use ignore::{
+	gitignore::Gitignore,
+	overrides::{Override, OverrideBuilder},
+};
+use miniz_oxide::deflate::compress_to_vec_zlib;
 use serde::Deserialize;
 
+// The Gopher service parses gophermaps with the same module
+#[path = "src/services/gophermap.rs"]
+mod gophermap;
+
+use gophermap::Line;
+
 #[derive(Debug, Deserialize)]
 struct UserInfo {
 	username: String,
@@ -16,6 +30,15 @@ struct UserInfo {
 	info: String,
 }
 
+/// The embedded file system as code, and the contents of its files
+struct EmbeddedFs {
+	entries: String,
+	index: String,
+	paths: String,
+	terms: String,
+	blob: Vec<u8>,
+}
+
 const QUOTE_VALID_CHARACTERS: &[u8] =
 	r#"!#"$%&'()*+,-./0123456789:;<=>?ABCDEFGHIJKLMNOPQRSTUVWXYZ_abcdefghijklmnopqrstuvwxyz~ "#
 		.as_bytes();
@@ -29,8 +52,20 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 	eprintln!("Adding file system entries");
 
-	let out_path = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("fs.rs");
-	fs::write(out_path, get_fs().as_bytes())?;
+	let EmbeddedFs {
+		entries,
+		index,
+		paths,
+		terms,
+		blob,
+	} = get_fs()?;
+
+	let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+	fs::write(out_dir.join("fs.rs"), entries.as_bytes())?;
+	fs::write(out_dir.join("fs_index.rs"), index.as_bytes())?;
+	fs::write(out_dir.join("fs_paths.rs"), paths.as_bytes())?;
+	fs::write(out_dir.join("fs_search.rs"), terms.as_bytes())?;
+	fs::write(out_dir.join("fs.blob"), blob)?;
 
 	eprintln!("Added file system entries");
 
@@ -122,17 +157,98 @@ fn asciify(s: &str) -> String {
 
 /// Get file system entries as code
 ///
-/// The returned string is a Rust literal in the format of `Entry::Directory {
-/// name: "", entries: &[Entry::File { ... }] }`
-fn get_fs() -> String {
-	fn get_fs_entries(path: &Path, ignorer: &Gitignore, is_project_root_dir: bool) -> String {
+/// The embedded directory is `SIMPLE_PROTOCOLS_EMBED_DIR` (relative to the
+/// project directory), or the project itself by default, filtered by its
+/// `.gitignore` and the comma-separated globs in
+/// `SIMPLE_PROTOCOLS_EMBED_INCLUDE` and `SIMPLE_PROTOCOLS_EMBED_EXCLUDE`. If
+/// the files are larger than `SIMPLE_PROTOCOLS_EMBED_MAX_SIZE` in total, the
+/// build fails.
+///
+/// The contents of all files are stored in a single blob, compressed in the
+/// zlib format if that makes them smaller, and the returned index has the
+/// location of each file's contents in it, as a Rust literal in the format of
+/// `[Stored { offset: 0, len: 42, compressed: true, size: 100 }, ...]`.
+///
+/// The returned paths are sorted, each with the indices of the directory
+/// entries leading to it, as a Rust literal in the format of `[("/", &[]),
+/// ("/src/fs.rs", &[3, 1]), ...]`.
+///
+/// The returned search terms are every word in the text files, sorted, with
+/// the indices of the files containing it in the paths, as a Rust literal in
+/// the format of `[("async", &[12, 40]), ...]`.
+///
+/// The returned entries are a Rust literal in the format of `Entry::Directory
+/// { name: Cow::Borrowed(""), entries: Cow::Borrowed(&[Entry::File { ... }]) }`
+fn get_fs() -> Result<EmbeddedFs, Box<dyn Error>> {
+	/// Which files to embed, and the ones embedded so far
+	struct Embedding {
+		ignorer: Gitignore,
+		overrides: Override,
+		epoch: Option<u64>,
+		/// The paths and sizes of the embedded files
+		files: Vec<(PathBuf, u64)>,
+		/// The locations of the files' contents in `blob`
+		index: Vec<String>,
+		blob: Vec<u8>,
+		/// The path of every entry, with the indices of the directory entries
+		/// leading to it
+		paths: Vec<(String, Vec<usize>)>,
+		/// Every word in the text files, with the paths of the files containing
+		/// it
+		terms: BTreeMap<String, Vec<String>>,
+		/// The paths and contents of the gophermaps
+		gophermaps: Vec<(String, Option<String>)>,
+	}
+
+	/// Get the metadata of the entry at `path` as code, with the modification
+	/// time pinned to `epoch` (and the mode normalized) if it's set
+	fn get_metadata(path: &Path, size: &str, epoch: Option<u64>) -> String {
+		let meta = fs::metadata(path).expect("can't access file system metadata");
+
+		#[cfg(unix)]
+		let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777;
+		#[cfg(not(unix))]
+		let mode = match (meta.is_dir(), meta.permissions().readonly()) {
+			(true, _) => 0o755,
+			(false, false) => 0o644,
+			(false, true) => 0o444,
+		};
+
+		let (mtime, mode) = match epoch {
+			Some(epoch) if meta.is_dir() || mode & 0o111 != 0 => (epoch, 0o755),
+			Some(epoch) => (epoch, 0o644),
+			None => (
+				meta.modified()
+					.expect("can't get modification time")
+					.duration_since(UNIX_EPOCH)
+					.map_or(0, |time| time.as_secs()),
+				mode,
+			),
+		};
+
+		format!("Metadata {{ size: {size}, mtime: {mtime}, mode: {mode:#o} }}")
+	}
+
+	fn get_fs_entries(
+		path: &Path,
+		embedding: &mut Embedding,
+		fs_path: &str,
+		route: &mut Vec<usize>,
+	) -> String {
+		embedding.paths.push((
+			if fs_path.is_empty() { "/" } else { fs_path }.to_string(),
+			route.clone(),
+		));
+
 		if fs::metadata(path)
 			.expect("can't access file system metadata")
 			.is_dir()
 		{
+			let mut child = 0;
+
 			format!(
-				r#"Entry::Directory {{ name: "{}", entries: &[{}] }}"#,
-				if is_project_root_dir {
+				r#"Entry::Directory {{ name: Cow::Borrowed("{}"), entries: Cow::Borrowed(&[{}]), meta: {} }}"#,
+				if route.is_empty() {
 					PathBuf::new()
 				} else {
 					PathBuf::from(&path.file_name().expect("file path has no file name"))
@@ -142,49 +258,307 @@ fn get_fs() -> String {
 					.expect("can't read directory")
 					.filter_map(|e| {
 						let e = e.expect("can't read directory entry");
-
-						(!ignorer
-							.matched_path_or_any_parents(
-								e.path(),
-								e.metadata()
-									.expect("can't get directory entry metadata")
-									.is_dir(),
-							)
-							.is_ignore())
-						.then(|| get_fs_entries(&e.path(), ignorer, false))
+						let is_dir = e
+							.metadata()
+							.expect("can't get directory entry metadata")
+							.is_dir();
+
+						let ignored = embedding
+							.ignorer
+							.matched_path_or_any_parents(e.path(), is_dir)
+							.is_ignore();
+						let excluded = embedding.overrides.matched(e.path(), is_dir).is_ignore();
+
+						if ignored || excluded {
+							return None;
+						}
+
+						route.push(child);
+						child += 1;
+						let entry = get_fs_entries(
+							&e.path(),
+							embedding,
+							&format!("{fs_path}/{}", e.file_name().to_string_lossy()),
+							route,
+						);
+						route.pop();
+
+						Some(entry)
 					})
 					.collect::<Vec<_>>()
-					.join(", ")
+					.join(", "),
+				get_metadata(path, "0", embedding.epoch)
 			)
 		} else {
+			println!("cargo:rerun-if-changed={}", path.display());
+
+			let contents = fs::read(path).expect("can't read file");
+			embedding
+				.files
+				.push((path.to_path_buf(), contents.len() as u64));
+
+			let compressed = compress_to_vec_zlib(&contents, 9);
+			let (stored, is_compressed) = if compressed.len() < contents.len() {
+				(&compressed[..], true)
+			} else {
+				(&contents[..], false)
+			};
+
+			let index = embedding.index.len();
+			embedding.index.push(format!(
+				"Stored {{ offset: {}, len: {}, compressed: {is_compressed}, size: {} }}",
+				embedding.blob.len(),
+				stored.len(),
+				contents.len()
+			));
+			embedding.blob.extend_from_slice(stored);
+
+			let text = is_text(&contents);
+			if path.file_name().is_some_and(|name| name == "gophermap") {
+				embedding.gophermaps.push((
+					fs_path.to_string(),
+					text.then(|| String::from_utf8_lossy(&contents).into_owned()),
+				));
+			}
+
+			if text {
+				let text = str::from_utf8(&contents).expect("text is valid UTF-8");
+				for word in words(text).collect::<BTreeSet<_>>() {
+					embedding
+						.terms
+						.entry(word)
+						.or_default()
+						.push(fs_path.to_string());
+				}
+			}
+
 			format!(
-				r##"Entry::File {{ name: "{}", contents: include_str!(r#"{}"#) }}"##,
+				r#"Entry::File {{ name: Cow::Borrowed("{}"), contents: Contents::Embedded({index}), kind: Kind::{}, meta: {} }}"#,
 				PathBuf::from(&path.file_name().expect("file path has no file name")).display(),
-				path.canonicalize()
-					.expect("can't canonicalize file path")
-					.display()
+				if text { "Text" } else { "Binary" },
+				get_metadata(path, &contents.len().to_string(), embedding.epoch)
 			)
 		}
 	}
 
-	let (ignorer, err) = Gitignore::new(
-		PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap())
-			.join("./.gitignore")
-			.canonicalize()
-			.expect("can't canonicalize .gitignore path"),
+	for var in [
+		"SOURCE_DATE_EPOCH",
+		"SIMPLE_PROTOCOLS_EMBED_DIR",
+		"SIMPLE_PROTOCOLS_EMBED_INCLUDE",
+		"SIMPLE_PROTOCOLS_EMBED_EXCLUDE",
+		"SIMPLE_PROTOCOLS_EMBED_MAX_SIZE",
+	] {
+		println!("cargo:rerun-if-env-changed={var}");
+	}
+
+	// Reproducible builds pin all timestamps
+	// (https://reproducible-builds.org/specs/source-date-epoch/)
+	let epoch = env::var("SOURCE_DATE_EPOCH").ok().map(|epoch| {
+		epoch
+			.trim()
+			.parse()
+			.expect("`SOURCE_DATE_EPOCH` isn't a number of seconds")
+	});
+
+	let root = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap())
+		.join(env::var_os("SIMPLE_PROTOCOLS_EMBED_DIR").unwrap_or_default())
+		.canonicalize()
+		.map_err(|e| format!("can't canonicalize the embedded directory: {e}"))?;
+
+	let gitignore = root.join(".gitignore");
+	let ignorer = if gitignore.exists() {
+		let (ignorer, err) = Gitignore::new(gitignore);
+
+		if let Some(err) = err {
+			eprintln!("Error instantiating .gitignore-based ignorer: {err}");
+		}
+
+		ignorer
+	} else {
+		Gitignore::empty()
+	};
+
+	// Later globs take precedence, so exclusions win over inclusions
+	let mut overrides = OverrideBuilder::new(&root);
+	for (var, prefix) in [
+		("SIMPLE_PROTOCOLS_EMBED_INCLUDE", ""),
+		("SIMPLE_PROTOCOLS_EMBED_EXCLUDE", "!"),
+	] {
+		for glob in env::var(var).unwrap_or_default().split(',') {
+			let glob = glob.trim();
+			if !glob.is_empty() {
+				overrides
+					.add(&format!("{prefix}{glob}"))
+					.map_err(|e| format!("invalid glob in `{var}`: {e}"))?;
+			}
+		}
+	}
+
+	let mut embedding = Embedding {
+		ignorer,
+		overrides: overrides.build()?,
+		epoch,
+		files: Vec::new(),
+		index: Vec::new(),
+		blob: Vec::new(),
+		paths: Vec::new(),
+		terms: BTreeMap::new(),
+		gophermaps: Vec::new(),
+	};
+
+	let fs = get_fs_entries(&root, &mut embedding, "", &mut Vec::new());
+
+	let total = embedding.files.iter().map(|(_, size)| size).sum::<u64>();
+	eprintln!(
+		"Embedding {} files with {total} bytes ({} bytes compressed)",
+		embedding.files.len(),
+		embedding.blob.len()
 	);
 
-	if let Some(err) = err {
-		eprintln!("Error instantiating .gitignore-based ignorer: {err}");
+	if let Ok(max_size) = env::var("SIMPLE_PROTOCOLS_EMBED_MAX_SIZE") {
+		let max_size = parse_size(&max_size)
+			.ok_or("`SIMPLE_PROTOCOLS_EMBED_MAX_SIZE` isn't a size (e.g. `1048576` or `16M`)")?;
+
+		if total > max_size {
+			embedding
+				.files
+				.sort_unstable_by_key(|(_, size)| Reverse(*size));
+
+			eprintln!("The largest embedded files are:");
+			for (path, size) in embedding.files.iter().take(10) {
+				let path = path.strip_prefix(&root).unwrap_or(path);
+				eprintln!("{size:>12} {}", path.display());
+			}
+
+			return Err(format!(
+				"the embedded files are {total} bytes in total, more than the {max_size} bytes \
+				 allowed by `SIMPLE_PROTOCOLS_EMBED_MAX_SIZE`"
+			)
+			.into());
+		}
 	}
 
-	get_fs_entries(
-		&PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap())
-			.canonicalize()
-			.expect("can't canonicalize project path"),
-		&ignorer,
-		true,
-	)
+	// Sorted, so entries can be looked up by a binary search
+	embedding.paths.sort_unstable();
+
+	let embedded = embedding
+		.paths
+		.iter()
+		.map(|(path, _)| path.as_str())
+		.collect::<BTreeSet<_>>();
+	for (path, map) in &embedding.gophermaps {
+		let map = map
+			.as_deref()
+			.ok_or_else(|| format!("gophermap {path} isn't text"))?;
+		check_gophermap(path, map, &embedded)?;
+	}
+	let paths = embedding
+		.paths
+		.iter()
+		.map(|(path, route)| format!("({path:?}, &{route:?})"))
+		.collect::<Vec<_>>();
+
+	let terms = embedding
+		.terms
+		.iter()
+		.map(|(word, files)| {
+			let mut files = files
+				.iter()
+				.map(|file| {
+					embedding
+						.paths
+						.binary_search_by(|(path, _)| path.cmp(file))
+						.expect("every embedded file has a path")
+				})
+				.collect::<Vec<_>>();
+			files.sort_unstable();
+
+			format!("({word:?}, &{files:?})")
+		})
+		.collect::<Vec<_>>();
+
+	Ok(EmbeddedFs {
+		entries: fs,
+		index: format!("[{}]", embedding.index.join(", ")),
+		paths: format!("[{}]", paths.join(", ")),
+		terms: format!("[{}]", terms.join(", ")),
+		blob: embedding.blob,
+	})
+}
+
+/// Check that every line of the gophermap at `path` is valid, and that its
+/// local file and directory items are embedded, like the Gopher service would
+/// read it
+///
+/// The service skips invalid lines, so a malformed gophermap fails the build
+/// instead of being served with items missing.
+fn check_gophermap(path: &str, map: &str, embedded: &BTreeSet<&str>) -> Result<(), String> {
+	let dir = &path[..path.rfind('/').unwrap_or(0)];
+
+	for (n, line) in map.lines().enumerate() {
+		let invalid = |reason: &str| format!("invalid gophermap {path} line {}: {reason}", n + 1);
+
+		let item = match gophermap::parse(line).map_err(|reason| invalid(&reason))? {
+			Line::End => break,
+			// Files and directories of this server
+			Line::Item(item)
+				if item.host.is_none()
+					&& !item.raw_selector.starts_with("URL:")
+					&& b"019gI".contains(&item.code) =>
+			{
+				item
+			}
+			_ => continue,
+		};
+
+		let selector = item.selector(dir);
+		let mut components = Vec::new();
+		for component in selector.split('/') {
+			match component {
+				"" | "." => {}
+				".." => {
+					components
+						.pop()
+						.ok_or_else(|| invalid(&format!("`{selector}` is outside the root")))?;
+				}
+				_ => components.push(component),
+			}
+		}
+
+		let target = format!("/{}", components.join("/"));
+		if !embedded.contains(target.as_str()) && !target.starts_with("/.status") {
+			return Err(invalid(&format!("`{target}` isn't embedded")));
+		}
+	}
+
+	Ok(())
+}
+
+/// Parse a size in bytes, optionally with a binary `K`, `M`, or `G` suffix
+fn parse_size(size: &str) -> Option<u64> {
+	let size = size.trim();
+	let (number, shift) = match size.char_indices().last()? {
+		(i, 'K' | 'k') => (&size[..i], 10),
+		(i, 'M' | 'm') => (&size[..i], 20),
+		(i, 'G' | 'g') => (&size[..i], 30),
+		_ => (size, 0),
+	};
+
+	number.trim().parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Whether file contents are text (valid UTF-8 without NUL bytes), in the same
+/// way as `fs::Kind::of`
+fn is_text(contents: &[u8]) -> bool {
+	str::from_utf8(contents).is_ok() && !contents.contains(&0)
+}
+
+/// Split text into lowercase words of ASCII letters and digits for the search
+/// index, in the same way as `search::words`
+fn words(text: &str) -> impl Iterator<Item = String> {
+	text.split(|c: char| !c.is_ascii_alphanumeric())
+		.filter(|word| (2..=32).contains(&word.len()))
+		.map(str::to_ascii_lowercase)
 }
 
 /// Get user information as code
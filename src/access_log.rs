@@ -0,0 +1,228 @@
+//! A structured access log, with one JSON object per connection or datagram
+//!
+//! The log is shared by all servers in the process, and written independently
+//! of the usual logging verbosity.
+
+use std::{
+	fmt::Write as _,
+	fs::OpenOptions,
+	io::{self, LineWriter, Write},
+	path::Path,
+	sync::{
+		Mutex,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::SystemTime,
+};
+
+use anyhow::{Error, anyhow};
+use log::{info, warn};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use crate::{metrics::Transport, utils::Peer};
+
+/// Where entries are written, if anywhere
+static LOG: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Whether there's a log, so entries aren't formatted for nothing
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Start writing the access log to the file at `path` (appending to it), or
+/// to standard output if `path` is `-`, or stop writing it if `None`
+pub fn open(path: Option<&Path>) -> Result<(), Error> {
+	let log: Option<Box<dyn Write + Send>> = match path {
+		None => None,
+		Some(path) if path == Path::new("-") => Some(Box::new(io::stdout())),
+		Some(path) => {
+			let file = OpenOptions::new()
+				.create(true)
+				.append(true)
+				.open(path)
+				.map_err(|e| anyhow!("couldn't open the access log {}: {e}", path.display()))?;
+
+			Some(Box::new(LineWriter::new(file)))
+		}
+	};
+
+	if let Some(path) = path {
+		info!("Writing the access log to {}", path.display());
+	}
+
+	ENABLED.store(log.is_some(), Ordering::Relaxed);
+	*LOG.lock().expect("access log lock poisoned") = log;
+
+	Ok(())
+}
+
+/// The access log entry of a single connection or datagram, written when
+/// dropped
+#[derive(Debug)]
+pub struct Entry {
+	service: &'static str,
+	transport: Transport,
+	local: Peer,
+	peer: Peer,
+	start: SystemTime,
+	/// Bytes received
+	pub bytes_in: u64,
+	/// Bytes sent
+	pub bytes_out: u64,
+	/// The kind of the first error, or `None` if everything went fine
+	outcome: Option<&'static str>,
+	/// Protocol-specific fields, in the order they were recorded
+	fields: Vec<(&'static str, String)>,
+}
+
+impl Entry {
+	/// Start an entry for a connection or datagram from `peer` to `local`
+	pub fn new(service: &'static str, transport: Transport, local: Peer, peer: Peer) -> Self {
+		Self {
+			service,
+			transport,
+			local,
+			peer,
+			start: SystemTime::now(),
+			bytes_in: 0,
+			bytes_out: 0,
+			outcome: None,
+			fields: Vec::new(),
+		}
+	}
+
+	/// Record an error of the given kind as the outcome, unless there already
+	/// was one
+	pub fn error(&mut self, kind: &'static str) {
+		self.outcome.get_or_insert(kind);
+	}
+
+	/// Record a protocol-specific field (e.g. the Gopher selector), replacing
+	/// its previous value
+	pub fn field(&mut self, key: &'static str, value: String) {
+		if !ENABLED.load(Ordering::Relaxed) {
+			return;
+		}
+
+		match self.fields.iter_mut().find(|(k, _)| *k == key) {
+			Some((_, v)) => *v = value,
+			None => self.fields.push((key, value)),
+		}
+	}
+
+	/// Format the entry as a single-line JSON object, ending at `end`
+	fn to_json(&self, end: SystemTime) -> String {
+		let time = |time: SystemTime| {
+			OffsetDateTime::from(time)
+				.format(&Rfc3339)
+				.unwrap_or_default()
+		};
+
+		let mut out = String::from("{");
+		for (key, value) in [
+			("service", self.service),
+			("transport", self.transport.name()),
+			("local", &self.local.to_string()),
+			("peer", &self.peer.to_string()),
+			("start", &time(self.start)),
+			("end", &time(end)),
+		] {
+			let _ = write!(out, "{}:{},", json_string(key), json_string(value));
+		}
+
+		let _ = write!(
+			out,
+			"\"bytes_in\":{},\"bytes_out\":{},\"outcome\":{}",
+			self.bytes_in,
+			self.bytes_out,
+			json_string(self.outcome.unwrap_or("ok"))
+		);
+
+		for (key, value) in &self.fields {
+			let _ = write!(out, ",{}:{}", json_string(key), json_string(value));
+		}
+
+		out.push('}');
+		out
+	}
+}
+
+impl Drop for Entry {
+	fn drop(&mut self) {
+		if !ENABLED.load(Ordering::Relaxed) {
+			return;
+		}
+
+		let line = self.to_json(SystemTime::now());
+
+		if let Some(log) = LOG.lock().expect("access log lock poisoned").as_mut() {
+			if let Err(e) = writeln!(log, "{line}") {
+				warn!("Couldn't write to the access log: {e}");
+			}
+		}
+	}
+}
+
+/// Quote and escape `s` as a JSON string
+fn json_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\r' => out.push_str("\\r"),
+			'\t' => out.push_str("\\t"),
+			c if c.is_control() => {
+				let _ = write!(out, "\\u{:04x}", c as u32);
+			}
+			c => out.push(c),
+		}
+	}
+
+	out.push('"');
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::{Ipv4Addr, SocketAddr},
+		time::Duration,
+	};
+
+	use super::*;
+
+	#[test]
+	fn escaping() {
+		assert_eq!(json_string("plain"), "\"plain\"");
+		assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+		assert_eq!(json_string("\r\n\t\0\u{7f}"), "\"\\r\\n\\t\\u0000\\u007f\"");
+		assert_eq!(json_string("ünïcödé"), "\"ünïcödé\"");
+	}
+
+	#[test]
+	fn json() {
+		let mut entry = Entry::new(
+			"gopher",
+			Transport::Tcp,
+			Peer::Inet(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 70)),
+			Peer::Inet(SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 50000)),
+		);
+
+		entry.start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+		entry.bytes_in = 6;
+		entry.bytes_out = 1234;
+		entry.error("not_found");
+		entry.error("io");
+		entry.fields.push(("selector", "/a \"b\"".to_string()));
+
+		assert_eq!(
+			entry.to_json(entry.start + Duration::from_millis(1500)),
+			"{\"service\":\"gopher\",\"transport\":\"tcp\",\"local\":\"127.0.0.1:70\",\"peer\":\"\
+			 192.0.2.1:50000\",\"start\":\"2001-09-09T01:46:40Z\",\"end\":\"2001-09-09T01:46:41.\
+			 5Z\",\"bytes_in\":6,\"bytes_out\":1234,\"outcome\":\"not_found\",\"selector\":\"/a \
+			 \\\"b\\\"\"}"
+		);
+	}
+}
@@ -0,0 +1,152 @@
+//! The multi-threaded executor all services' tasks run on
+
+use std::{
+	cell::Cell,
+	future::{Future, pending, poll_fn},
+	panic::{self, AssertUnwindSafe},
+	pin::pin,
+	sync::{
+		Mutex,
+		atomic::{AtomicU64, AtomicUsize, Ordering},
+	},
+	thread,
+	time::{Duration, Instant},
+};
+
+use log::{debug, error};
+use smol::{Executor, Task, block_on};
+
+static EXECUTOR: Executor<'static> = Executor::new();
+
+/// The statistics of every worker thread, in the order they were started
+static WORKERS: Mutex<Vec<&'static Worker>> = Mutex::new(Vec::new());
+
+/// The number of started worker threads, so spawning doesn't need the lock
+static THREADS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+	/// The statistics of the worker thread running on this thread, if any
+	static CURRENT: Cell<Option<&'static Worker>> = const { Cell::new(None) };
+}
+
+/// Counters of the work done by a worker thread
+#[derive(Debug, Default)]
+struct Worker {
+	/// How many times tasks were polled
+	polls: AtomicU64,
+	/// Total time spent polling tasks, in nanoseconds
+	busy: AtomicU64,
+}
+
+/// Start worker threads until there are at least `threads` of them
+///
+/// Worker threads can't be stopped, so there may already be more.
+pub fn start(threads: usize) {
+	let mut workers = WORKERS.lock().expect("executor lock poisoned");
+
+	while workers.len() < threads {
+		let worker: &'static Worker = Box::leak(Box::default());
+		let id = workers.len();
+
+		let res = thread::Builder::new()
+			.name(format!("simple-protocols-{id}"))
+			.spawn(move || {
+				CURRENT.set(Some(worker));
+
+				// Panics are propagated to the tasks' handles, but keep the thread
+				// running if one escapes anyway
+				loop {
+					if panic::catch_unwind(AssertUnwindSafe(|| {
+						block_on(EXECUTOR.run(pending::<()>()))
+					}))
+					.is_err()
+					{
+						error!("Worker thread {id} panicked, restarting it");
+					}
+				}
+			});
+
+		if let Err(e) = res {
+			error!("Couldn't start worker thread {id}: {e}");
+			break;
+		}
+
+		workers.push(worker);
+	}
+
+	THREADS.store(workers.len(), Ordering::Release);
+	debug!("Running tasks on {} worker threads", workers.len());
+}
+
+/// The number of started worker threads
+pub fn threads() -> usize {
+	THREADS.load(Ordering::Acquire)
+}
+
+/// Spawn `future` on the worker threads, starting one if there aren't any yet
+pub fn spawn<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> Task<T> {
+	if threads() == 0 {
+		start(1);
+	}
+
+	EXECUTOR.spawn(async move {
+		let mut future = pin!(future);
+
+		poll_fn(|cx| {
+			let start = Instant::now();
+			let res = future.as_mut().poll(cx);
+
+			if let Some(worker) = CURRENT.get() {
+				let busy = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+				worker.polls.fetch_add(1, Ordering::Relaxed);
+				worker.busy.fetch_add(busy, Ordering::Relaxed);
+			}
+
+			res
+		})
+		.await
+	})
+}
+
+/// Get a snapshot of the counters of every worker thread, as `(thread, polls,
+/// busy)`
+pub fn stats() -> Vec<(usize, u64, Duration)> {
+	WORKERS
+		.lock()
+		.expect("executor lock poisoned")
+		.iter()
+		.enumerate()
+		.map(|(id, worker)| {
+			(
+				id,
+				worker.polls.load(Ordering::Relaxed),
+				Duration::from_nanos(worker.busy.load(Ordering::Relaxed)),
+			)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn worker_threads() {
+		start(2);
+		assert!(threads() >= 2);
+		assert!(stats().len() >= 2);
+
+		let before: u64 = stats().iter().map(|(_, polls, _)| polls).sum();
+
+		let tasks = (0..16)
+			.map(|i| spawn(async move { thread::current().name().map(|_| i) }))
+			.collect::<Vec<_>>();
+
+		for (i, task) in tasks.into_iter().enumerate() {
+			assert_eq!(block_on(task), Some(i));
+		}
+
+		let after: u64 = stats().iter().map(|(_, polls, _)| polls).sum();
+		assert!(after >= before + 16);
+	}
+}
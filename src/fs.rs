@@ -1,50 +1,395 @@
 //! A fake, read-only file system containing the source code of this project for
-//! use with file-transferring protocols (e.g. FTP, HTTP, ...)
+//! use with file-transferring protocols (e.g. FTP, HTTP, ...), or alternatively
+//! a sandboxed directory of the host
 
 use std::{
+	borrow::Cow,
 	error::Error,
 	fmt::{Display, Formatter, Result as FmtResult},
+	fs,
+	io::{self, ErrorKind, Read},
+	path::{Path, PathBuf},
+	str,
+	sync::{Arc, OnceLock},
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use anyhow::{anyhow, bail};
+use log::warn;
+use miniz_oxide::inflate::decompress_to_vec_zlib_with_limit;
+#[cfg(unix)]
+use nix::{
+	dir::Dir,
+	fcntl::{AtFlags, OFlag, openat},
+	sys::stat::{Mode, SFlag, fstatat},
+};
+use smol::unblock;
+
+use crate::status;
+
 pub const PATH_VALID_CHARACTERS: &[u8] =
 	b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ-./_";
 
-pub const FS: Entry<'static> = include!(concat!(env!("OUT_DIR"), "/fs.rs"));
+/// The largest file served from a host directory
+pub const MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The most entries listed for a directory of the host
+pub const MAX_DIRECTORY_ENTRIES: usize = 10_000;
+
+/// How much of a file of the host is looked at to tell whether it's text
+const CLASSIFY_LEN: u64 = 1024;
+
+pub static FS: Entry<'static> = include!(concat!(env!("OUT_DIR"), "/fs.rs"));
+
+/// The contents of all embedded files
+static BLOB: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/fs.blob"));
+
+/// Where the contents of each embedded file are in [`BLOB`]
+const INDEX: &[Stored] = &include!(concat!(env!("OUT_DIR"), "/fs_index.rs"));
+
+/// The normalized path of every embedded entry, sorted, with the indices of
+/// the directory entries leading to it from [`FS`]
+pub static PATHS: &[(&str, &[usize])] = &include!(concat!(env!("OUT_DIR"), "/fs_paths.rs"));
+
+/// The decompressed contents of embedded files, once they were first read
+static CACHE: [OnceLock<Box<[u8]>>; INDEX.len()] = [const { OnceLock::new() }; INDEX.len()];
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// The location of an embedded file's contents in [`BLOB`]
+#[derive(Debug)]
+struct Stored {
+	offset: usize,
+	len: usize,
+	/// Whether the contents are compressed in the zlib format (they're only
+	/// compressed if that makes them smaller)
+	compressed: bool,
+	/// The size of the contents when decompressed
+	size: usize,
+}
+
+/// The contents of a file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Contents<'e> {
+	/// Contents held in memory
+	Bytes(Cow<'e, [u8]>),
+	/// The contents of the embedded file with this index
+	Embedded(usize),
+}
+
+impl Contents<'_> {
+	/// The (decompressed) contents
+	pub fn bytes(&self) -> &[u8] {
+		match self {
+			Self::Bytes(bytes) => bytes,
+			Self::Embedded(index) => {
+				let stored = &INDEX[*index];
+				let data = &BLOB[stored.offset..][..stored.len];
+
+				if !stored.compressed {
+					return data;
+				}
+
+				CACHE[*index].get_or_init(|| {
+					decompress_to_vec_zlib_with_limit(data, stored.size)
+						.expect("the embedded file system is corrupt")
+						.into_boxed_slice()
+				})
+			}
+		}
+	}
+
+	/// The contents compressed in the zlib format (i.e. in the `deflate`
+	/// content coding of HTTP), if they're stored that way
+	pub fn deflated(&self) -> Option<&'static [u8]> {
+		match self {
+			Self::Embedded(index) if INDEX[*index].compressed => {
+				let stored = &INDEX[*index];
+				Some(&BLOB[stored.offset..][..stored.len])
+			}
+			_ => None,
+		}
+	}
+}
+
+impl From<Vec<u8>> for Contents<'_> {
+	fn from(bytes: Vec<u8>) -> Self {
+		Self::Bytes(bytes.into())
+	}
+}
+
+impl<'e> From<&'e [u8]> for Contents<'e> {
+	fn from(bytes: &'e [u8]) -> Self {
+		Self::Bytes(bytes.into())
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Entry<'e> {
 	File {
-		name: &'e str,
-		contents: &'e str,
+		name: Cow<'e, str>,
+		contents: Contents<'e>,
+		kind: Kind,
+		meta: Metadata,
 	},
 	Directory {
-		name: &'e str,
-		entries: &'e [Entry<'e>],
+		name: Cow<'e, str>,
+		entries: Cow<'e, [Entry<'e>]>,
+		meta: Metadata,
 	},
 }
 
-impl<'e> Entry<'e> {
-	pub fn name(self) -> &'e str {
+impl Entry<'_> {
+	pub fn name(&self) -> &str {
 		match self {
 			Self::Directory { name, .. } => name,
 			Self::File { name, .. } => name,
 		}
 	}
 
-	pub fn is_file(self) -> bool {
+	pub fn meta(&self) -> &Metadata {
+		match self {
+			Self::Directory { meta, .. } => meta,
+			Self::File { meta, .. } => meta,
+		}
+	}
+
+	/// Guess the media type of a file from its name and kind (`None` for
+	/// directories)
+	pub fn mime(&self) -> Option<&'static str> {
+		let Self::File { name, kind, .. } = self else {
+			return None;
+		};
+
+		let extension = name
+			.rsplit_once('.')
+			.map(|(_, ext)| ext.to_ascii_lowercase());
+		let mime = extension.and_then(|extension| {
+			MIME_TYPES
+				.iter()
+				.find(|(ext, _)| *ext == extension)
+				.map(|(_, mime)| *mime)
+		});
+
+		Some(mime.unwrap_or(match kind {
+			Kind::Text => "text/plain",
+			Kind::Binary => "application/octet-stream",
+		}))
+	}
+
+	pub fn is_file(&self) -> bool {
 		matches!(self, Self::File { .. })
 	}
 
-	pub fn is_directory(self) -> bool {
+	pub fn is_directory(&self) -> bool {
 		matches!(self, Self::Directory { .. })
 	}
 }
 
+/// Media types of files by their (lowercase) extension
+const MIME_TYPES: &[(&str, &str)] = &[
+	("bmp", "image/bmp"),
+	("css", "text/css"),
+	("gif", "image/gif"),
+	("gz", "application/gzip"),
+	("htm", "text/html"),
+	("html", "text/html"),
+	("ico", "image/vnd.microsoft.icon"),
+	("jpeg", "image/jpeg"),
+	("jpg", "image/jpeg"),
+	("js", "text/javascript"),
+	("json", "application/json"),
+	("md", "text/markdown"),
+	("pdf", "application/pdf"),
+	("png", "image/png"),
+	("rs", "text/x-rust"),
+	("svg", "image/svg+xml"),
+	("tar", "application/x-tar"),
+	("tif", "image/tiff"),
+	("tiff", "image/tiff"),
+	("toml", "application/toml"),
+	("txt", "text/plain"),
+	("webp", "image/webp"),
+	("yaml", "application/yaml"),
+	("yml", "application/yaml"),
+	("zip", "application/zip"),
+];
+
+/// Metadata of files and directories
+///
+/// For the embedded file system, this is captured by the build script, with
+/// the modification times pinned to `SOURCE_DATE_EPOCH` if it's set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+	/// The size in bytes (0 for directories)
+	pub size: u64,
+	/// The last modification time in seconds since the Unix epoch
+	pub mtime: u64,
+	/// The Unix permission bits (e.g. `0o644`)
+	pub mode: u32,
+}
+
+impl Metadata {
+	/// The last modification time
+	pub fn modified(&self) -> SystemTime {
+		UNIX_EPOCH + Duration::from_secs(self.mtime)
+	}
+}
+
+impl From<&fs::Metadata> for Metadata {
+	fn from(meta: &fs::Metadata) -> Self {
+		#[cfg(unix)]
+		let mode = std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777;
+		#[cfg(not(unix))]
+		let mode = match (meta.is_dir(), meta.permissions().readonly()) {
+			(true, _) => 0o755,
+			(false, false) => 0o644,
+			(false, true) => 0o444,
+		};
+
+		Self {
+			size: if meta.is_file() { meta.len() } else { 0 },
+			mtime: meta
+				.modified()
+				.ok()
+				.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+				.map_or(0, |time| time.as_secs()),
+			mode,
+		}
+	}
+}
+
+/// Whether a file contains text or binary data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+	/// UTF-8 text without NUL bytes
+	Text,
+	Binary,
+}
+
+impl Kind {
+	/// Classify the contents of a file, of which `data` may only be the
+	/// beginning
+	///
+	/// The build script classifies embedded files the same way.
+	pub fn of(data: &[u8]) -> Self {
+		let utf8 = match str::from_utf8(data) {
+			Ok(_) => true,
+			// The rest of the character may just be cut off
+			Err(e) => e.error_len().is_none(),
+		};
+
+		if utf8 && !data.contains(&0) {
+			Self::Text
+		} else {
+			Self::Binary
+		}
+	}
+}
+
+/// Where the files served to clients come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Root<'r> {
+	/// The file system embedded into the binary
+	#[default]
+	Embedded,
+	/// A directory of the host, of which only regular files and directories
+	/// with names made of ASCII letters, digits, `-`, `.`, and `_` are served,
+	/// and symbolic links are never followed
+	Directory(&'r HostDir),
+}
+
+impl Root<'_> {
+	/// Read the entry at the absolute `path`, after normalizing it
+	///
+	/// The virtual files of the `/.status/` directory are available in every
+	/// root, in place of anything at the same path.
+	///
+	/// Directories read from the host only list their entries: the contents
+	/// of the listed files (apart from their kind) and directories are empty
+	/// until they're read themselves.
+	///
+	/// Files of the host are read on a thread of their own, as reading them
+	/// blocks.
+	pub async fn read<'p>(&self, path: &'p [u8]) -> Result<Entry<'static>, FsError<'p>> {
+		match self {
+			Self::Embedded => read(path),
+			Self::Directory(root) => {
+				let (root, owned) = (HostDir::clone(root), path.to_vec());
+
+				unblock(move || read_host(&root, &owned).map_err(|e| e.with_path(&[])))
+					.await
+					.map_err(|e| e.with_path(path))
+			}
+		}
+	}
+}
+
+/// A directory of the host to serve files from, opened once so that it stays
+/// reachable after changing the root directory of the process
+///
+/// Clones share the opened directory, and are equal to each other.
+#[derive(Debug, Clone)]
+pub struct HostDir(Arc<HostDirInner>);
+
+#[derive(Debug)]
+struct HostDirInner {
+	path: PathBuf,
+	#[cfg(unix)]
+	dir: fs::File,
+}
+
+impl HostDir {
+	/// Open the directory at `path`
+	pub fn open(path: impl Into<PathBuf>) -> Result<Self, anyhow::Error> {
+		let path = path.into();
+
+		if !fs::metadata(&path)
+			.map_err(|e| anyhow!("couldn't access {}: {e}", path.display()))?
+			.is_dir()
+		{
+			bail!("{} is not a directory", path.display());
+		}
+
+		Ok(Self(Arc::new(HostDirInner {
+			#[cfg(unix)]
+			dir: fs::File::open(&path)
+				.map_err(|e| anyhow!("couldn't open {}: {e}", path.display()))?,
+			path,
+		})))
+	}
+
+	/// The path the directory was opened at
+	pub fn path(&self) -> &Path {
+		&self.0.path
+	}
+}
+
+impl PartialEq for HostDir {
+	fn eq(&self, other: &Self) -> bool {
+		Arc::ptr_eq(&self.0, &other.0)
+	}
+}
+
+impl Eq for HostDir {}
+
 #[derive(Debug, Clone)]
 pub enum FsError<'p> {
 	NonAbsolutePath(&'p [u8]),
 	InvalidPath(&'p [u8]),
 	NotFound(&'p [u8]),
+	/// The path leads out of the root with `..`
+	OutsideRoot(&'p [u8]),
+	/// The file is larger than 16 MiB
+	TooLarge(&'p [u8]),
+	/// The file couldn't be read (e.g. because of its permissions)
+	Unreadable(&'p [u8]),
+	/// Something is already at the path
+	Exists(&'p [u8]),
+	/// The directory can't be removed, because it has entries
+	NotEmpty(&'p [u8]),
+	/// The change would exceed the quota of an overlay
+	QuotaExceeded(&'p [u8]),
+	/// The path can't be changed
+	ReadOnly(&'p [u8]),
 }
 
 impl Display for FsError<'_> {
@@ -62,17 +407,76 @@ impl Display for FsError<'_> {
 				"File not found: '{}'",
 				String::from_utf8_lossy(path)
 			)),
+			Self::OutsideRoot(path) => f.write_fmt(format_args!(
+				"Path outside of the root: '{}'",
+				String::from_utf8_lossy(path)
+			)),
+			Self::TooLarge(path) => f.write_fmt(format_args!(
+				"File too large: '{}'",
+				String::from_utf8_lossy(path)
+			)),
+			Self::Unreadable(path) => f.write_fmt(format_args!(
+				"File unreadable: '{}'",
+				String::from_utf8_lossy(path)
+			)),
+			Self::Exists(path) => f.write_fmt(format_args!(
+				"File exists: '{}'",
+				String::from_utf8_lossy(path)
+			)),
+			Self::NotEmpty(path) => f.write_fmt(format_args!(
+				"Directory not empty: '{}'",
+				String::from_utf8_lossy(path)
+			)),
+			Self::QuotaExceeded(path) => f.write_fmt(format_args!(
+				"Quota exceeded: '{}'",
+				String::from_utf8_lossy(path)
+			)),
+			Self::ReadOnly(path) => f.write_fmt(format_args!(
+				"Read-only path: '{}'",
+				String::from_utf8_lossy(path)
+			)),
 		}
 	}
 }
 
-impl Error for FsError<'_> {}
+impl FsError<'_> {
+	/// A short name for the kind of error, for metrics
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::NonAbsolutePath(_) => "non_absolute_path",
+			Self::InvalidPath(_) => "invalid_path",
+			Self::NotFound(_) => "not_found",
+			Self::OutsideRoot(_) => "outside_root",
+			Self::TooLarge(_) => "too_large",
+			Self::Unreadable(_) => "unreadable",
+			Self::Exists(_) => "exists",
+			Self::NotEmpty(_) => "not_empty",
+			Self::QuotaExceeded(_) => "quota_exceeded",
+			Self::ReadOnly(_) => "read_only",
+		}
+	}
 
-pub fn read(path: &[u8]) -> Result<Entry<'static>, FsError<'_>> {
-	if path == b"/" {
-		return Ok(FS);
+	/// The same error, for another path
+	pub fn with_path(self, path: &[u8]) -> FsError<'_> {
+		match self {
+			Self::NonAbsolutePath(_) => FsError::NonAbsolutePath(path),
+			Self::InvalidPath(_) => FsError::InvalidPath(path),
+			Self::NotFound(_) => FsError::NotFound(path),
+			Self::OutsideRoot(_) => FsError::OutsideRoot(path),
+			Self::TooLarge(_) => FsError::TooLarge(path),
+			Self::Unreadable(_) => FsError::Unreadable(path),
+			Self::Exists(_) => FsError::Exists(path),
+			Self::NotEmpty(_) => FsError::NotEmpty(path),
+			Self::QuotaExceeded(_) => FsError::QuotaExceeded(path),
+			Self::ReadOnly(_) => FsError::ReadOnly(path),
+		}
 	}
+}
 
+impl Error for FsError<'_> {}
+
+/// Check that `path` is absolute and only contains valid characters
+fn check_path(path: &[u8]) -> Result<(), FsError<'_>> {
 	if path.iter().next() != Some(&b'/') {
 		return Err(FsError::NonAbsolutePath(path));
 	}
@@ -81,32 +485,353 @@ pub fn read(path: &[u8]) -> Result<Entry<'static>, FsError<'_>> {
 		return Err(FsError::InvalidPath(path));
 	}
 
-	let mut entry = &FS;
-	for name in path.strip_suffix(b"/").unwrap_or(path)[1..].split(|&b| b == b'/') {
-		match entry {
-			Entry::Directory { entries, .. } => {
-				entry = entries
-					.iter()
-					.find(|&e| e.name().as_bytes() == name)
-					.ok_or(FsError::NotFound(path))?;
+	Ok(())
+}
+
+/// Normalize the absolute `path` by resolving `.` and `..` and removing
+/// duplicate and trailing slashes
+///
+/// `..` can't lead out of the root. Also returns whether the path has to be a
+/// directory, because it ends with a slash (or `.` or `..`).
+pub fn normalize(path: &[u8]) -> Result<(Vec<u8>, bool), FsError<'_>> {
+	check_path(path)?;
+
+	let mut components = Vec::new();
+	let mut directory = false;
+	for component in path[1..].split(|&b| b == b'/') {
+		directory = true;
+
+		match component {
+			b"" | b"." => {}
+			b".." => {
+				components.pop().ok_or(FsError::OutsideRoot(path))?;
+			}
+			name => {
+				components.push(name);
+				directory = false;
 			}
-			Entry::File { .. } => return Err(FsError::NotFound(path)),
 		}
 	}
 
-	Ok(*entry)
+	let mut normalized = Vec::with_capacity(path.len());
+	for component in &components {
+		normalized.push(b'/');
+		normalized.extend_from_slice(component);
+	}
+	if normalized.is_empty() {
+		normalized.push(b'/');
+	}
+
+	Ok((normalized, directory))
+}
+
+/// Read the virtual entry at the `normalized` path, if it's in the
+/// [`status`] directory
+fn read_status<'p>(
+	path: &'p [u8],
+	normalized: &[u8],
+	directory: bool,
+) -> Option<Result<Entry<'static>, FsError<'p>>> {
+	if !status::contains(normalized) {
+		return None;
+	}
+
+	Some(match status::read(normalized) {
+		Some(entry) if !directory || entry.is_directory() => Ok(entry),
+		_ => Err(FsError::NotFound(path)),
+	})
+}
+
+/// Read the entry at the absolute `path` of the embedded file system, or a
+/// virtual file of the [`status`] directory
+pub fn read(path: &[u8]) -> Result<Entry<'static>, FsError<'_>> {
+	let (normalized, directory) = normalize(path)?;
+
+	if let Some(res) = read_status(path, &normalized, directory) {
+		return res;
+	}
+
+	let (_, route) = PATHS
+		.binary_search_by(|(p, _)| p.as_bytes().cmp(&normalized))
+		.map(|i| PATHS[i])
+		.map_err(|_| FsError::NotFound(path))?;
+
+	let mut entry = &FS;
+	for &i in route {
+		let Entry::Directory { entries, .. } = entry else {
+			unreachable!("only directories have entries");
+		};
+		entry = &entries[i];
+	}
+
+	if directory && !entry.is_directory() {
+		return Err(FsError::NotFound(path));
+	}
+
+	Ok(entry.clone())
 }
 
-pub fn root_entries() -> &'static [Entry<'static>] {
-	let Entry::Directory { entries, .. } = FS else {
-		unreachable!();
+/// Read the entry at `path` in the host directory `root`
+fn read_host<'p>(root: &HostDir, path: &'p [u8]) -> Result<Entry<'static>, FsError<'p>> {
+	let (normalized, directory) = normalize(path)?;
+
+	if let Some(res) = read_status(path, &normalized, directory) {
+		return res;
+	}
+
+	let mut host = root.path().to_path_buf();
+	let mut name = "";
+	let mut entry = HostEntry::root(root).map_err(|_| FsError::NotFound(path))?;
+
+	let normalized = str::from_utf8(&normalized).expect("valid paths are ASCII");
+	for component in normalized.split('/').filter(|c| !c.is_empty()) {
+		// Hidden files (like `.git` or `.env`) aren't served
+		if component.starts_with('.') {
+			return Err(FsError::NotFound(path));
+		}
+
+		// Every component is opened on its own, so a symbolic link can't lead
+		// out of the root
+		entry = entry.open(component).map_err(|_| FsError::NotFound(path))?;
+		host.push(component);
+		name = component;
+	}
+
+	let meta = entry.metadata().map_err(|_| FsError::NotFound(path))?;
+	if directory && !meta.is_dir() {
+		return Err(FsError::NotFound(path));
+	}
+
+	let unreadable = |e: io::Error| {
+		warn!("Couldn't read {}: {e}", host.display());
+		FsError::Unreadable(path)
 	};
 
-	entries
+	if meta.is_file() {
+		if meta.len() > MAX_FILE_SIZE {
+			return Err(FsError::TooLarge(path));
+		}
+
+		// The file may have grown since
+		let contents = entry.read(MAX_FILE_SIZE + 1).map_err(unreadable)?;
+		if contents.len() as u64 > MAX_FILE_SIZE {
+			return Err(FsError::TooLarge(path));
+		}
+
+		Ok(Entry::File {
+			name: name.to_string().into(),
+			kind: Kind::of(&contents),
+			contents: contents.into(),
+			meta: (&meta).into(),
+		})
+	} else if meta.is_dir() {
+		let mut entries = Vec::new();
+
+		for name in entry.names().map_err(unreadable)? {
+			if name.starts_with('.') || !name.bytes().all(|b| PATH_VALID_CHARACTERS.contains(&b)) {
+				continue;
+			}
+
+			// Symbolic links and special files are left out
+			entries.push(match entry.stat(&name) {
+				Ok((HostKind::File, meta)) => Entry::File {
+					// Reading the file itself will fail as well if this does
+					kind: entry
+						.open(&name)
+						.and_then(|file| file.read(CLASSIFY_LEN))
+						.map_or(Kind::Binary, |data| Kind::of(&data)),
+					name: name.into(),
+					contents: Contents::Bytes(Cow::Borrowed(&[])),
+					meta,
+				},
+				Ok((HostKind::Directory, meta)) => Entry::Directory {
+					name: name.into(),
+					entries: Cow::Borrowed(&[]),
+					meta,
+				},
+				Ok((HostKind::Other, _)) => continue,
+				Err(e) if e.kind() == ErrorKind::NotFound => continue,
+				Err(e) => return Err(unreadable(e)),
+			});
+
+			if entries.len() == MAX_DIRECTORY_ENTRIES {
+				warn!(
+					"Only listing the first {MAX_DIRECTORY_ENTRIES} entries of {}",
+					host.display()
+				);
+				break;
+			}
+		}
+
+		entries.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+		Ok(Entry::Directory {
+			name: name.to_string().into(),
+			entries: entries.into(),
+			meta: (&meta).into(),
+		})
+	} else {
+		Err(FsError::NotFound(path))
+	}
+}
+
+/// What an entry of a host directory is
+enum HostKind {
+	File,
+	Directory,
+	/// A symbolic link or a special file
+	Other,
+}
+
+/// A file or directory of the host, opened relative to its directory without
+/// following symbolic links, so it can't be swapped for one leading out of the
+/// root while it's read
+#[cfg(unix)]
+struct HostEntry(fs::File);
+
+#[cfg(unix)]
+impl HostEntry {
+	fn root(dir: &HostDir) -> io::Result<Self> {
+		dir.0.dir.try_clone().map(Self)
+	}
+
+	/// Open the entry `name` of this directory
+	fn open(&self, name: &str) -> io::Result<Self> {
+		// Special files (e.g. FIFOs) mustn't block opening them
+		let fd = openat(
+			&self.0,
+			name,
+			OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC,
+			Mode::empty(),
+		)?;
+		Ok(Self(fd.into()))
+	}
+
+	fn metadata(&self) -> io::Result<fs::Metadata> {
+		self.0.metadata()
+	}
+
+	/// Read at most `limit` bytes of this file
+	fn read(&self, limit: u64) -> io::Result<Vec<u8>> {
+		let mut data = Vec::new();
+		(&self.0).take(limit).read_to_end(&mut data)?;
+		Ok(data)
+	}
+
+	/// The names of this directory's entries which are valid UTF-8
+	fn names(&self) -> io::Result<Vec<String>> {
+		let mut dir = Dir::openat(
+			&self.0,
+			".",
+			OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+			Mode::empty(),
+		)?;
+
+		let mut names = Vec::new();
+		for entry in dir.iter() {
+			if let Ok(name) = entry?.file_name().to_str() {
+				if name != "." && name != ".." {
+					names.push(name.to_string());
+				}
+			}
+		}
+
+		Ok(names)
+	}
+
+	/// Get what the entry `name` of this directory is, without following
+	/// symbolic links
+	fn stat(&self, name: &str) -> io::Result<(HostKind, Metadata)> {
+		let stat = fstatat(&self.0, name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+		let kind = match SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT {
+			kind if kind == SFlag::S_IFREG => HostKind::File,
+			kind if kind == SFlag::S_IFDIR => HostKind::Directory,
+			_ => HostKind::Other,
+		};
+
+		let meta = Metadata {
+			size: match kind {
+				HostKind::File => u64::try_from(stat.st_size).unwrap_or(0),
+				_ => 0,
+			},
+			mtime: u64::try_from(stat.st_mtime).unwrap_or(0),
+			mode: stat.st_mode as u32 & 0o7777,
+		};
+
+		Ok((kind, meta))
+	}
+}
+
+/// A file or directory of the host, checked not to be a symbolic link when
+/// it's opened (though unlike on Unix, it could be replaced by one before it's
+/// read)
+#[cfg(not(unix))]
+struct HostEntry(PathBuf);
+
+#[cfg(not(unix))]
+impl HostEntry {
+	fn root(dir: &HostDir) -> io::Result<Self> {
+		Ok(Self(dir.path().to_path_buf()))
+	}
+
+	/// Open the entry `name` of this directory
+	fn open(&self, name: &str) -> io::Result<Self> {
+		let path = self.0.join(name);
+
+		if fs::symlink_metadata(&path)?.is_symlink() {
+			return Err(ErrorKind::NotFound.into());
+		}
+
+		Ok(Self(path))
+	}
+
+	fn metadata(&self) -> io::Result<fs::Metadata> {
+		fs::symlink_metadata(&self.0)
+	}
+
+	/// Read at most `limit` bytes of this file
+	fn read(&self, limit: u64) -> io::Result<Vec<u8>> {
+		let mut data = Vec::new();
+		fs::File::open(&self.0)?
+			.take(limit)
+			.read_to_end(&mut data)?;
+		Ok(data)
+	}
+
+	/// The names of this directory's entries which are valid UTF-8
+	fn names(&self) -> io::Result<Vec<String>> {
+		let mut names = Vec::new();
+		for entry in fs::read_dir(&self.0)? {
+			if let Ok(name) = entry?.file_name().into_string() {
+				names.push(name);
+			}
+		}
+
+		Ok(names)
+	}
+
+	/// Get what the entry `name` of this directory is, without following
+	/// symbolic links
+	fn stat(&self, name: &str) -> io::Result<(HostKind, Metadata)> {
+		let meta = fs::symlink_metadata(self.0.join(name))?;
+		let kind = if meta.is_file() {
+			HostKind::File
+		} else if meta.is_dir() {
+			HostKind::Directory
+		} else {
+			HostKind::Other
+		};
+
+		Ok((kind, (&meta).into()))
+	}
 }
 
 #[cfg(test)]
 mod tests {
+	use std::cell::Cell;
+
+	use smol::{block_on, future};
+
 	use super::*;
 
 	#[test]
@@ -149,7 +874,11 @@ mod tests {
 			unreachable!();
 		};
 
-		assert!(contents.contains("This is part of this file."));
+		assert!(
+			str::from_utf8(contents.bytes())
+				.unwrap()
+				.contains("This is part of this file.")
+		);
 	}
 
 	#[test]
@@ -162,7 +891,7 @@ mod tests {
 			unreachable!();
 		};
 
-		assert_eq!(contents, include_str!("../build.rs"));
+		assert_eq!(contents.bytes(), include_bytes!("../build.rs"));
 	}
 
 	#[test]
@@ -170,6 +899,85 @@ mod tests {
 		assert!(read(b"/target").is_err());
 	}
 
+	#[test]
+	fn kind() {
+		assert_eq!(Kind::of(b""), Kind::Text);
+		assert_eq!(Kind::of("plain ünïcödé".as_bytes()), Kind::Text);
+		assert_eq!(Kind::of(&"ü".as_bytes()[..1]), Kind::Text);
+		assert_eq!(Kind::of(b"\xff\xfe"), Kind::Binary);
+		assert_eq!(Kind::of(b"a\0b"), Kind::Binary);
+
+		assert!(matches!(
+			read(b"/build.rs"),
+			Ok(Entry::File {
+				kind: Kind::Text,
+				..
+			})
+		));
+		assert!(matches!(read(b"/data/pixel.gif"), Ok(Entry::File {
+			contents,
+			kind: Kind::Binary,
+			..
+		}) if contents.bytes().starts_with(b"GIF89a")));
+	}
+
+	#[test]
+	fn compressed() {
+		let Entry::File { contents, .. } = read(b"/data/quotes.txt").unwrap() else {
+			panic!();
+		};
+
+		let deflated = contents.deflated().unwrap();
+		assert!(deflated.len() < contents.bytes().len());
+		assert_eq!(
+			decompress_to_vec_zlib_with_limit(deflated, usize::MAX).unwrap(),
+			include_bytes!("../data/quotes.txt")
+		);
+
+		// Decompressed contents are cached
+		assert_eq!(contents.bytes(), include_bytes!("../data/quotes.txt"));
+		assert!(std::ptr::eq(contents.bytes(), contents.bytes()));
+
+		let Entry::File { contents, .. } = read(b"/data/pixel.gif").unwrap() else {
+			panic!();
+		};
+
+		assert_eq!(contents.bytes(), include_bytes!("../data/pixel.gif"));
+		assert_eq!(Contents::from(&b"bytes"[..]).deflated(), None);
+	}
+
+	#[test]
+	fn metadata() {
+		let build = read(b"/build.rs").unwrap();
+		assert_eq!(
+			build.meta().size,
+			include_bytes!("../build.rs").len() as u64
+		);
+		assert_ne!(build.meta().mode, 0);
+		if let Some(epoch) = option_env!("SOURCE_DATE_EPOCH") {
+			assert_eq!(build.meta().mtime, epoch.parse::<u64>().unwrap());
+			assert_eq!(build.meta().mode, 0o644);
+		}
+		assert_eq!(build.mime(), Some("text/x-rust"));
+
+		assert_eq!(read(b"/src").unwrap().meta().size, 0);
+		assert_eq!(read(b"/src").unwrap().mime(), None);
+		assert_eq!(read(b"/data/pixel.gif").unwrap().mime(), Some("image/gif"));
+		assert_eq!(read(b"/LICENSE-MIT").unwrap().mime(), Some("text/plain"));
+		assert_eq!(
+			read(b"/Cargo.toml").unwrap().mime(),
+			Some("application/toml")
+		);
+
+		let binary = Entry::File {
+			name: "file.DAT".into(),
+			contents: b"\0"[..].into(),
+			kind: Kind::Binary,
+			meta: *build.meta(),
+		};
+		assert_eq!(binary.mime(), Some("application/octet-stream"));
+	}
+
 	#[test]
 	fn read_dir() {
 		let dir = read(b"/src/services/").unwrap();
@@ -183,6 +991,87 @@ mod tests {
 		assert!(entries.iter().any(|e| e.name() == dir.name()));
 	}
 
+	#[test]
+	fn normalize_paths() {
+		for (path, normalized, directory) in [
+			(&b"/"[..], &b"/"[..], true),
+			(b"//", b"/", true),
+			(b"/src", b"/src", false),
+			(b"/src/", b"/src", true),
+			(b"//src//fs.rs", b"/src/fs.rs", false),
+			(b"/src/./fs.rs", b"/src/fs.rs", false),
+			(b"/src/.", b"/src", true),
+			(b"/src/services/../fs.rs", b"/src/fs.rs", false),
+			(b"/src/..", b"/", true),
+		] {
+			assert_eq!(normalize(path).unwrap(), (normalized.to_vec(), directory));
+		}
+
+		assert!(matches!(
+			normalize(b"/.."),
+			Err(FsError::OutsideRoot(b"/.."))
+		));
+		assert!(matches!(
+			normalize(b"/src/../../fs.rs"),
+			Err(FsError::OutsideRoot(_))
+		));
+		assert!(matches!(
+			normalize(b"src"),
+			Err(FsError::NonAbsolutePath(_))
+		));
+
+		let err = normalize(b"/..").unwrap_err();
+		assert_eq!(err.kind(), "outside_root");
+		assert!(format!("{err}").contains("outside of the root"));
+	}
+
+	#[test]
+	fn read_normalized() {
+		let file = read(b"/src/fs.rs").unwrap();
+
+		for path in [
+			&b"//src/fs.rs"[..],
+			b"/src/./fs.rs",
+			b"/src/services/../fs.rs",
+			b"/./src//fs.rs",
+		] {
+			assert_eq!(read(path).unwrap(), file);
+		}
+
+		assert_eq!(&read(b"/src/..").unwrap(), &FS);
+		assert!(matches!(read(b"/src/fs.rs/"), Err(FsError::NotFound(_))));
+		assert!(matches!(read(b"/src/fs.rs/."), Err(FsError::NotFound(_))));
+		assert!(matches!(read(b"/../src"), Err(FsError::OutsideRoot(_))));
+	}
+
+	#[test]
+	fn paths_index() {
+		assert!(PATHS.is_sorted_by_key(|(path, _)| path.as_bytes()));
+
+		for (path, _) in PATHS {
+			assert_eq!(
+				read(path.as_bytes()).unwrap().name(),
+				path.rsplit('/').next().unwrap()
+			);
+		}
+	}
+
+	#[test]
+	fn read_virtual() {
+		assert!(read(b"/.status").unwrap().is_directory());
+		assert!(read(b"/.status/").unwrap().is_directory());
+		assert_eq!(read(b"//.status/./uptime").unwrap().name(), "uptime");
+		assert_eq!(read(b"/src/../.status/quote").unwrap().name(), "quote");
+
+		for path in [
+			&b"/.status/uptime/"[..],
+			b"/.status/nothing",
+			b"/.statusquote",
+		] {
+			assert!(matches!(read(path), Err(FsError::NotFound(_))));
+		}
+	}
+
 	#[test]
 	fn read_nothing() {
 		let entry = read(b"/src/foo/bar.rs");
@@ -202,10 +1091,181 @@ mod tests {
 	}
 
 	#[test]
-	fn root_has_root_entries() {
-		assert_eq!(FS, Entry::Directory {
-			name: "",
-			entries: root_entries()
-		});
+	fn read_root() {
+		assert_eq!(&read(b"/").unwrap(), &FS);
+		assert_eq!(FS.name(), "");
+	}
+
+	#[test]
+	fn read_host_dir() {
+		let root = std::env::temp_dir().join(format!("simple-protocols-{}-fs", std::process::id()));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(root.join("dir/sub")).unwrap();
+		fs::write(root.join("dir/file.txt"), "Hello, World!").unwrap();
+		fs::write(root.join("dir/not valid.txt"), "").unwrap();
+		fs::write(root.join("binary"), b"\xff\xfe").unwrap();
+		fs::write(root.join("dir/.env"), "SECRET=1").unwrap();
+		fs::create_dir_all(root.join(".git")).unwrap();
+		fs::write(root.join(".git/config"), "").unwrap();
+		#[cfg(unix)]
+		{
+			std::os::unix::fs::symlink("/etc", root.join("dir/link")).unwrap();
+			std::os::unix::fs::symlink("..", root.join("dir/sub/up")).unwrap();
+			nix::unistd::mkfifo(&root.join("dir/fifo"), Mode::S_IRWXU).unwrap();
+		}
+
+		let dir = HostDir::open(&root).unwrap();
+		let host = Root::Directory(&dir);
+		assert_eq!(dir.path(), root);
+		assert!(HostDir::open(root.join("dir/file.txt")).is_err());
+		assert!(HostDir::open(root.join("nothing")).is_err());
+
+		let modified = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+		fs::File::options()
+			.write(true)
+			.open(root.join("dir/file.txt"))
+			.unwrap()
+			.set_modified(modified)
+			.unwrap();
+
+		let meta = Metadata {
+			size: 13,
+			mtime: 1_000_000_000,
+			mode: Metadata::from(&fs::metadata(root.join("dir/file.txt")).unwrap()).mode,
+		};
+
+		assert_eq!(
+			block_on(host.read(b"/dir/file.txt")).unwrap(),
+			Entry::File {
+				name: "file.txt".into(),
+				contents: b"Hello, World!"[..].into(),
+				kind: Kind::Text,
+				meta,
+			}
+		);
+
+		let Ok(Entry::Directory { name, entries, .. }) = block_on(host.read(b"/dir/")) else {
+			panic!();
+		};
+
+		assert_eq!(name, "dir");
+		assert_eq!(entries.iter().map(Entry::name).collect::<Vec<_>>(), [
+			"file.txt", "sub"
+		]);
+		assert!(entries[0].is_file());
+		assert!(entries[1].is_directory());
+		assert_eq!(entries[0].meta(), &meta);
+		assert_eq!(entries[0].meta().modified(), modified);
+		assert_eq!(entries[1].meta().size, 0);
+
+		let Ok(Entry::Directory { name, entries, .. }) = block_on(host.read(b"/")) else {
+			panic!();
+		};
+
+		assert_eq!(name, "");
+		assert_eq!(entries.len(), 2);
+
+		for path in [
+			&b"/dir/link"[..],
+			b"/dir/link/passwd",
+			b"/dir/sub/up/file.txt",
+			b"/dir/file.txt/",
+			b"/dir/fifo",
+			b"/dir/.env",
+			b"/.git/",
+			b"/.git/config",
+			b"/nothing",
+		] {
+			assert!(matches!(
+				block_on(host.read(path)),
+				Err(FsError::NotFound(_))
+			));
+		}
+
+		for path in [
+			&b"/dir/../dir/file.txt"[..],
+			b"/dir/./file.txt",
+			b"//dir//file.txt",
+		] {
+			assert_eq!(block_on(host.read(path)).unwrap().name(), "file.txt");
+		}
+		assert!(matches!(
+			block_on(host.read(b"/dir/../..")),
+			Err(FsError::OutsideRoot(_))
+		));
+		assert_eq!(
+			block_on(host.read(b"/.status/services")).unwrap().name(),
+			"services"
+		);
+
+		assert!(matches!(
+			block_on(host.read(b"/binary")),
+			Ok(Entry::File {
+				kind: Kind::Binary,
+				..
+			})
+		));
+		assert!(matches!(
+			block_on(host.read(b"dir")),
+			Err(FsError::NonAbsolutePath(_))
+		));
+		assert!(matches!(
+			block_on(host.read(b"/dir/not valid.txt")),
+			Err(FsError::InvalidPath(_))
+		));
+
+		// The directory stays open when it can't be reached at its path anymore
+		#[cfg(unix)]
+		{
+			let moved = root.with_extension("moved");
+			fs::rename(&root, &moved).unwrap();
+			assert_eq!(
+				block_on(host.read(b"/dir/file.txt")).unwrap().name(),
+				"file.txt"
+			);
+			fs::rename(&moved, &root).unwrap();
+		}
+
+		fs::remove_dir_all(&root).unwrap();
+	}
+
+	#[test]
+	fn host_reads_dont_block() {
+		let root = std::env::temp_dir().join(format!(
+			"simple-protocols-{}-fs-unblock",
+			std::process::id()
+		));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(&root).unwrap();
+		for i in 0..1000 {
+			fs::write(root.join(format!("{i}.txt")), "Hello, World!").unwrap();
+		}
+
+		let dir = HostDir::open(&root).unwrap();
+		let (read, other) = (Cell::new(false), Cell::new(0));
+
+		// Other tasks on the same thread keep running while the directory is
+		// read, instead of only after it
+		let (entry, ()) = block_on(future::zip(
+			async {
+				let entry = Root::Directory(&dir).read(b"/").await;
+				read.set(true);
+				entry
+			},
+			async {
+				while !read.get() {
+					other.set(other.get() + 1);
+					future::yield_now().await;
+				}
+			},
+		));
+
+		assert!(other.get() > 0);
+		let Ok(Entry::Directory { entries, .. }) = entry else {
+			panic!();
+		};
+		assert_eq!(entries.len(), 1000);
+
+		fs::remove_dir_all(&root).unwrap();
 	}
 }
@@ -0,0 +1,404 @@
+//! Handing the listening sockets over to a new server process, so upgrading
+//! the server never refuses a connection
+//!
+//! A running server listens on a Unix stream socket (only accessible to its
+//! user). A new server connects to it and receives a header describing the
+//! sockets, followed by the sockets themselves (`SCM_RIGHTS`, in batches of at
+//! most [`MAX_FDS`], each attached to a single zero byte). Once the new server
+//! is listening on them, it sends [`READY`], and the previous server stops.
+
+#[cfg(unix)]
+use std::{
+	fmt::Write as _,
+	fs::{self, Permissions},
+	io::{ErrorKind, IoSlice, IoSliceMut, Read, Write},
+	os::{
+		fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+		unix::{
+			fs::{FileTypeExt, PermissionsExt},
+			net::{UnixListener, UnixStream},
+		},
+	},
+	time::Duration,
+};
+use std::{future::pending, path::Path};
+
+use anyhow::{Error, anyhow};
+#[cfg(unix)]
+use log::{info, warn};
+#[cfg(unix)]
+use nix::sys::socket::{ControlMessage, ControlMessageOwned, MsgFlags, recvmsg, sendmsg};
+#[cfg(unix)]
+use smol::{
+	Async, Timer, future,
+	io::{AsyncReadExt, AsyncWriteExt},
+};
+#[cfg(unix)]
+use socket2::{Socket, Type};
+
+use crate::reuse::Bound;
+#[cfg(unix)]
+use crate::{
+	tcp::Stream,
+	utils::{Peer, PeerCred},
+};
+
+/// The first line of the header, changed whenever the protocol changes
+#[cfg(unix)]
+const VERSION: &str = "simple-protocols handover 1";
+
+/// The maximum number of sockets sent in one message
+#[cfg(unix)]
+const MAX_FDS: usize = 64;
+
+/// What the new server sends once it's listening on the sockets
+#[cfg(unix)]
+const READY: &[u8] = b"ready\n";
+
+/// How long either side waits for the other
+#[cfg(unix)]
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listens for new servers taking over the listening sockets
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Listener(Async<UnixListener>);
+
+#[cfg(unix)]
+impl Listener {
+	/// Listen on a Unix stream socket at `path`, replacing a stale socket file
+	pub fn bind(path: &Path) -> Result<Self, Error> {
+		if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+			fs::remove_file(path)?;
+		}
+
+		let listener = UnixListener::bind(path)
+			.map_err(|e| anyhow!("couldn't bind the handover socket {}: {e}", path.display()))?;
+		fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+		info!("Listening for new servers on {}", path.display());
+
+		Ok(Self(Async::new(listener)?))
+	}
+
+	/// Wait until a new server has taken `sockets` over and is listening on
+	/// them
+	pub async fn taken_over(&self, sockets: &[Bound]) {
+		loop {
+			let stream = match self.0.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					warn!("Handover socket `accept` error: {e}");
+					continue;
+				}
+			};
+
+			let peer = stream.peer();
+
+			// The socket file's permissions don't apply between binding and setting
+			// them, so also check the new server's user if possible
+			if let Peer::Unix {
+				cred: Some(PeerCred { uid, .. }),
+				..
+			} = peer
+			{
+				if uid != 0 && uid != nix::unistd::geteuid().as_raw() {
+					warn!("Refusing to hand the listening sockets over to {peer}");
+					continue;
+				}
+			}
+
+			info!("Handing the listening sockets over to a new server ({peer})");
+
+			let timeout = async {
+				Timer::after(TIMEOUT).await;
+				Err(anyhow!("timed out"))
+			};
+
+			match future::or(send(stream, sockets), timeout).await {
+				Ok(()) => {
+					info!("The new server is listening, stopping");
+					return;
+				}
+				Err(e) => warn!("Couldn't hand the listening sockets over: {e}"),
+			}
+		}
+	}
+}
+
+/// Send the `sockets` to a new server, returning once it's listening on them
+#[cfg(unix)]
+async fn send(mut stream: Async<UnixStream>, sockets: &[Bound]) -> Result<(), Error> {
+	let mut header = format!("{VERSION}\n");
+	for bound in sockets {
+		let ty = if bound.ty() == Type::STREAM {
+			"stream"
+		} else {
+			"dgram"
+		};
+
+		writeln!(
+			header,
+			"{} {ty} {}",
+			bound.service(),
+			u8::from(bound.inherited())
+		)?;
+	}
+
+	stream
+		.write_all(&u32::try_from(header.len())?.to_be_bytes())
+		.await?;
+	stream.write_all(header.as_bytes()).await?;
+
+	let fds = sockets
+		.iter()
+		.map(|bound| bound.socket().as_raw_fd())
+		.collect::<Vec<_>>();
+
+	for batch in fds.chunks(MAX_FDS) {
+		stream
+			.write_with(|stream| {
+				sendmsg::<()>(
+					stream.as_raw_fd(),
+					&[IoSlice::new(&[0])],
+					&[ControlMessage::ScmRights(batch)],
+					MsgFlags::empty(),
+					None,
+				)
+				.map_err(Into::into)
+			})
+			.await?;
+	}
+
+	let mut ready = [0; READY.len()];
+	stream.read_exact(&mut ready).await?;
+
+	if ready != READY {
+		return Err(anyhow!("unexpected response from the new server"));
+	}
+
+	Ok(())
+}
+
+/// A running server whose listening sockets were taken over
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct Previous(UnixStream);
+
+#[cfg(unix)]
+impl Previous {
+	/// Connect to the handover socket of a running server at `path`, and
+	/// receive its listening sockets, or `None` if no server is listening there
+	pub fn connect(path: &Path) -> Result<Option<(Self, Vec<Bound>)>, Error> {
+		let mut stream = match UnixStream::connect(path) {
+			Ok(stream) => stream,
+			Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+				info!("No running server to take over at {}", path.display());
+				return Ok(None);
+			}
+			Err(e) => {
+				return Err(anyhow!(
+					"couldn't connect to the handover socket {}: {e}",
+					path.display()
+				));
+			}
+		};
+
+		stream.set_read_timeout(Some(TIMEOUT))?;
+
+		let mut len = [0; 4];
+		stream.read_exact(&mut len)?;
+		let mut header = vec![0; usize::try_from(u32::from_be_bytes(len))?];
+		stream.read_exact(&mut header)?;
+		let header = String::from_utf8(header)?;
+
+		let mut lines = header.lines();
+		if lines.next() != Some(VERSION) {
+			return Err(anyhow!("the running server uses another handover protocol"));
+		}
+
+		let described = lines
+			.map(|line| {
+				let mut fields = line.split(' ');
+
+				match (fields.next(), fields.next(), fields.next(), fields.next()) {
+					(Some(service), Some("stream"), Some(inherited), None) => {
+						Ok((service, Type::STREAM, inherited == "1"))
+					}
+					(Some(service), Some("dgram"), Some(inherited), None) => {
+						Ok((service, Type::DGRAM, inherited == "1"))
+					}
+					_ => Err(anyhow!("invalid handover header line {line:?}")),
+				}
+			})
+			.collect::<Result<Vec<_>, Error>>()?;
+
+		let mut fds = Vec::with_capacity(described.len());
+		while fds.len() < described.len() {
+			let mut byte = [0];
+			let mut iov = [IoSliceMut::new(&mut byte)];
+			let mut cmsgs = nix::cmsg_space!([RawFd; MAX_FDS]);
+
+			let msg = recvmsg::<()>(
+				stream.as_raw_fd(),
+				&mut iov,
+				Some(&mut cmsgs),
+				MsgFlags::empty(),
+			)?;
+
+			for cmsg in msg.cmsgs()? {
+				if let ControlMessageOwned::ScmRights(received) = cmsg {
+					// SAFETY: the file descriptors were just received, so nothing else owns
+					// them in this process
+					fds.extend(
+						received
+							.into_iter()
+							.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+					);
+				}
+			}
+
+			if msg.flags.contains(MsgFlags::MSG_CTRUNC) {
+				return Err(anyhow!("too many sockets received at once"));
+			} else if msg.bytes == 0 {
+				return Err(anyhow!("the running server stopped sending sockets"));
+			}
+		}
+
+		if fds.len() != described.len() {
+			return Err(anyhow!(
+				"expected {} sockets, but received {}",
+				described.len(),
+				fds.len()
+			));
+		}
+
+		let sockets = described
+			.into_iter()
+			.zip(fds)
+			.map(|((service, ty, inherited), fd)| {
+				let socket = Socket::from(fd);
+				socket.set_cloexec(true)?;
+				Ok(Bound::new(service, ty, inherited, socket)?)
+			})
+			.collect::<Result<Vec<_>, Error>>()?;
+
+		info!(
+			"Received {} listening sockets from the running server",
+			sockets.len()
+		);
+
+		Ok(Some((Self(stream), sockets)))
+	}
+
+	/// Tell the previous server that this one is listening, so it can stop
+	pub fn confirm(mut self) -> Result<(), Error> {
+		self.0.write_all(READY)?;
+		Ok(())
+	}
+}
+
+/// Listens for new servers taking over the listening sockets (only on Unix)
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub enum Listener {}
+
+#[cfg(not(unix))]
+impl Listener {
+	pub fn bind(_: &Path) -> Result<Self, Error> {
+		Err(anyhow!("handing over sockets is only supported on Unix"))
+	}
+
+	pub async fn taken_over(&self, _: &[Bound]) {
+		match *self {}
+	}
+}
+
+/// A running server whose listening sockets were taken over (only on Unix)
+#[cfg(not(unix))]
+#[derive(Debug)]
+pub enum Previous {}
+
+#[cfg(not(unix))]
+impl Previous {
+	pub fn connect(_: &Path) -> Result<Option<(Self, Vec<Bound>)>, Error> {
+		Err(anyhow!("taking over sockets is only supported on Unix"))
+	}
+
+	pub fn confirm(self) -> Result<(), Error> {
+		match self {}
+	}
+}
+
+/// Wait until a new server has taken `sockets` over from `listener`, or
+/// forever if there's no listener
+pub async fn taken_over(listener: Option<&Listener>, sockets: &[Bound]) {
+	match listener {
+		Some(listener) => listener.taken_over(sockets).await,
+		None => pending().await,
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use std::net::{Ipv4Addr, SocketAddr};
+
+	use socket2::{Domain, Protocol};
+
+	use super::*;
+
+	#[test]
+	fn handover() {
+		let path =
+			std::env::temp_dir().join(format!("simple-protocols-{}-handover", std::process::id()));
+
+		let sockets = (0..MAX_FDS + 2)
+			.map(|i| {
+				let (ty, protocol) = if i % 2 == 0 {
+					(Type::STREAM, Protocol::TCP)
+				} else {
+					(Type::DGRAM, Protocol::UDP)
+				};
+
+				let socket = Socket::new(Domain::IPV4, ty, Some(protocol)).unwrap();
+				socket
+					.bind(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0).into())
+					.unwrap();
+				Bound::new(&format!("service-{i}"), ty, i == 1, socket).unwrap()
+			})
+			.collect::<Vec<_>>();
+
+		assert!(Previous::connect(&path).unwrap().is_none());
+
+		let listener = Listener::bind(&path).unwrap();
+		assert_eq!(
+			fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+			0o600
+		);
+
+		let new = std::thread::spawn({
+			let path = path.clone();
+			move || {
+				let (previous, received) = Previous::connect(&path).unwrap().unwrap();
+				previous.confirm().unwrap();
+				received
+			}
+		});
+
+		smol::block_on(listener.taken_over(&sockets));
+		let received = new.join().unwrap();
+
+		assert_eq!(received.len(), sockets.len());
+		for (sent, received) in sockets.iter().zip(&received) {
+			assert_eq!(received.service(), sent.service());
+			assert_eq!(received.ty(), sent.ty());
+			assert_eq!(received.inherited(), sent.inherited());
+			assert_eq!(
+				received.socket().local_addr().unwrap().as_socket(),
+				sent.socket().local_addr().unwrap().as_socket()
+			);
+		}
+
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,153 @@
+//! Serving a single service over inherited standard I/O, as started by inetd
+
+use std::{
+	io::{self, Error as IoError, Stdin, Stdout},
+	net::UdpSocket as StdSocket,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+use anyhow::{Error, anyhow};
+use log::info;
+use smol::{
+	Unblock,
+	io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+	services::{Config, ServiceErr, SimpleService},
+	tcp::Stream,
+	udp::Listener as UdpListener,
+	utils::Peer,
+};
+
+/// A stream reading from standard input and writing to standard output
+pub struct Stdio {
+	stdin: Unblock<Stdin>,
+	stdout: Unblock<Stdout>,
+}
+
+impl Stdio {
+	pub fn new() -> Self {
+		Self {
+			stdin: Unblock::new(io::stdin()),
+			stdout: Unblock::new(io::stdout()),
+		}
+	}
+}
+
+impl AsyncRead for Stdio {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut [u8],
+	) -> Poll<Result<usize, IoError>> {
+		Pin::new(&mut self.stdin).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for Stdio {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, IoError>> {
+		Pin::new(&mut self.stdout).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Pin::new(&mut self.stdout).poll_flush(cx)
+	}
+
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Pin::new(&mut self.stdout).poll_close(cx)
+	}
+}
+
+impl Stream for Stdio {
+	/// The other end of the socket on standard input, if it is one
+	fn peer(&self) -> Peer {
+		#[cfg(unix)]
+		{
+			let stdin = io::stdin();
+			let socket = socket2::SockRef::from(&stdin);
+			socket.peer_addr().map_or(Peer::Unknown, |addr| {
+				crate::unix::with_cred(Peer::from(&addr), &*socket)
+			})
+		}
+		#[cfg(not(unix))]
+		{
+			Peer::Unknown
+		}
+	}
+
+	/// This end of the socket on standard input, if it is one
+	fn local(&self) -> Peer {
+		#[cfg(unix)]
+		{
+			let stdin = io::stdin();
+			socket2::SockRef::from(&stdin)
+				.local_addr()
+				.map_or(Peer::Unknown, |addr| Peer::from(&addr))
+		}
+		#[cfg(not(unix))]
+		{
+			Peer::Unknown
+		}
+	}
+
+	fn error(&mut self, _: &'static str) {}
+
+	fn field(&mut self, _: &'static str, _: String) {}
+}
+
+/// Get the UDP socket passed as standard input
+#[cfg(unix)]
+fn stdin_socket() -> Result<StdSocket, Error> {
+	use std::os::fd::AsFd;
+
+	use socket2::{SockRef, Type};
+
+	let stdin = io::stdin();
+	if SockRef::from(&stdin).r#type().ok() != Some(Type::DGRAM) {
+		return Err(anyhow!("standard input is not a UDP socket"));
+	}
+
+	Ok(StdSocket::from(stdin.as_fd().try_clone_to_owned()?))
+}
+
+/// Get the UDP socket passed as standard input
+#[cfg(not(unix))]
+fn stdin_socket() -> Result<StdSocket, Error> {
+	Err(anyhow!(
+		"serving UDP over standard input is only supported on Unix"
+	))
+}
+
+/// Serve a single connection over standard input and output, as started by
+/// inetd in `nowait` mode
+pub async fn serve_tcp<S: SimpleService>(config: &Arc<Config>) -> Result<(), ServiceErr> {
+	let mut stdio = Stdio::new();
+
+	info!("Serving inetd connection from {}", stdio.peer());
+
+	S::serve_tcp(config, &mut stdio)?.await;
+	stdio.close().await?;
+
+	Ok(())
+}
+
+/// Serve datagrams received on the UDP socket passed as standard input, as
+/// started by inetd in `wait` mode
+pub async fn serve_udp<S: SimpleService>(config: &Arc<Config>) -> Result<(), ServiceErr> {
+	let listener = UdpListener::from_std(stdin_socket()?)?;
+
+	info!("Serving inetd datagrams on {:?}", listener.local_addrs());
+
+	for task in S::serve_udp(config, listener)?.tasks {
+		task.await;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,30 @@
+#![doc = include_str!("../README.md")]
+
+mod access_log;
+mod executor;
+mod fs;
+mod handover;
+mod inetd;
+mod metrics;
+mod overlay;
+mod privileges;
+mod proxy;
+mod reuse;
+mod search;
+mod server;
+mod services;
+mod status;
+mod systemd;
+mod tcp;
+mod tls;
+mod udp;
+mod unix;
+mod utils;
+
+pub use executor::stats as thread_stats;
+pub use fs::{Contents, Entry, FsError, HostDir, Kind, Metadata, Root};
+pub use metrics::render as render_metrics;
+pub use overlay::{Overlay, Quota, Session, Visibility};
+pub use server::{Running, Server};
+pub use systemd::notify as sd_notify;
+pub use udp::dropped as udp_dropped;
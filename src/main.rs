@@ -1,27 +1,82 @@
-#![doc = include_str!("../README.md")]
+//! The simple-protocols server binary, see the library crate for details
 
-use std::{borrow::Cow, env};
+use std::{
+	borrow::Cow,
+	env,
+	ffi::OsString,
+	fs,
+	path::{Path, PathBuf},
+	process,
+	time::Duration,
+};
 
+use anyhow::{Context, Error};
 use env_logger::Env;
 use log::{error, info};
 use pico_args::Arguments;
-use smol::{channel, future::pending};
+use simple_protocols::{Server, sd_notify, thread_stats, udp_dropped};
+use smol::{
+	channel,
+	future::{self, pending},
+};
 
-mod fs;
-mod services;
-mod tcp;
-mod udp;
-mod utils;
+/// How long connections are given to finish once a new server has taken over
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What the server should do next
+enum Event {
+	Shutdown,
+	#[cfg_attr(not(unix), allow(dead_code))]
+	Reload,
+	TakenOver,
+}
+
+/// Configure a server from the command line arguments, followed by the
+/// arguments in the configuration file (if any)
+fn load(args: &[OsString], config_file: Option<&Path>) -> Result<Server, Error> {
+	let mut args = args.to_vec();
+
+	if let Some(path) = config_file {
+		let contents = fs::read_to_string(path)
+			.with_context(|| format!("couldn't read the configuration file {}", path.display()))?;
+
+		args.extend(
+			contents
+				.lines()
+				.map(|line| line.split_once('#').map_or(line, |(line, _)| line))
+				.flat_map(str::split_whitespace)
+				.map(OsString::from),
+		);
+	}
+
+	Server::from_args(Arguments::from_vec(args))
+}
 
 fn main() {
-	let args = {
+	let (args, config_file, inetd) = {
 		let mut args = Arguments::from_env();
 
+		let inetd = match (
+			args.opt_value_from_str::<_, String>("--inetd")
+				.expect("argument parsing"),
+			args.opt_value_from_str::<_, String>("--inetd-udp")
+				.expect("argument parsing"),
+		) {
+			(Some(service), None) => Some((service, false)),
+			(None, Some(service)) => Some((service, true)),
+			(None, None) => None,
+			(Some(_), Some(_)) => panic!("`--inetd` and `--inetd-udp` can't be used together"),
+		};
+
+		// Under inetd, standard error is usually connected to the client too, so
+		// nothing is logged unless explicitly configured
+		let default_log = if inetd.is_some() { "off" } else { "error" };
+
 		let log = match args.opt_value_from_str("--log") {
 			Ok(Some(log)) => (Cow::Owned(log), None, true),
-			Ok(None) => (Cow::Borrowed("error"), None, false),
+			Ok(None) => (Cow::Borrowed(default_log), None, false),
 			Err(e) => (
-				Cow::Borrowed("error"),
+				Cow::Borrowed(default_log),
 				Some(format!(
 					"Couldn't parse contents of the `--log` command line option: {e}"
 				)),
@@ -54,7 +109,7 @@ fn main() {
 			error!("{msg}");
 		}
 
-		if !log.2 && env::var_os("SIMPLE_PROTOCOLS_LOG").is_none() {
+		if !log.2 && inetd.is_none() && env::var_os("SIMPLE_PROTOCOLS_LOG").is_none() {
 			eprintln!("Logging is not configured, and only errors will be logged by default");
 			eprintln!(
 				"Configure logging using the `SIMPLE_PROTOCOLS_LOG` environment variable or the \
@@ -62,9 +117,39 @@ fn main() {
 			);
 		}
 
-		args
+		let config_file = args
+			.opt_value_from_str::<_, PathBuf>("--config")
+			.expect("argument parsing");
+
+		(args.finish(), config_file, inetd)
 	};
 
+	let server = load(&args, config_file.as_deref()).expect("configuration loading");
+
+	if let Some((service, udp)) = inetd {
+		let res = smol::block_on(async {
+			if udp {
+				server.inetd_udp(&service).await
+			} else {
+				server.inetd_tcp(&service).await
+			}
+		});
+
+		if let Err(e) = res {
+			error!("{e}");
+			process::exit(1);
+		}
+
+		return;
+	}
+
+	// The configuration file is read again on reload, possibly after changing
+	// the root directory
+	let config_file = config_file
+		.map(|path| server.path_in_root(&path))
+		.transpose()
+		.expect("configuration loading");
+
 	let (shutdown_tx, shutdown_rx) = channel::bounded(1);
 	if let Err(e) = ctrlc::set_handler(move || {
 		if let Err(e) = shutdown_tx.send_blocking(()) {
@@ -75,16 +160,103 @@ fn main() {
 	};
 
 	smol::block_on(async {
-		services::spawn_all(args);
+		let mut running = server.start().expect("server startup");
 
 		info!("Simple Protocols Started");
+		sd_notify("READY=1");
+
+		#[cfg(unix)]
+		let mut signals = {
+			use async_signal::{Signal, Signals};
 
-		let Ok(()) = shutdown_rx.recv().await else {
-			error!("Couldn't use CTRL-C handler, the server may not gracefully exit on CTRL-C");
-			pending::<()>().await;
-			unreachable!()
+			Signals::new([Signal::Term, Signal::Hup])
+				.inspect_err(|e| {
+					error!(
+						"Couldn't handle SIGTERM and SIGHUP, the server may not gracefully exit \
+						 or reload: {e}"
+					)
+				})
+				.ok()
 		};
+
+		let taken_over = loop {
+			let ctrl_c = async {
+				if let Ok(()) = shutdown_rx.recv().await {
+					Event::Shutdown
+				} else {
+					error!(
+						"Couldn't use CTRL-C handler, the server may not gracefully exit on CTRL-C"
+					);
+					pending().await
+				}
+			};
+
+			let signal = async {
+				#[cfg(unix)]
+				if let Some(signals) = &mut signals {
+					use async_signal::Signal;
+					use smol::stream::StreamExt;
+
+					match signals.next().await {
+						Some(Ok(Signal::Hup)) => return Event::Reload,
+						Some(Ok(_)) => return Event::Shutdown,
+						Some(Err(e)) => error!("Couldn't receive signals: {e}"),
+						None => (),
+					}
+				}
+
+				pending().await
+			};
+
+			let taken_over = async {
+				running.taken_over().await;
+				Event::TakenOver
+			};
+
+			let event = future::or(ctrl_c, future::or(signal, taken_over)).await;
+
+			match event {
+				Event::Shutdown => break false,
+				Event::TakenOver => break true,
+				Event::Reload => {
+					info!("Reloading configuration");
+					sd_notify("RELOADING=1");
+
+					let res = match load(&args, config_file.as_deref()) {
+						Ok(server) => running.reload(server).await,
+						Err(e) => Err(e),
+					};
+
+					if let Err(e) = res {
+						error!("Couldn't reload the configuration, keeping the previous one: {e}");
+					}
+
+					sd_notify("READY=1");
+				}
+			}
+		};
+
+		sd_notify("STOPPING=1");
+
+		if taken_over {
+			running.drain(DRAIN_TIMEOUT).await;
+		} else {
+			running.shutdown().await;
+		}
 	});
 
+	for (service, rate_limited, too_large) in udp_dropped() {
+		if rate_limited > 0 || too_large > 0 {
+			info!(
+				"Dropped {rate_limited} rate-limited and {too_large} too large UDP {service} \
+				 responses"
+			);
+		}
+	}
+
+	for (thread, polls, busy) in thread_stats() {
+		info!("Worker thread {thread} polled tasks {polls} times, busy for {busy:.1?}");
+	}
+
 	info!("Simple Protocols Exiting");
 }
@@ -0,0 +1,706 @@
+//! Per-service metrics, exported in the Prometheus text exposition format
+//!
+//! Counters are kept for every service and transport it has been started on,
+//! and are shared by all servers in the process, so restarted services keep
+//! counting where they left off.
+
+use std::{
+	fmt::Write as _,
+	io::Error as IoError,
+	net::{SocketAddr, TcpListener as StdListener},
+	pin::Pin,
+	sync::{
+		Mutex,
+		atomic::{AtomicU64, Ordering},
+	},
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use smol::{
+	Async, Timer, future,
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+};
+use socket2::{Protocol, Type};
+
+use crate::{
+	access_log::Entry,
+	executor::{self, spawn},
+	services::{Config, Handler, ServiceErr},
+	tcp::Stream,
+	udp,
+	utils::{self, Peer},
+};
+
+/// Upper bounds of the session duration histogram buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.1, 1.0, 10.0, 60.0, 600.0, 3600.0];
+
+/// The maximum size of an HTTP request head
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// How long scrapers have to send their request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The counters of every service and transport, in the order they were started
+static METRICS: Mutex<Vec<&'static Metrics>> = Mutex::new(Vec::new());
+
+/// What a service is served over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transport {
+	Tcp,
+	Udp,
+	#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+	Tls,
+	#[cfg_attr(not(unix), allow(dead_code))]
+	Unix,
+	UnixDgram,
+}
+
+impl Transport {
+	/// The name used in the `transport` label
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Tcp => "tcp",
+			Self::Udp => "udp",
+			Self::Tls => "tls",
+			Self::Unix => "unix",
+			Self::UnixDgram => "unix_dgram",
+		}
+	}
+
+	/// Whether the transport carries connections rather than datagrams
+	pub fn is_stream(self) -> bool {
+		matches!(self, Self::Tcp | Self::Tls | Self::Unix)
+	}
+}
+
+/// Counters of a single service over a single transport
+#[derive(Debug)]
+pub struct Metrics {
+	service: &'static str,
+	transport: Transport,
+	/// Connections accepted
+	accepted: AtomicU64,
+	/// Connections closed
+	closed: AtomicU64,
+	/// Datagrams received
+	received: AtomicU64,
+	/// Datagrams sent
+	sent: AtomicU64,
+	/// Bytes received in connections or datagrams
+	bytes_received: AtomicU64,
+	/// Bytes sent in connections or datagrams
+	bytes_sent: AtomicU64,
+	/// Closed connections by duration, one more than there are buckets
+	durations: [AtomicU64; DURATION_BUCKETS.len() + 1],
+	/// Total duration of closed connections, in nanoseconds
+	duration_sum: AtomicU64,
+	/// Handler errors by kind, in the order they first happened
+	errors: Mutex<Vec<(&'static str, u64)>>,
+}
+
+impl Metrics {
+	/// Start the access log entry of a connection or datagram from `peer` to
+	/// `local`
+	pub fn entry(&self, local: Peer, peer: Peer) -> Entry {
+		Entry::new(self.service, self.transport, local, peer)
+	}
+
+	/// Count a received datagram of `len` bytes
+	pub fn received(&self, len: usize) {
+		self.received.fetch_add(1, Ordering::Relaxed);
+		self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+	}
+
+	/// Count a sent datagram of `len` bytes
+	pub fn sent(&self, len: usize) {
+		self.sent.fetch_add(1, Ordering::Relaxed);
+		self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+	}
+
+	/// Count an error of the given kind (e.g. `"not_found"`)
+	pub fn error(&self, kind: &'static str) {
+		let mut errors = self.errors.lock().expect("metrics lock poisoned");
+
+		match errors.iter_mut().find(|(k, _)| *k == kind) {
+			Some((_, n)) => *n += 1,
+			None => errors.push((kind, 1)),
+		}
+	}
+
+	fn closed(&self, duration: Duration) {
+		let bucket = DURATION_BUCKETS
+			.iter()
+			.position(|&le| duration.as_secs_f64() <= le)
+			.unwrap_or(DURATION_BUCKETS.len());
+		let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+
+		self.durations[bucket].fetch_add(1, Ordering::Relaxed);
+		self.duration_sum.fetch_add(nanos, Ordering::Relaxed);
+		self.closed.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+/// Get the counters of the named service over `transport`
+pub fn get(service: &'static str, transport: Transport) -> &'static Metrics {
+	let mut all = METRICS.lock().expect("metrics lock poisoned");
+
+	if let Some(metrics) = all
+		.iter()
+		.find(|m| m.service == service && m.transport == transport)
+	{
+		return metrics;
+	}
+
+	let metrics: &'static Metrics = Box::leak(Box::new(Metrics {
+		service,
+		transport,
+		accepted: AtomicU64::new(0),
+		closed: AtomicU64::new(0),
+		received: AtomicU64::new(0),
+		sent: AtomicU64::new(0),
+		bytes_received: AtomicU64::new(0),
+		bytes_sent: AtomicU64::new(0),
+		durations: Default::default(),
+		duration_sum: AtomicU64::new(0),
+		errors: Mutex::new(Vec::new()),
+	}));
+
+	all.push(metrics);
+	metrics
+}
+
+/// Count an error of the given kind in the named service over `transport`,
+/// for connections that are already gone
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub fn error(service: &'static str, transport: Transport, kind: &'static str) {
+	get(service, transport).error(kind);
+}
+
+/// An accepted connection, counted in its service's metrics and written to the
+/// access log once dropped
+///
+/// Bytes are counted as they are read and written, which for TLS means
+/// including the TLS overhead.
+#[derive(Debug)]
+pub struct Metered<S> {
+	stream: S,
+	metrics: &'static Metrics,
+	start: Instant,
+	entry: Entry,
+}
+
+impl<S> Metered<S> {
+	/// Start counting a newly accepted connection from `peer` to `local`
+	pub fn new(stream: S, metrics: &'static Metrics, local: Peer, peer: Peer) -> Self {
+		metrics.accepted.fetch_add(1, Ordering::Relaxed);
+
+		Self {
+			stream,
+			metrics,
+			start: Instant::now(),
+			entry: metrics.entry(local, peer),
+		}
+	}
+
+	/// Count an error of the given kind, also making it the outcome of the
+	/// connection if it's the first one
+	pub fn error(&mut self, kind: &'static str) {
+		self.metrics.error(kind);
+		self.entry.error(kind);
+	}
+
+	/// Record a protocol-specific field of the connection's access log entry
+	pub fn field(&mut self, key: &'static str, value: String) {
+		self.entry.field(key, value);
+	}
+
+	/// Count an I/O error, passing the result through
+	fn count<T>(&mut self, res: Poll<Result<T, IoError>>) -> Poll<Result<T, IoError>> {
+		if let Poll::Ready(Err(_)) = &res {
+			self.error("io");
+		}
+
+		res
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut [u8],
+	) -> Poll<Result<usize, IoError>> {
+		let res = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+		if let Poll::Ready(Ok(n)) = res {
+			self.metrics
+				.bytes_received
+				.fetch_add(n as u64, Ordering::Relaxed);
+			self.entry.bytes_in += n as u64;
+		}
+
+		self.count(res)
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, IoError>> {
+		let res = Pin::new(&mut self.stream).poll_write(cx, buf);
+
+		if let Poll::Ready(Ok(n)) = res {
+			self.metrics
+				.bytes_sent
+				.fetch_add(n as u64, Ordering::Relaxed);
+			self.entry.bytes_out += n as u64;
+		}
+
+		self.count(res)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		let res = Pin::new(&mut self.stream).poll_flush(cx);
+		self.count(res)
+	}
+
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		let res = Pin::new(&mut self.stream).poll_close(cx);
+		self.count(res)
+	}
+}
+
+impl<S: Stream> Stream for Metered<S> {
+	fn peer(&self) -> Peer {
+		self.stream.peer()
+	}
+
+	fn local(&self) -> Peer {
+		self.stream.local()
+	}
+
+	fn error(&mut self, kind: &'static str) {
+		Metered::error(self, kind);
+	}
+
+	fn field(&mut self, key: &'static str, value: String) {
+		Metered::field(self, key, value);
+	}
+}
+
+impl<S> Drop for Metered<S> {
+	fn drop(&mut self) {
+		self.metrics.closed(self.start.elapsed());
+	}
+}
+
+/// The connections of every service and transport started so far, as the
+/// number of currently open and of all accepted connections (both 0 for
+/// datagram transports), sorted by service and transport
+pub fn connections() -> Vec<(&'static str, Transport, u64, u64)> {
+	let mut all = METRICS
+		.lock()
+		.expect("metrics lock poisoned")
+		.iter()
+		.map(|m| {
+			// Read `closed` first, so the result is never negative
+			let closed = m.closed.load(Ordering::Relaxed);
+			let accepted = m.accepted.load(Ordering::Relaxed);
+			(
+				m.service,
+				m.transport,
+				accepted.saturating_sub(closed),
+				accepted,
+			)
+		})
+		.collect::<Vec<_>>();
+
+	all.sort_by_key(|&(service, transport, ..)| (service, transport));
+	all
+}
+
+/// Render all metrics in the Prometheus text exposition format
+pub fn render() -> String {
+	let mut all = METRICS.lock().expect("metrics lock poisoned").clone();
+	all.sort_by_key(|m| (m.service, m.transport));
+
+	let mut out = String::new();
+	let streams = all.iter().filter(|m| m.transport.is_stream());
+	let dgrams = all.iter().filter(|m| !m.transport.is_stream());
+
+	let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+	family(
+		&mut out,
+		"connections_accepted_total",
+		"counter",
+		"Connections accepted",
+		streams.clone().map(|m| (*m, load(&m.accepted))),
+	);
+	family(
+		&mut out,
+		"connections_active",
+		"gauge",
+		"Connections currently open",
+		streams.clone().map(|m| {
+			// Read `closed` first, so the result is never negative
+			let closed = load(&m.closed);
+			(*m, load(&m.accepted).saturating_sub(closed))
+		}),
+	);
+	family(
+		&mut out,
+		"connections_closed_total",
+		"counter",
+		"Connections closed",
+		streams.map(|m| (*m, load(&m.closed))),
+	);
+	family(
+		&mut out,
+		"datagrams_received_total",
+		"counter",
+		"Datagrams received",
+		dgrams.clone().map(|m| (*m, load(&m.received))),
+	);
+	family(
+		&mut out,
+		"datagrams_sent_total",
+		"counter",
+		"Datagrams sent",
+		dgrams.map(|m| (*m, load(&m.sent))),
+	);
+	family(
+		&mut out,
+		"received_bytes_total",
+		"counter",
+		"Bytes received",
+		all.iter().map(|m| (*m, load(&m.bytes_received))),
+	);
+	family(
+		&mut out,
+		"sent_bytes_total",
+		"counter",
+		"Bytes sent",
+		all.iter().map(|m| (*m, load(&m.bytes_sent))),
+	);
+
+	header(
+		&mut out,
+		"errors_total",
+		"counter",
+		"Errors handling connections or datagrams, by kind",
+	);
+	for m in &all {
+		for (kind, n) in m.errors.lock().expect("metrics lock poisoned").iter() {
+			let _ = writeln!(
+				out,
+				"simple_protocols_errors_total{{{},kind=\"{kind}\"}} {n}",
+				labels(m)
+			);
+		}
+	}
+
+	header(
+		&mut out,
+		"session_duration_seconds",
+		"histogram",
+		"How long connections were open for",
+	);
+	for m in all.iter().filter(|m| m.transport.is_stream()) {
+		let mut count = 0;
+		for (i, bucket) in m.durations.iter().enumerate() {
+			count += load(bucket);
+			let le = DURATION_BUCKETS
+				.get(i)
+				.map_or_else(|| "+Inf".to_string(), f64::to_string);
+			let _ = writeln!(
+				out,
+				"simple_protocols_session_duration_seconds_bucket{{{},le=\"{le}\"}} {count}",
+				labels(m)
+			);
+		}
+
+		let sum = Duration::from_nanos(load(&m.duration_sum)).as_secs_f64();
+		let _ = writeln!(
+			out,
+			"simple_protocols_session_duration_seconds_sum{{{}}} {sum}",
+			labels(m)
+		);
+		let _ = writeln!(
+			out,
+			"simple_protocols_session_duration_seconds_count{{{}}} {count}",
+			labels(m)
+		);
+	}
+
+	header(
+		&mut out,
+		"udp_dropped_total",
+		"counter",
+		"UDP responses dropped by the anti-amplification limits, by reason",
+	);
+	for (service, rate_limited, too_large) in udp::dropped() {
+		for (reason, n) in [("rate_limit", rate_limited), ("too_large", too_large)] {
+			let _ = writeln!(
+				out,
+				"simple_protocols_udp_dropped_total{{service=\"{service}\",reason=\"{reason}\"}} \
+				 {n}"
+			);
+		}
+	}
+
+	let threads = executor::stats();
+	header(
+		&mut out,
+		"worker_polls_total",
+		"counter",
+		"How many times worker threads polled tasks",
+	);
+	for (thread, polls, _) in &threads {
+		let _ = writeln!(
+			out,
+			"simple_protocols_worker_polls_total{{thread=\"{thread}\"}} {polls}"
+		);
+	}
+	header(
+		&mut out,
+		"worker_busy_seconds_total",
+		"counter",
+		"How long worker threads spent polling tasks",
+	);
+	for (thread, _, busy) in &threads {
+		let _ = writeln!(
+			out,
+			"simple_protocols_worker_busy_seconds_total{{thread=\"{thread}\"}} {}",
+			busy.as_secs_f64()
+		);
+	}
+
+	out
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+	let _ = writeln!(out, "# HELP simple_protocols_{name} {help}");
+	let _ = writeln!(out, "# TYPE simple_protocols_{name} {ty}");
+}
+
+fn labels(metrics: &Metrics) -> String {
+	format!(
+		"service=\"{}\",transport=\"{}\"",
+		metrics.service,
+		metrics.transport.name()
+	)
+}
+
+fn family<'a>(
+	out: &mut String,
+	name: &str,
+	ty: &str,
+	help: &str,
+	values: impl Iterator<Item = (&'a Metrics, u64)>,
+) {
+	header(out, name, ty, help);
+
+	for (metrics, value) in values {
+		let _ = writeln!(
+			out,
+			"simple_protocols_{name}{{{}}} {value}",
+			labels(metrics)
+		);
+	}
+}
+
+/// Start serving the metrics over HTTP on the configured address
+pub fn serve(config: &Config) -> Result<Handler, ServiceErr> {
+	let Some(addr) = config.metrics else {
+		return Err(ServiceErr::NoHandler);
+	};
+
+	info!("starting metrics endpoint on {addr}");
+
+	let socket = utils::bind(
+		"metrics",
+		Some(addr.ip()),
+		addr.port(),
+		false,
+		Type::STREAM,
+		Protocol::TCP,
+		|_| Ok(()),
+	)?
+	.pop()
+	.ok_or_else(|| anyhow!("couldn't bind the metrics endpoint to {addr}"))?;
+
+	socket.listen(128)?;
+	let listener = TcpListener::from(Async::new_nonblocking(StdListener::from(socket))?);
+	let addrs: Vec<SocketAddr> = vec![listener.local_addr()?];
+
+	let task = spawn(async move {
+		loop {
+			match listener.accept().await {
+				Ok((stream, peer)) => {
+					debug!("New metrics request from {peer}");
+					spawn(handle(stream)).detach();
+				}
+				Err(e) => warn!("Metrics `accept` error: {e}"),
+			}
+		}
+	});
+
+	Ok(Handler {
+		addrs,
+		tasks: vec![task],
+	})
+}
+
+/// Answer a single HTTP request, with the metrics if it's for `/metrics`
+async fn handle(mut stream: TcpStream) {
+	let mut buf = vec![0; MAX_REQUEST_LEN];
+	let mut len = 0;
+
+	let read = async {
+		while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+			if len == buf.len() {
+				return Err(anyhow!("request too large"));
+			}
+
+			match stream.read(&mut buf[len..]).await? {
+				0 => return Err(anyhow!("connection closed")),
+				n => len += n,
+			}
+		}
+
+		Ok(())
+	};
+
+	let timeout = async {
+		Timer::after(REQUEST_TIMEOUT).await;
+		Err(anyhow!("timed out"))
+	};
+
+	if let Err(e) = future::or(read, timeout).await {
+		debug!("Invalid metrics request: {e}");
+		return;
+	}
+
+	let response = respond(&buf[..len]);
+
+	if let Err(e) = stream.write_all(&response).await {
+		debug!("Error sending metrics: {e}");
+	}
+}
+
+/// Build the HTTP response to a request
+fn respond(request: &[u8]) -> Vec<u8> {
+	let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+	let mut parts = line.split(|&b| b == b' ');
+
+	let (status, body, head) = match (parts.next(), parts.next()) {
+		(Some(method @ (b"GET" | b"HEAD")), Some(b"/metrics")) => {
+			("200 OK", render(), method == b"HEAD")
+		}
+		(Some(b"GET" | b"HEAD"), _) => ("404 Not Found", "not found\n".to_string(), false),
+		_ => (
+			"405 Method Not Allowed",
+			"method not allowed\n".to_string(),
+			false,
+		),
+	};
+
+	let mut response = format!(
+		"HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; \
+		 charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+		body.len()
+	)
+	.into_bytes();
+
+	if !head {
+		response.extend_from_slice(body.as_bytes());
+	}
+
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn counters() {
+		let tcp = get("test-counters", Transport::Tcp);
+		assert!(std::ptr::eq(tcp, get("test-counters", Transport::Tcp)));
+
+		drop(Metered::new((), tcp, Peer::Unknown, Peer::Unknown));
+		tcp.error("not_found");
+		tcp.error("not_found");
+		tcp.error("io");
+
+		let udp = get("test-counters", Transport::Udp);
+		udp.received(10);
+		udp.sent(20);
+
+		let rendered = render();
+		for line in [
+			r#"simple_protocols_connections_accepted_total{service="test-counters",transport="tcp"} 1"#,
+			r#"simple_protocols_connections_active{service="test-counters",transport="tcp"} 0"#,
+			r#"simple_protocols_connections_closed_total{service="test-counters",transport="tcp"} 1"#,
+			r#"simple_protocols_datagrams_received_total{service="test-counters",transport="udp"} 1"#,
+			r#"simple_protocols_datagrams_sent_total{service="test-counters",transport="udp"} 1"#,
+			r#"simple_protocols_received_bytes_total{service="test-counters",transport="udp"} 10"#,
+			r#"simple_protocols_sent_bytes_total{service="test-counters",transport="udp"} 20"#,
+			r#"simple_protocols_errors_total{service="test-counters",transport="tcp",kind="not_found"} 2"#,
+			r#"simple_protocols_errors_total{service="test-counters",transport="tcp",kind="io"} 1"#,
+			r#"simple_protocols_session_duration_seconds_bucket{service="test-counters",transport="tcp",le="0.01"} 1"#,
+			r#"simple_protocols_session_duration_seconds_bucket{service="test-counters",transport="tcp",le="+Inf"} 1"#,
+			r#"simple_protocols_session_duration_seconds_count{service="test-counters",transport="tcp"} 1"#,
+		] {
+			assert!(rendered.lines().any(|l| l == line), "missing {line:?}");
+		}
+
+		assert!(
+			!rendered.contains("datagrams_sent_total{service=\"test-counters\",transport=\"tcp\"}")
+		);
+		assert!(
+			!rendered.contains(
+				"connections_accepted_total{service=\"test-counters\",transport=\"udp\"}"
+			)
+		);
+	}
+
+	#[test]
+	fn duration_buckets() {
+		let metrics = get("test-durations", Transport::Unix);
+		metrics.closed(Duration::from_millis(50));
+		metrics.closed(Duration::from_secs(5));
+		metrics.closed(Duration::from_secs(7200));
+
+		let buckets = metrics
+			.durations
+			.iter()
+			.map(|b| b.load(Ordering::Relaxed))
+			.collect::<Vec<_>>();
+		assert_eq!(buckets, [0, 1, 0, 1, 0, 0, 0, 1]);
+	}
+
+	#[test]
+	fn responses() {
+		let ok = String::from_utf8(respond(b"GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n")).unwrap();
+		assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(ok.contains("# TYPE simple_protocols_connections_accepted_total counter\n"));
+
+		let head = String::from_utf8(respond(b"HEAD /metrics HTTP/1.1\r\n\r\n")).unwrap();
+		assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(head.ends_with("\r\n\r\n"));
+
+		let not_found = String::from_utf8(respond(b"GET / HTTP/1.1\r\n\r\n")).unwrap();
+		assert!(not_found.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+		let not_allowed = String::from_utf8(respond(b"POST /metrics HTTP/1.1\r\n\r\n")).unwrap();
+		assert!(not_allowed.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+	}
+}
@@ -0,0 +1,635 @@
+//! A writable in-memory overlay over a file system [`Root`], for protocols
+//! that upload files
+//!
+//! Changes are never written to the root itself, and are gone once the overlay
+//! is dropped (e.g. when the server is restarted). They're either seen by all
+//! sessions of an overlay, or only by the session that made them.
+
+use std::{
+	borrow::Cow,
+	collections::BTreeMap,
+	sync::{Arc, Mutex, MutexGuard},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+	fs::{self, Entry, FsError, Kind, MAX_FILE_SIZE, Metadata, Root},
+	status,
+};
+
+/// Who sees the changes made in a session of an overlay
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Visibility {
+	/// All sessions share their changes
+	#[default]
+	Global,
+	/// Every session only sees its own changes
+	Session,
+}
+
+/// Limits on the changes of an overlay (or with [`Visibility::Session`], of
+/// every session)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+	/// The most bytes in all written files together
+	pub bytes: u64,
+	/// The most files and directories written, created, or removed
+	pub entries: usize,
+}
+
+impl Default for Quota {
+	fn default() -> Self {
+		Self {
+			bytes: MAX_FILE_SIZE,
+			entries: 1000,
+		}
+	}
+}
+
+/// A change to the entry at a normalized path
+#[derive(Debug)]
+enum Node {
+	File {
+		contents: Vec<u8>,
+		mtime: u64,
+	},
+	Directory {
+		mtime: u64,
+	},
+	/// Hides the root's entry
+	Removed,
+}
+
+/// The changes of an overlay, or of one of its sessions
+#[derive(Debug, Default)]
+struct Layer {
+	nodes: BTreeMap<Vec<u8>, Node>,
+	/// The size of all files in `nodes` together
+	bytes: u64,
+}
+
+/// A writable in-memory overlay over a [`Root`]
+#[derive(Debug)]
+pub struct Overlay<'r> {
+	root: Root<'r>,
+	quota: Quota,
+	visibility: Visibility,
+	/// The changes shared by all sessions with [`Visibility::Global`]
+	global: Arc<Mutex<Layer>>,
+}
+
+impl<'r> Overlay<'r> {
+	/// Layer an empty overlay over `root`
+	pub fn new(root: Root<'r>, quota: Quota, visibility: Visibility) -> Self {
+		Self {
+			root,
+			quota,
+			visibility,
+			global: Arc::default(),
+		}
+	}
+
+	/// Start a session, seeing the changes of all sessions or only its own,
+	/// depending on the overlay's visibility
+	pub fn session(&self) -> Session<'r> {
+		Session {
+			root: self.root,
+			quota: self.quota,
+			layer: match self.visibility {
+				Visibility::Global => Arc::clone(&self.global),
+				Visibility::Session => Arc::default(),
+			},
+		}
+	}
+}
+
+/// A session of an [`Overlay`], reading and changing its files
+#[derive(Debug, Clone)]
+pub struct Session<'r> {
+	root: Root<'r>,
+	quota: Quota,
+	layer: Arc<Mutex<Layer>>,
+}
+
+impl Session<'_> {
+	/// Read the entry at the absolute `path` like [`Root::read`], with the
+	/// changes seen by this session
+	pub async fn read<'p>(&self, path: &'p [u8]) -> Result<Entry<'static>, FsError<'p>> {
+		let (normalized, directory) = fs::normalize(path)?;
+		// The root is read before locking the changes, so other sessions can
+		// go on meanwhile
+		let below = self.root.read(&normalized).await;
+
+		let entry = self
+			.lookup(&self.lock(), &normalized, below)
+			.map_err(|e| e.with_path(path))?;
+
+		if directory && !entry.is_directory() {
+			return Err(FsError::NotFound(path));
+		}
+
+		Ok(entry)
+	}
+
+	/// Write the file at the absolute `path`, replacing it if it exists
+	pub async fn write<'p>(&self, path: &'p [u8], contents: &[u8]) -> Result<(), FsError<'p>> {
+		let (normalized, directory) = fs::normalize(path)?;
+
+		if directory {
+			return Err(FsError::InvalidPath(path));
+		}
+
+		if contents.len() as u64 > MAX_FILE_SIZE {
+			return Err(FsError::TooLarge(path));
+		}
+
+		let below = self.root.read(&normalized).await;
+		let parent_below = self.root.read(parent(&normalized)).await;
+
+		let mut layer = self.lock();
+		self.check_writable(&layer, path, &normalized, parent_below)?;
+
+		let replaced = match self.lookup(&layer, &normalized, below) {
+			Ok(Entry::Directory { .. }) => return Err(FsError::Exists(path)),
+			Ok(Entry::File { .. }) | Err(FsError::NotFound(_)) => {
+				match layer.nodes.get(&normalized) {
+					Some(Node::File { contents, .. }) => contents.len() as u64,
+					_ => 0,
+				}
+			}
+			Err(e) => return Err(e.with_path(path)),
+		};
+
+		let bytes = layer.bytes - replaced + contents.len() as u64;
+		self.check_quota(&layer, path, &normalized, bytes)?;
+
+		layer.bytes = bytes;
+		layer.nodes.insert(normalized, Node::File {
+			contents: contents.to_vec(),
+			mtime: now(),
+		});
+
+		Ok(())
+	}
+
+	/// Create a directory at the absolute `path`
+	pub async fn create_dir<'p>(&self, path: &'p [u8]) -> Result<(), FsError<'p>> {
+		let (normalized, _) = fs::normalize(path)?;
+		let below = self.root.read(&normalized).await;
+		let parent_below = self.root.read(parent(&normalized)).await;
+
+		let mut layer = self.lock();
+		self.check_writable(&layer, path, &normalized, parent_below)?;
+
+		match self.lookup(&layer, &normalized, below) {
+			Ok(_) => return Err(FsError::Exists(path)),
+			Err(FsError::NotFound(_)) => {}
+			Err(e) => return Err(e.with_path(path)),
+		}
+
+		self.check_quota(&layer, path, &normalized, layer.bytes)?;
+		layer
+			.nodes
+			.insert(normalized, Node::Directory { mtime: now() });
+
+		Ok(())
+	}
+
+	/// Remove the file or empty directory at the absolute `path`
+	pub async fn remove<'p>(&self, path: &'p [u8]) -> Result<(), FsError<'p>> {
+		let (normalized, _) = fs::normalize(path)?;
+		let below = self.root.read(&normalized).await;
+		let parent_below = self.root.read(parent(&normalized)).await;
+		let in_root = below.is_ok();
+
+		let mut layer = self.lock();
+		self.check_writable(&layer, path, &normalized, parent_below)?;
+
+		match self
+			.lookup(&layer, &normalized, below)
+			.map_err(|e| e.with_path(path))?
+		{
+			Entry::Directory { entries, .. } if !entries.is_empty() => {
+				return Err(FsError::NotEmpty(path));
+			}
+			_ => {}
+		}
+
+		// The root's entry has to be hidden, but changes can just be dropped
+		let removed = if in_root {
+			self.check_quota(&layer, path, &normalized, layer.bytes)?;
+			layer.nodes.insert(normalized, Node::Removed)
+		} else {
+			layer.nodes.remove(&normalized)
+		};
+
+		if let Some(Node::File { contents, .. }) = removed {
+			layer.bytes -= contents.len() as u64;
+		}
+
+		Ok(())
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Layer> {
+		self.layer.lock().expect("overlay lock poisoned")
+	}
+
+	/// Look up the entry at the `normalized` path, with the changes in `layer`
+	/// applied to the entry `below` it in the root
+	fn lookup<'n>(
+		&self,
+		layer: &Layer,
+		normalized: &'n [u8],
+		below: Result<Entry<'static>, FsError<'n>>,
+	) -> Result<Entry<'static>, FsError<'n>> {
+		let slash = normalized.iter().rposition(|&b| b == b'/').unwrap_or(0);
+		let name = &normalized[slash + 1..];
+
+		let entry = match layer.nodes.get(normalized) {
+			Some(Node::Removed) => return Err(FsError::NotFound(normalized)),
+			Some(file @ Node::File { .. }) => {
+				return Ok(node_entry(name, file).expect("files have an entry"));
+			}
+			Some(Node::Directory { mtime }) => Entry::Directory {
+				name: name_of(name).into(),
+				entries: match below {
+					Ok(Entry::Directory { entries, .. }) => entries,
+					_ => Cow::Borrowed(&[]),
+				},
+				meta: Metadata {
+					size: 0,
+					mtime: *mtime,
+					mode: 0o755,
+				},
+			},
+			None => below?,
+		};
+
+		let Entry::Directory {
+			name,
+			entries,
+			meta,
+		} = entry
+		else {
+			return Ok(entry);
+		};
+
+		let mut prefix = normalized.to_vec();
+		if prefix != b"/" {
+			prefix.push(b'/');
+		}
+
+		// Changed entries of the root are replaced by their changes
+		let mut merged = entries
+			.iter()
+			.filter(|e| {
+				let path = [&prefix[..], e.name().as_bytes()].concat();
+				!layer.nodes.contains_key(&path)
+			})
+			.cloned()
+			.collect::<Vec<_>>();
+
+		for (path, node) in layer.nodes.range(prefix.clone()..) {
+			let Some(child) = path.strip_prefix(&prefix[..]) else {
+				break;
+			};
+
+			if !child.contains(&b'/') {
+				merged.extend(node_entry(child, node));
+			}
+		}
+
+		merged.sort_unstable_by(|a, b| a.name().cmp(b.name()));
+
+		Ok(Entry::Directory {
+			name,
+			entries: merged.into(),
+			meta,
+		})
+	}
+
+	/// Check that the entry at the `normalized` path can be changed, which
+	/// needs its parent directory to exist (given the entry `parent_below` it
+	/// in the root)
+	fn check_writable<'p>(
+		&self,
+		layer: &Layer,
+		path: &'p [u8],
+		normalized: &[u8],
+		parent_below: Result<Entry<'static>, FsError<'_>>,
+	) -> Result<(), FsError<'p>> {
+		if normalized == b"/" || status::contains(normalized) {
+			return Err(FsError::ReadOnly(path));
+		}
+
+		match self.lookup(layer, parent(normalized), parent_below) {
+			Ok(Entry::Directory { .. }) => Ok(()),
+			Ok(Entry::File { .. }) => Err(FsError::NotFound(path)),
+			Err(e) => Err(e.with_path(path)),
+		}
+	}
+
+	/// Check that changing the entry at the `normalized` path (leaving
+	/// `bytes` in all files) stays within the quota
+	fn check_quota<'p>(
+		&self,
+		layer: &Layer,
+		path: &'p [u8],
+		normalized: &[u8],
+		bytes: u64,
+	) -> Result<(), FsError<'p>> {
+		let added = !layer.nodes.contains_key(normalized);
+
+		if bytes > self.quota.bytes || added && layer.nodes.len() >= self.quota.entries {
+			Err(FsError::QuotaExceeded(path))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// The parent directory of the `normalized` path
+fn parent(normalized: &[u8]) -> &[u8] {
+	match normalized.iter().rposition(|&b| b == b'/').unwrap_or(0) {
+		0 => b"/",
+		slash => &normalized[..slash],
+	}
+}
+
+/// The entry of a changed file or directory (with its entries left out)
+fn node_entry(name: &[u8], node: &Node) -> Option<Entry<'static>> {
+	match node {
+		Node::File { contents, mtime } => Some(Entry::File {
+			name: name_of(name).into(),
+			contents: contents.clone().into(),
+			kind: Kind::of(contents),
+			meta: Metadata {
+				size: contents.len() as u64,
+				mtime: *mtime,
+				mode: 0o644,
+			},
+		}),
+		Node::Directory { mtime } => Some(Entry::Directory {
+			name: name_of(name).into(),
+			entries: Cow::Borrowed(&[]),
+			meta: Metadata {
+				size: 0,
+				mtime: *mtime,
+				mode: 0o755,
+			},
+		}),
+		Node::Removed => None,
+	}
+}
+
+fn name_of(name: &[u8]) -> String {
+	String::from_utf8(name.to_vec()).expect("valid paths are ASCII")
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+	use smol::block_on;
+
+	use super::*;
+
+	fn names(entry: Entry<'_>) -> Vec<String> {
+		let Entry::Directory { entries, .. } = entry else {
+			panic!("not a directory");
+		};
+
+		entries.iter().map(|e| e.name().to_string()).collect()
+	}
+
+	#[test]
+	fn write_read() {
+		let overlay = Overlay::new(Root::Embedded, Quota::default(), Visibility::Global);
+		let session = overlay.session();
+
+		block_on(session.write(b"/upload.txt", b"Hello, World!")).unwrap();
+		let Ok(Entry::File {
+			name,
+			contents,
+			kind,
+			meta,
+		}) = block_on(session.read(b"//upload.txt"))
+		else {
+			panic!();
+		};
+
+		assert_eq!(name, "upload.txt");
+		assert_eq!(contents.bytes(), b"Hello, World!");
+		assert_eq!(kind, Kind::Text);
+		assert_eq!(meta.size, 13);
+
+		block_on(session.write(b"/upload.txt", b"\0")).unwrap();
+		assert!(matches!(
+			block_on(session.read(b"/upload.txt")),
+			Ok(Entry::File {
+				kind: Kind::Binary,
+				..
+			})
+		));
+
+		// The root itself is left alone
+		assert!(matches!(
+			fs::read(b"/upload.txt"),
+			Err(FsError::NotFound(_))
+		));
+
+		let root = names(block_on(session.read(b"/")).unwrap());
+		assert!(root.contains(&"upload.txt".to_string()));
+		assert!(root.contains(&"Cargo.toml".to_string()));
+		assert!(root.is_sorted());
+
+		// Replacing files of the root
+		block_on(session.write(b"/src/fs.rs", b"replaced")).unwrap();
+		let Ok(Entry::File { contents, .. }) = block_on(session.read(b"/src/fs.rs")) else {
+			panic!();
+		};
+		assert_eq!(contents.bytes(), b"replaced");
+		assert_eq!(
+			names(block_on(session.read(b"/src")).unwrap())
+				.iter()
+				.filter(|name| *name == "fs.rs")
+				.count(),
+			1
+		);
+	}
+
+	#[test]
+	fn directories() {
+		let overlay = Overlay::new(Root::Embedded, Quota::default(), Visibility::Global);
+		let session = overlay.session();
+
+		block_on(session.create_dir(b"/uploads/")).unwrap();
+		block_on(session.create_dir(b"/uploads/nested")).unwrap();
+		block_on(session.write(b"/uploads/nested/file", b"data")).unwrap();
+
+		assert_eq!(names(block_on(session.read(b"/uploads")).unwrap()), [
+			"nested"
+		]);
+		assert_eq!(
+			names(block_on(session.read(b"/uploads/nested/")).unwrap()),
+			["file"]
+		);
+
+		assert!(matches!(
+			block_on(session.create_dir(b"/uploads")),
+			Err(FsError::Exists(_))
+		));
+		assert!(matches!(
+			block_on(session.create_dir(b"/src")),
+			Err(FsError::Exists(_))
+		));
+		assert!(matches!(
+			block_on(session.write(b"/uploads", b"")),
+			Err(FsError::Exists(_))
+		));
+		assert!(matches!(
+			block_on(session.write(b"/nothing/file", b"")),
+			Err(FsError::NotFound(_))
+		));
+		assert!(matches!(
+			block_on(session.write(b"/Cargo.toml/file", b"")),
+			Err(FsError::NotFound(_))
+		));
+		assert!(matches!(
+			block_on(session.write(b"/uploads/", b"")),
+			Err(FsError::InvalidPath(_))
+		));
+		assert!(matches!(
+			block_on(session.write(b"/../file", b"")),
+			Err(FsError::OutsideRoot(_))
+		));
+		assert!(matches!(
+			block_on(session.write(b"/.status/uptime", b"")),
+			Err(FsError::ReadOnly(_))
+		));
+		assert!(matches!(
+			block_on(session.create_dir(b"/")),
+			Err(FsError::ReadOnly(_))
+		));
+	}
+
+	#[test]
+	fn remove() {
+		let overlay = Overlay::new(Root::Embedded, Quota::default(), Visibility::Global);
+		let session = overlay.session();
+
+		assert!(matches!(
+			block_on(session.remove(b"/src/services")),
+			Err(FsError::NotEmpty(_))
+		));
+		assert!(matches!(
+			block_on(session.remove(b"/nothing")),
+			Err(FsError::NotFound(_))
+		));
+
+		block_on(session.remove(b"/src/services/echo.rs")).unwrap();
+		assert!(matches!(
+			block_on(session.read(b"/src/services/echo.rs")),
+			Err(FsError::NotFound(_))
+		));
+		assert!(
+			!names(block_on(session.read(b"/src/services")).unwrap())
+				.contains(&"echo.rs".to_string())
+		);
+
+		let Entry::Directory { entries, .. } = block_on(session.read(b"/src/services")).unwrap()
+		else {
+			panic!();
+		};
+		for entry in entries.iter() {
+			let path = format!("/src/services/{}", entry.name());
+			if entry.is_directory() {
+				for name in names(block_on(session.read(path.as_bytes())).unwrap()) {
+					block_on(session.remove(format!("{path}/{name}").as_bytes())).unwrap();
+				}
+			}
+			block_on(session.remove(path.as_bytes())).unwrap();
+		}
+
+		block_on(session.remove(b"/src/services")).unwrap();
+		assert!(!names(block_on(session.read(b"/src")).unwrap()).contains(&"services".to_string()));
+
+		// Entries of the root stay hidden in a new directory in their place
+		block_on(session.create_dir(b"/src/services")).unwrap();
+		assert!(names(block_on(session.read(b"/src/services")).unwrap()).is_empty());
+		assert!(matches!(
+			block_on(session.read(b"/src/services/echo.rs")),
+			Err(FsError::NotFound(_))
+		));
+
+		// Written files are just dropped
+		block_on(session.write(b"/file", b"data")).unwrap();
+		block_on(session.remove(b"/file")).unwrap();
+		assert!(matches!(
+			block_on(session.read(b"/file")),
+			Err(FsError::NotFound(_))
+		));
+		block_on(session.write(b"/file", b"data")).unwrap();
+	}
+
+	#[test]
+	fn quota() {
+		let quota = Quota {
+			bytes: 10,
+			entries: 3,
+		};
+		let overlay = Overlay::new(Root::Embedded, quota, Visibility::Global);
+		let session = overlay.session();
+
+		block_on(session.write(b"/a", b"12345")).unwrap();
+		assert!(matches!(
+			block_on(session.write(b"/b", b"123456")),
+			Err(FsError::QuotaExceeded(_))
+		));
+
+		// Replacing a file only counts its new size
+		block_on(session.write(b"/a", b"1234567890")).unwrap();
+		block_on(session.write(b"/a", b"1")).unwrap();
+		block_on(session.write(b"/b", b"123456789")).unwrap();
+		block_on(session.remove(b"/a")).unwrap();
+		block_on(session.write(b"/c", b"1")).unwrap();
+
+		block_on(session.create_dir(b"/d")).unwrap();
+		assert!(matches!(
+			block_on(session.create_dir(b"/e")),
+			Err(FsError::QuotaExceeded(_))
+		));
+		assert!(matches!(
+			block_on(session.remove(b"/Cargo.toml")),
+			Err(FsError::QuotaExceeded(_))
+		));
+
+		let err = block_on(session.create_dir(b"/e")).unwrap_err();
+		assert_eq!(err.kind(), "quota_exceeded");
+		assert!(format!("{err}").contains("/e"));
+	}
+
+	#[test]
+	fn visibility() {
+		let global = Overlay::new(Root::Embedded, Quota::default(), Visibility::Global);
+		block_on(global.session().write(b"/shared", b"data")).unwrap();
+		assert!(block_on(global.session().read(b"/shared")).is_ok());
+
+		let separate = Overlay::new(Root::Embedded, Quota::default(), Visibility::Session);
+		let session = separate.session();
+		block_on(session.write(b"/own", b"data")).unwrap();
+		assert!(block_on(session.clone().read(b"/own")).is_ok());
+		assert!(matches!(
+			block_on(separate.session().read(b"/own")),
+			Err(FsError::NotFound(_))
+		));
+		assert!(matches!(
+			block_on(separate.session().read(b"/shared")),
+			Err(FsError::NotFound(_))
+		));
+	}
+}
@@ -0,0 +1,172 @@
+//! Dropping root privileges once all listeners are bound
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Error, anyhow};
+
+use crate::services::Config;
+
+/// Where the file at `path` can be found once the root directory is changed
+/// to `root` (if it is), or an error if it can't be reached from inside it
+///
+/// Both paths are taken as they are, so `path` has to be absolute and start
+/// with `root`.
+pub fn in_root(root: Option<&Path>, path: &Path) -> Result<PathBuf, Error> {
+	let Some(root) = root else {
+		return Ok(path.to_path_buf());
+	};
+
+	match path.strip_prefix(root) {
+		Ok(inside) if path.is_absolute() => Ok(Path::new("/").join(inside)),
+		_ => Err(anyhow!(
+			"{} can't be read after changing the root directory to {}, as it isn't an absolute \
+			 path inside of it",
+			path.display(),
+			root.display()
+		)),
+	}
+}
+
+/// Check that the files read while running (the content directory, and the
+/// access log, which is reopened on reload) can be reached after changing the
+/// root directory
+pub fn check(config: &Config) -> Result<(), Error> {
+	let access_log = config
+		.access_log
+		.as_deref()
+		.filter(|path| *path != Path::new("-"));
+
+	for path in config.content_dir.as_deref().into_iter().chain(access_log) {
+		in_root(config.chroot.as_deref(), path)?;
+	}
+
+	Ok(())
+}
+
+/// Change the root directory, group, and user of the process as configured,
+/// then log the effective UID and GID
+///
+/// This must only be called once all listeners are bound, because privileged
+/// ports can't be bound afterwards.
+#[cfg(unix)]
+pub fn drop(config: &Config) -> Result<(), Error> {
+	use std::{env, os::unix::fs::chroot};
+
+	use anyhow::Context;
+	use log::info;
+	use nix::unistd::{self, Gid, Uid};
+
+	// Users and groups are looked up before `chroot`, which hides `/etc/passwd`
+	// and `/etc/group`
+	let user = config.user.as_deref().map(user).transpose()?;
+	let gid = match (config.group.as_deref(), &user) {
+		(Some(name), _) => Some(group(name)?),
+		(None, Some((_, Some(gid)))) => Some(*gid),
+		(None, Some((uid, None))) => {
+			return Err(anyhow!(
+				"user {uid} has no primary group, so a group needs to be set too"
+			));
+		}
+		(None, None) => None,
+	};
+
+	if let Some(path) = &config.chroot {
+		chroot(path).with_context(|| format!("couldn't chroot to {}", path.display()))?;
+		env::set_current_dir("/").context("couldn't change directory to the new root")?;
+		info!("Changed root directory to {}", path.display());
+	}
+
+	if let Some(gid) = gid {
+		#[cfg(not(any(target_vendor = "apple", target_os = "redox", target_os = "haiku")))]
+		unistd::setgroups(&[gid]).context("couldn't set supplementary groups")?;
+		unistd::setgid(gid).with_context(|| format!("couldn't set group to {gid}"))?;
+	}
+
+	if let Some((uid, _)) = user {
+		unistd::setuid(uid).with_context(|| format!("couldn't set user to {uid}"))?;
+	}
+
+	info!(
+		"Running with effective UID {} and GID {}",
+		Uid::effective(),
+		Gid::effective()
+	);
+
+	Ok(())
+}
+
+/// Dropping privileges is only supported on Unix, so fail if it's configured
+#[cfg(not(unix))]
+pub fn drop(config: &Config) -> Result<(), Error> {
+	if config.user.is_some() || config.group.is_some() || config.chroot.is_some() {
+		return Err(anyhow!("dropping privileges is only supported on Unix"));
+	}
+
+	Ok(())
+}
+
+/// Look up a user by name or numeric UID, returning its UID and primary GID
+///
+/// Numeric UIDs without a user database entry have no known primary group.
+#[cfg(unix)]
+fn user(name: &str) -> Result<(nix::unistd::Uid, Option<nix::unistd::Gid>), Error> {
+	use nix::unistd::{Uid, User};
+
+	if let Ok(uid) = name.parse() {
+		let uid = Uid::from_raw(uid);
+		return Ok((uid, User::from_uid(uid)?.map(|user| user.gid)));
+	}
+
+	User::from_name(name)?
+		.map(|user| (user.uid, Some(user.gid)))
+		.ok_or_else(|| anyhow!("unknown user \"{name}\""))
+}
+
+/// Look up a group by name or numeric GID
+#[cfg(unix)]
+fn group(name: &str) -> Result<nix::unistd::Gid, Error> {
+	use nix::unistd::{Gid, Group};
+
+	if let Ok(gid) = name.parse() {
+		return Ok(Gid::from_raw(gid));
+	}
+
+	Group::from_name(name)?
+		.map(|group| group.gid)
+		.ok_or_else(|| anyhow!("unknown group \"{name}\""))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use nix::unistd::{Gid, Uid};
+
+	use super::*;
+
+	#[test]
+	fn lookup() {
+		let root = (Uid::from_raw(0), Some(Gid::from_raw(0)));
+		assert_eq!(user("root").unwrap(), root);
+		assert_eq!(user("0").unwrap(), root);
+		assert_eq!(group("0").unwrap(), Gid::from_raw(0));
+		assert_eq!(group("12345").unwrap(), Gid::from_raw(12345));
+		assert!(user("no-such-user-hopefully").is_err());
+		assert!(group("no-such-group-hopefully").is_err());
+	}
+
+	#[test]
+	fn paths_in_root() {
+		let root = Some(Path::new("/srv"));
+		assert_eq!(
+			in_root(root, Path::new("/srv/gopher")).unwrap(),
+			Path::new("/gopher")
+		);
+		assert_eq!(in_root(root, Path::new("/srv")).unwrap(), Path::new("/"));
+		assert_eq!(
+			in_root(None, Path::new("gopher")).unwrap(),
+			Path::new("gopher")
+		);
+		assert!(in_root(root, Path::new("/srv2/gopher")).is_err());
+		assert!(in_root(root, Path::new("/etc")).is_err());
+		assert!(in_root(Some(Path::new("srv")), Path::new("srv/gopher")).is_err());
+	}
+}
@@ -0,0 +1,344 @@
+//! The PROXY protocol
+//! ([spec](https://www.haproxy.org/download/3.0/doc/proxy-protocol.txt)), used
+//! by proxies and load balancers to pass on the client's address
+
+use std::{
+	fmt::{Display, Formatter, Result as FmtResult},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	str::{self, FromStr},
+};
+
+use anyhow::{Error, anyhow};
+use smol::io::{AsyncRead, AsyncReadExt};
+
+/// The signature at the start of every version 2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a version 1 header, including the final CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// A range of IP addresses, such as `192.0.2.0/24` or `2001:db8::/32`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+	addr: IpAddr,
+	prefix_len: u8,
+}
+
+impl IpNet {
+	/// Create a new range of the addresses sharing their first `prefix_len`
+	/// bits with `addr`, with too long prefixes matching just `addr`
+	pub fn new(addr: IpAddr, prefix_len: u8) -> Self {
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+		Self {
+			addr,
+			prefix_len: prefix_len.min(max_len),
+		}
+	}
+
+	/// Whether `ip` (or the IPv4 address mapped to it) is in this range
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.addr, ip.to_canonical()) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX
+					.checked_shl(32 - u32::from(self.prefix_len))
+					.unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX
+					.checked_shl(128 - u32::from(self.prefix_len))
+					.unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+impl FromStr for IpNet {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (addr, prefix_len) = match s.split_once('/') {
+			Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+			None => (s, None),
+		};
+
+		let addr = addr.parse::<IpAddr>()?;
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+		let prefix_len = match prefix_len {
+			Some(len) => len.parse::<u8>()?,
+			None => max_len,
+		};
+
+		if prefix_len > max_len {
+			return Err(anyhow!(
+				"the prefix length of {addr} can be at most {max_len}, but it was {prefix_len}"
+			));
+		}
+
+		Ok(Self { addr, prefix_len })
+	}
+}
+
+impl Display for IpNet {
+	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+		write!(f, "{}/{}", self.addr, self.prefix_len)
+	}
+}
+
+/// Whether connections or datagrams from `ip` come through one of the
+/// `trusted` proxies, and so start with a PROXY protocol header
+pub fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+	trusted.iter().any(|net| net.contains(ip))
+}
+
+/// Read a version 1 or 2 PROXY protocol header from the start of `stream`,
+/// without reading any data after it
+///
+/// Returns the client's address, or `None` if the proxy didn't send one (e.g.
+/// for its own health checks).
+pub async fn read(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, Error> {
+	// Both versions' headers are at least this long
+	let mut header = vec![0; V2_SIGNATURE.len()];
+	stream.read_exact(&mut header).await?;
+
+	if header == V2_SIGNATURE {
+		header.resize(16, 0);
+		stream.read_exact(&mut header[12..]).await?;
+
+		let len = u16::from_be_bytes([header[14], header[15]]);
+		header.resize(16 + usize::from(len), 0);
+		stream.read_exact(&mut header[16..]).await?;
+
+		return parse_v2(&header).map(|(addr, _)| addr);
+	}
+
+	if !header.starts_with(b"PROXY ") {
+		return Err(anyhow!("missing PROXY protocol header"));
+	}
+
+	while !header.ends_with(b"\r\n") {
+		if header.len() >= V1_MAX_LEN {
+			return Err(anyhow!("PROXY protocol header too long"));
+		}
+
+		let mut byte = 0;
+		stream.read_exact(std::slice::from_mut(&mut byte)).await?;
+		header.push(byte);
+	}
+
+	parse_v1(&header)
+}
+
+/// Parse a version 1 (text) header, including the final CRLF
+fn parse_v1(header: &[u8]) -> Result<Option<SocketAddr>, Error> {
+	let header = str::from_utf8(header)?
+		.strip_suffix("\r\n")
+		.ok_or_else(|| anyhow!("unterminated PROXY protocol header"))?;
+
+	let invalid = || anyhow!("invalid PROXY protocol header {header:?}");
+
+	let mut fields = header.split(' ');
+	if fields.next() != Some("PROXY") {
+		return Err(invalid());
+	}
+
+	let ipv4 = match fields.next() {
+		Some("TCP4") => true,
+		Some("TCP6") => false,
+		Some("UNKNOWN") => return Ok(None),
+		_ => return Err(invalid()),
+	};
+
+	let (Some(source), Some(_), Some(port), Some(_), None) = (
+		fields.next(),
+		fields.next(),
+		fields.next(),
+		fields.next(),
+		fields.next(),
+	) else {
+		return Err(invalid());
+	};
+
+	let ip = source.parse::<IpAddr>().map_err(|_| invalid())?;
+	let port = port.parse::<u16>().map_err(|_| invalid())?;
+
+	if ip.is_ipv4() != ipv4 {
+		return Err(invalid());
+	}
+
+	Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Parse a version 2 (binary) header at the start of `buf`, returning the
+/// client's address and the length of the header
+pub fn parse_v2(buf: &[u8]) -> Result<(Option<SocketAddr>, usize), Error> {
+	if buf.len() < 16 || !buf.starts_with(V2_SIGNATURE) {
+		return Err(anyhow!("missing PROXY protocol header"));
+	}
+
+	let len = 16 + usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+	let addrs = buf
+		.get(16..len)
+		.ok_or_else(|| anyhow!("truncated PROXY protocol header"))?;
+
+	let local = match buf[12] {
+		0x20 => true,
+		0x21 => false,
+		_ => return Err(anyhow!("unsupported PROXY protocol version or command")),
+	};
+
+	// The family's addresses come first, followed by any TLVs which are ignored
+	let addr = match buf[13] >> 4 {
+		_ if local => None,
+		0x1 => {
+			let addrs = addrs
+				.first_chunk::<12>()
+				.ok_or_else(|| anyhow!("truncated PROXY protocol IPv4 addresses"))?;
+			let ip = Ipv4Addr::from(*addrs.first_chunk::<4>().expect("12 > 4"));
+			Some(SocketAddr::new(
+				ip.into(),
+				u16::from_be_bytes([addrs[8], addrs[9]]),
+			))
+		}
+		0x2 => {
+			let addrs = addrs
+				.first_chunk::<36>()
+				.ok_or_else(|| anyhow!("truncated PROXY protocol IPv6 addresses"))?;
+			let ip = Ipv6Addr::from(*addrs.first_chunk::<16>().expect("36 > 16"));
+			Some(SocketAddr::new(
+				ip.into(),
+				u16::from_be_bytes([addrs[32], addrs[33]]),
+			))
+		}
+		// Unspecified and Unix domain socket addresses aren't useful here
+		_ => None,
+	};
+
+	Ok((addr, len))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ip_net() {
+		let net = "192.0.2.0/24".parse::<IpNet>().unwrap();
+		assert!(net.contains("192.0.2.1".parse().unwrap()));
+		assert!(net.contains("::ffff:192.0.2.1".parse().unwrap()));
+		assert!(!net.contains("192.0.3.1".parse().unwrap()));
+		assert!(!net.contains("::1".parse().unwrap()));
+
+		let net = "2001:db8::/32".parse::<IpNet>().unwrap();
+		assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+		assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+		let net = "127.0.0.1".parse::<IpNet>().unwrap();
+		assert_eq!(net.to_string(), "127.0.0.1/32");
+		assert!(net.contains(Ipv4Addr::LOCALHOST.into()));
+		assert!(!net.contains("127.0.0.2".parse().unwrap()));
+
+		assert!(
+			"0.0.0.0/0"
+				.parse::<IpNet>()
+				.unwrap()
+				.contains(Ipv4Addr::BROADCAST.into())
+		);
+		assert!(
+			"::/0"
+				.parse::<IpNet>()
+				.unwrap()
+				.contains(Ipv6Addr::LOCALHOST.into())
+		);
+		assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+		assert!("localhost".parse::<IpNet>().is_err());
+	}
+
+	#[test]
+	fn v1() {
+		assert_eq!(
+			parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 7\r\n").unwrap(),
+			Some("192.0.2.1:56324".parse().unwrap())
+		);
+		assert_eq!(
+			parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 7\r\n").unwrap(),
+			Some("[2001:db8::1]:56324".parse().unwrap())
+		);
+		assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+		assert_eq!(
+			parse_v1(b"PROXY UNKNOWN 192.0.2.1 198.51.100.1 56324 7\r\n").unwrap(),
+			None
+		);
+
+		assert!(parse_v1(b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 7\r\n").is_err());
+		assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+		assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 7 8\r\n").is_err());
+		assert!(parse_v1(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 7\r\n").is_err());
+		assert!(parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 7").is_err());
+	}
+
+	#[test]
+	fn v2() {
+		let mut header = V2_SIGNATURE.to_vec();
+		header.extend([
+			0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 7,
+		]);
+		header.extend(b"data");
+
+		assert_eq!(
+			parse_v2(&header).unwrap(),
+			(Some("192.0.2.1:56324".parse().unwrap()), 28)
+		);
+
+		// LOCAL commands have no client address
+		header[12] = 0x20;
+		assert_eq!(parse_v2(&header).unwrap(), (None, 28));
+
+		// TLVs are skipped
+		let mut header = V2_SIGNATURE.to_vec();
+		header.extend([0x21, 0x22, 0, 39]);
+		header.extend(Ipv6Addr::LOCALHOST.octets());
+		header.extend(Ipv6Addr::UNSPECIFIED.octets());
+		header.extend([0, 80, 0, 70, 0x04, 0, 0]);
+
+		assert_eq!(
+			parse_v2(&header).unwrap(),
+			(Some("[::1]:80".parse().unwrap()), 55)
+		);
+
+		assert!(parse_v2(&header[..40]).is_err());
+		assert!(parse_v2(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 7\r\n").is_err());
+
+		header[12] = 0x11;
+		assert!(parse_v2(&header).is_err());
+	}
+
+	#[test]
+	fn read_header() {
+		smol::block_on(async {
+			let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 7\r\nHello"[..];
+			assert_eq!(
+				read(&mut stream).await.unwrap(),
+				Some("192.0.2.1:56324".parse().unwrap())
+			);
+			assert_eq!(stream, b"Hello");
+
+			let mut header = V2_SIGNATURE.to_vec();
+			header.extend([0x20, 0x00, 0, 0]);
+			header.extend(b"Hello");
+			let mut stream = &header[..];
+			assert_eq!(read(&mut stream).await.unwrap(), None);
+			assert_eq!(stream, b"Hello");
+
+			assert!(read(&mut &b"Hello, World!"[..]).await.is_err());
+			assert!(
+				read(&mut &[b"PROXY ".as_slice(), &[b'a'; 200]].concat()[..])
+					.await
+					.is_err()
+			);
+		});
+	}
+}
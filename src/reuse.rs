@@ -0,0 +1,243 @@
+//! Keeping bound sockets open so restarted services can reuse them
+
+use std::{cell::RefCell, io::Error as IoError, mem, net::IpAddr};
+
+use log::warn;
+use socket2::{SockAddr, Socket, Type};
+
+use crate::utils::Peer;
+
+/// A socket bound for a service, kept open so it can be reused
+#[derive(Debug)]
+pub struct Bound {
+	service: String,
+	ty: Type,
+	addr: SockAddr,
+	/// Whether the socket was passed by systemd, rather than bound for the
+	/// service's configured address
+	inherited: bool,
+	socket: Socket,
+}
+
+impl Bound {
+	/// Describe a `socket` bound for the named service by another process
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn new(service: &str, ty: Type, inherited: bool, socket: Socket) -> Result<Self, IoError> {
+		Ok(Self {
+			service: service.to_string(),
+			ty,
+			addr: socket.local_addr()?,
+			inherited,
+			socket,
+		})
+	}
+
+	/// The name of the service the socket is bound for
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn service(&self) -> &str {
+		&self.service
+	}
+
+	/// The type of the socket
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn ty(&self) -> Type {
+		self.ty
+	}
+
+	/// Whether the socket was passed by systemd
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn inherited(&self) -> bool {
+		self.inherited
+	}
+
+	/// The address the socket is bound to
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn addr(&self) -> &SockAddr {
+		&self.addr
+	}
+
+	/// The socket itself
+	#[cfg_attr(not(unix), allow(dead_code))]
+	pub fn socket(&self) -> &Socket {
+		&self.socket
+	}
+
+	/// Duplicate this socket, so the copy can be given to a restarted service
+	/// while the original stays open
+	pub fn try_clone(&self) -> Option<Self> {
+		match self.socket.try_clone() {
+			Ok(socket) => Some(Self {
+				service: self.service.clone(),
+				ty: self.ty,
+				addr: self.addr.clone(),
+				inherited: self.inherited,
+				socket,
+			}),
+			Err(e) => {
+				warn!(
+					"Couldn't keep the socket for {} open: {e}",
+					Peer::from(&self.addr)
+				);
+				None
+			}
+		}
+	}
+}
+
+thread_local! {
+	/// Sockets bound on this thread while collecting, or `None` if not collecting
+	static BOUND: RefCell<Option<Vec<Bound>>> = const { RefCell::new(None) };
+	/// Sockets that services started on this thread can reuse instead of binding
+	static REUSABLE: RefCell<Vec<Bound>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `start`, letting the services it starts reuse any of the `reusable`
+/// sockets, and collect copies of all sockets they end up with
+///
+/// Reusable sockets that aren't reused are closed afterwards.
+pub fn collect<T>(reusable: Vec<Bound>, start: impl FnOnce() -> T) -> (T, Vec<Bound>) {
+	REUSABLE.set(reusable);
+	BOUND.set(Some(Vec::new()));
+
+	let res = start();
+
+	REUSABLE.take();
+	(res, BOUND.take().unwrap_or_default())
+}
+
+/// Record copies of the sockets bound for (or `inherited` from systemd by) the
+/// named service, if collecting
+pub fn record(service: &str, ty: Type, sockets: &[Socket], inherited: bool) {
+	BOUND.with_borrow_mut(|bound| {
+		let Some(bound) = bound else {
+			return;
+		};
+
+		for socket in sockets {
+			match (socket.try_clone(), socket.local_addr()) {
+				(Ok(socket), Ok(addr)) => bound.push(Bound {
+					service: service.to_string(),
+					ty,
+					addr,
+					inherited,
+					socket,
+				}),
+				(Err(e), _) => {
+					warn!("Couldn't keep the socket for the {service} service open: {e}")
+				}
+				(_, Err(_)) => (),
+			}
+		}
+	});
+}
+
+/// Take the reusable sockets of type `ty` previously bound for the named
+/// service to `port` (any port if 0) on `ip`, or on all addresses if `None`
+///
+/// Sockets passed by systemd are reused regardless of the address.
+pub fn take(service: &str, ip: Option<IpAddr>, port: u16, ty: Type) -> Vec<Socket> {
+	let (taken, inherited) = REUSABLE.with_borrow_mut(|reusable| {
+		let matches = |s: &Bound| {
+			s.service == service
+				&& s.ty == ty
+				&& (s.inherited
+					|| s.addr.as_socket().is_some_and(|addr| {
+						(port == 0 || addr.port() == port)
+							&& ip.map_or(addr.ip().is_unspecified(), |ip| addr.ip() == ip)
+					}))
+		};
+
+		take_matching(reusable, matches)
+	});
+
+	record(service, ty, &taken, inherited);
+	taken
+}
+
+/// Take the reusable Unix domain socket of type `ty` previously bound for the
+/// named service to `addr`
+pub fn take_unix(service: &str, addr: &SockAddr, ty: Type) -> Vec<Socket> {
+	let (taken, _) = REUSABLE.with_borrow_mut(|reusable| {
+		let matches = |s: &Bound| {
+			s.service == service
+				&& s.ty == ty
+				&& !s.inherited
+				&& s.addr.is_unix()
+				&& Peer::from(&s.addr) == Peer::from(addr)
+		};
+
+		take_matching(reusable, matches)
+	});
+
+	record(service, ty, &taken, false);
+	taken
+}
+
+/// Remove the `reusable` sockets that `matches`, returning them and whether
+/// any were passed by systemd
+fn take_matching(
+	reusable: &mut Vec<Bound>,
+	matches: impl Fn(&Bound) -> bool,
+) -> (Vec<Socket>, bool) {
+	let (taken, rest) = mem::take(reusable)
+		.into_iter()
+		.partition::<Vec<_>, _>(matches);
+
+	*reusable = rest;
+	let inherited = taken.iter().any(|s| s.inherited);
+	(taken.into_iter().map(|s| s.socket).collect(), inherited)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{Ipv4Addr, SocketAddr};
+
+	use socket2::{Domain, Protocol};
+
+	use super::*;
+
+	fn bind(ip: Ipv4Addr) -> Socket {
+		let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+		socket.bind(&SocketAddr::new(ip.into(), 0).into()).unwrap();
+		socket
+	}
+
+	#[test]
+	fn reuse() {
+		let ((), bound) = collect(Vec::new(), || {
+			record("echo", Type::DGRAM, &[bind(Ipv4Addr::UNSPECIFIED)], false);
+			record("time", Type::DGRAM, &[bind(Ipv4Addr::LOCALHOST)], false);
+			record("qotd", Type::DGRAM, &[bind(Ipv4Addr::LOCALHOST)], true);
+		});
+		assert_eq!(bound.len(), 3);
+
+		let echo = bound[0].addr.as_socket().unwrap();
+		let time = bound[1].addr.as_socket().unwrap();
+
+		let (taken, bound) = collect(bound, || {
+			// Sockets are only reused for the same service, type, address, and port
+			assert!(take("echo", None, echo.port(), Type::STREAM).is_empty());
+			assert!(take("echo", Some(Ipv4Addr::LOCALHOST.into()), 0, Type::DGRAM).is_empty());
+			assert!(take("echo", None, time.port(), Type::DGRAM).is_empty());
+			assert!(take("daytime", None, 0, Type::DGRAM).is_empty());
+
+			// Sockets passed by systemd are reused even if the address changed
+			assert_eq!(take("qotd", None, 17, Type::DGRAM).len(), 1);
+
+			(
+				take("echo", None, echo.port(), Type::DGRAM).len(),
+				take("time", Some(Ipv4Addr::LOCALHOST.into()), 0, Type::DGRAM).len(),
+			)
+		});
+
+		// Reused sockets are recorded again
+		assert_eq!(taken, (1, 1));
+		assert_eq!(bound.len(), 3);
+		assert_eq!(bound[1].addr.as_socket(), Some(echo));
+		assert_eq!(bound[2].addr.as_socket(), Some(time));
+
+		// Sockets aren't recorded outside of `collect`
+		record("echo", Type::DGRAM, &[bind(Ipv4Addr::LOCALHOST)], false);
+		assert!(BOUND.with_borrow(Option::is_none));
+	}
+}
@@ -0,0 +1,234 @@
+//! Full-text search over the embedded file system, using an inverted index
+//! generated by the build script
+//!
+//! All words of a query have to be in a file for it to match, `"quoted
+//! phrases"` with their words in that order. `OR` between words or phrases
+//! matches files with either of them, and words or phrases prefixed with `-`
+//! (or `NOT`) must not be in a file.
+
+#![cfg_attr(not(feature = "gopher"), allow(dead_code))]
+
+use std::{collections::BTreeSet, mem, str};
+
+use crate::fs::{self, Entry, PATHS};
+
+/// Every indexed word, sorted, with the indices into [`PATHS`] of the text
+/// files containing it
+static TERMS: &[(&str, &[usize])] = &include!(concat!(env!("OUT_DIR"), "/fs_search.rs"));
+
+/// Split text into lowercase words of ASCII letters and digits, of which only
+/// ones with 2 to 32 characters are indexed
+///
+/// The build script indexes files the same way.
+fn words(text: &str) -> impl Iterator<Item = String> {
+	text.split(|c: char| !c.is_ascii_alphanumeric())
+		.filter(|word| (2..=32).contains(&word.len()))
+		.map(str::to_ascii_lowercase)
+}
+
+/// Words or phrases of which files have to contain any, or (if negated) none
+#[derive(Debug, PartialEq, Eq)]
+struct Clause {
+	negated: bool,
+	alternatives: Vec<Vec<String>>,
+}
+
+/// Parse a query into its clauses
+fn parse(query: &str) -> Vec<Clause> {
+	let mut clauses = Vec::<Clause>::new();
+	let mut or = false;
+	let mut not = false;
+	let mut rest = query;
+
+	loop {
+		rest = rest.trim_start();
+		if rest.is_empty() {
+			break;
+		}
+
+		let mut negated = match rest.strip_prefix('-') {
+			Some(r) => {
+				rest = r;
+				true
+			}
+			None => false,
+		};
+
+		let (term, quoted) = match rest.strip_prefix('"') {
+			Some(r) => {
+				let end = r.find('"').unwrap_or(r.len());
+				rest = r.get(end + 1..).unwrap_or("");
+				(&r[..end], true)
+			}
+			None => {
+				let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+				let term = &rest[..end];
+				rest = &rest[end..];
+				(term, false)
+			}
+		};
+
+		match term {
+			"OR" if !quoted && !negated => or = true,
+			"NOT" if !quoted && !negated => not = true,
+			_ => {
+				// Words like `fs::read` are phrases of their parts
+				let words = words(term).collect::<Vec<_>>();
+				if words.is_empty() {
+					continue;
+				}
+
+				negated |= mem::take(&mut not);
+				match clauses.last_mut() {
+					Some(last) if mem::take(&mut or) && last.negated == negated => {
+						last.alternatives.push(words);
+					}
+					_ => clauses.push(Clause {
+						negated,
+						alternatives: vec![words],
+					}),
+				}
+			}
+		}
+	}
+
+	clauses
+}
+
+/// The indices into [`PATHS`] of the text files containing `word`
+fn postings(word: &str) -> &'static [usize] {
+	TERMS
+		.binary_search_by(|(term, _)| (*term).cmp(word))
+		.map_or(&[], |i| TERMS[i].1)
+}
+
+/// The indices into [`PATHS`] of the text files containing the `words` in this
+/// order
+fn matching(words: &[String]) -> BTreeSet<usize> {
+	let Some((first, rest)) = words.split_first() else {
+		return BTreeSet::new();
+	};
+
+	let mut files = postings(first)
+		.iter()
+		.copied()
+		.filter(|i| {
+			rest.iter()
+				.all(|word| postings(word).binary_search(i).is_ok())
+		})
+		.collect::<BTreeSet<_>>();
+
+	// The index doesn't know where words are, so phrases are checked in the
+	// files themselves
+	if words.len() > 1 {
+		files.retain(|&i| {
+			let Ok(Entry::File { contents, .. }) = fs::read(PATHS[i].0.as_bytes()) else {
+				return false;
+			};
+
+			let text = str::from_utf8(contents.bytes())
+				.map_or_else(|_| Vec::new(), |text| self::words(text).collect::<Vec<_>>());
+			text.windows(words.len()).any(|window| window == words)
+		});
+	}
+
+	files
+}
+
+/// Search the embedded text files, returning the paths of the ones matching the
+/// `query`, sorted
+pub fn search(query: &str) -> Vec<&'static str> {
+	let clauses = parse(query);
+	let mut found = None::<BTreeSet<usize>>;
+
+	for clause in clauses.iter().filter(|clause| !clause.negated) {
+		let matches = clause
+			.alternatives
+			.iter()
+			.flat_map(|words| matching(words))
+			.collect::<BTreeSet<_>>();
+
+		found = Some(match found {
+			Some(found) => &found & &matches,
+			None => matches,
+		});
+	}
+
+	// Queries with only negated clauses don't match anything
+	let mut found = found.unwrap_or_default();
+	for clause in clauses.iter().filter(|clause| clause.negated) {
+		for words in &clause.alternatives {
+			for i in matching(words) {
+				found.remove(&i);
+			}
+		}
+	}
+
+	found.into_iter().map(|i| PATHS[i].0).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn clause(negated: bool, alternatives: &[&[&str]]) -> Clause {
+		Clause {
+			negated,
+			alternatives: alternatives
+				.iter()
+				.map(|words| words.iter().map(ToString::to_string).collect())
+				.collect(),
+		}
+	}
+
+	#[test]
+	fn parse_queries() {
+		assert_eq!(parse("Gopher  SERVER"), [
+			clause(false, &[&["gopher"]]),
+			clause(false, &[&["server"]]),
+		]);
+		assert_eq!(parse("\"item type\" fs::read"), [
+			clause(false, &[&["item", "type"]]),
+			clause(false, &[&["fs", "read"]]),
+		]);
+		assert_eq!(parse("gopher OR finger -qotd NOT echo"), [
+			clause(false, &[&["gopher"], &["finger"]]),
+			clause(true, &[&["qotd"]]),
+			clause(true, &[&["echo"]]),
+		]);
+		assert_eq!(parse("-\"a phrase\" OR x or \"unterminated phrase"), [
+			clause(true, &[&["phrase"]]),
+			clause(false, &[&["or"]]),
+			clause(false, &[&["unterminated", "phrase"]]),
+		]);
+		assert_eq!(parse(" OR NOT "), []);
+	}
+
+	#[test]
+	fn search_files() {
+		let found = search("gopher ItemType");
+		assert!(found.contains(&"/src/services/gopher.rs"));
+		assert!(found.is_sorted());
+
+		assert!(
+			search("\"item types supported by this server\"").contains(&"/src/services/gopher.rs")
+		);
+		assert!(!search("\"server this by supported\"").contains(&"/src/services/gopher.rs"));
+
+		let either = search("\"RFC 1436\" OR \"RFC 865\"");
+		assert!(either.contains(&"/src/services/gopher.rs"));
+		assert!(either.contains(&"/src/services/qotd.rs"));
+
+		let without = search("\"RFC 1436\" OR \"RFC 865\" -gopher");
+		assert!(!without.contains(&"/src/services/gopher.rs"));
+		assert!(without.contains(&"/src/services/qotd.rs"));
+
+		assert!(search("-gopher").is_empty());
+		assert!(search("").is_empty());
+		// This file is searched as well, so the word can't be in it
+		assert!(search(&"xyzzy".repeat(2)).is_empty());
+
+		// Binary files aren't indexed
+		assert!(!search("gif89a").contains(&"/data/pixel.gif"));
+	}
+}
@@ -0,0 +1,573 @@
+//! An embeddable server running any of the services
+
+use std::{
+	collections::HashMap,
+	mem,
+	net::{IpAddr, SocketAddr},
+	num::NonZeroUsize,
+	path::{Path, PathBuf},
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
+	time::{Duration, Instant},
+};
+
+use anyhow::{Error, anyhow};
+use log::{error, info, warn};
+use pico_args::Arguments;
+use smol::{Task, Timer, block_on};
+
+use crate::{
+	access_log, executor,
+	handover::{self, Previous},
+	metrics, privileges,
+	proxy::IpNet,
+	reuse::{self, Bound},
+	services::{self, Config, Handler, ServiceErr},
+	status, systemd, tcp, unix,
+};
+
+/// A builder for a server running some or all of the services
+///
+/// By default all services compiled into the crate are started on their usual
+/// ports, on all IPv4 and IPv6 addresses, and services that can't start are
+/// skipped with an error logged. If specific services are selected with
+/// [`Server::service`], any of them failing to start is an error instead.
+#[derive(Debug, Default)]
+pub struct Server {
+	config: Config,
+}
+
+impl Server {
+	/// Create a new server with the default configuration
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Create a new server configured from command line arguments
+	pub fn from_args(args: Arguments) -> Result<Self, Error> {
+		Ok(Self {
+			config: Config::from_args(args)?,
+		})
+	}
+
+	/// Start the named service (e.g. `"echo"` or `"message"`), instead of all
+	/// of them
+	pub fn service(mut self, name: &str) -> Self {
+		self.config
+			.services
+			.get_or_insert_default()
+			.push(name.to_string());
+		self
+	}
+
+	/// Listen only on `ip`, instead of on all IPv4 and IPv6 addresses
+	pub fn ip(mut self, ip: IpAddr) -> Self {
+		self.config.ip = Some(ip);
+		self
+	}
+
+	/// Increase the usual port of each service by `base_port`
+	pub fn base_port(mut self, base_port: u16) -> Self {
+		self.config.base_port = base_port;
+		self
+	}
+
+	/// Run the named service on `port` instead of its usual port (0 picks an
+	/// ephemeral port)
+	pub fn port(mut self, name: &str, port: u16) -> Self {
+		self.config.ports.set(name, port);
+		self
+	}
+
+	/// Run all services without a specific port on ephemeral ports
+	pub fn ephemeral_ports(mut self) -> Self {
+		self.config.ports.set_default(0);
+		self
+	}
+
+	/// Set the hostname the server is reachable at (required for Gopher)
+	pub fn hostname(mut self, hostname: &str) -> Self {
+		self.config.hostname = Some(hostname.to_string());
+		self
+	}
+
+	/// Serve files from the host directory at `path` instead of the embedded
+	/// file system
+	pub fn content_dir(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.content_dir = Some(path.into());
+		self
+	}
+
+	/// Trust connections and datagrams from the addresses sharing their first
+	/// `prefix_len` bits with `network` to start with a PROXY protocol header,
+	/// and use the client addresses from it
+	pub fn proxy_from(mut self, network: IpAddr, prefix_len: u8) -> Self {
+		self.config.proxy_from.push(IpNet::new(network, prefix_len));
+		self
+	}
+
+	/// Run the services on `threads` worker threads instead of one
+	///
+	/// Worker threads are shared by all servers in the process and are never
+	/// stopped, so this only ever increases their number.
+	pub fn threads(mut self, threads: NonZeroUsize) -> Self {
+		self.config.threads = Some(threads);
+		self
+	}
+
+	/// Set `SO_REUSEPORT` on new listening sockets, and bind one socket per
+	/// worker thread for each address (only on Unix)
+	///
+	/// Other processes with this option set (and the same user) can then also
+	/// listen on the same addresses, with the kernel spreading clients between
+	/// all of them.
+	pub fn reuse_port(mut self) -> Self {
+		self.config.reuse_port = true;
+		self
+	}
+
+	/// Listen for new servers taking over the listening sockets on a Unix
+	/// socket at `path` (only on Unix)
+	///
+	/// Once a new server has taken over, [`Running::taken_over`] returns.
+	pub fn handover(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.handover = Some(path.into());
+		self
+	}
+
+	/// Take the listening sockets over from the running server listening for
+	/// new servers on `path` when starting, if there is one (only on Unix)
+	pub fn takeover(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.takeover = Some(path.into());
+		self
+	}
+
+	/// Write a JSON access log entry for every connection and datagram to the
+	/// file at `path` (or standard output if `path` is `-`)
+	///
+	/// The access log is shared by all servers in the process.
+	pub fn access_log(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.access_log = Some(path.into());
+		self
+	}
+
+	/// Serve metrics in the Prometheus text exposition format over HTTP at
+	/// `/metrics` on `addr` (port 0 picks an ephemeral port)
+	pub fn metrics(mut self, addr: SocketAddr) -> Self {
+		self.config.metrics = Some(addr);
+		self
+	}
+
+	/// Also serve the named service over TLS on `port` (0 picks an ephemeral
+	/// port)
+	pub fn tls_port(mut self, name: &str, port: u16) -> Self {
+		self.config.tls_ports.set(name, port);
+		self
+	}
+
+	/// Use the PEM certificate chain at `cert` with the private key at `key`
+	/// for TLS, or only for clients requesting `server_name` (SNI) if given
+	///
+	/// Without any certificates, a self-signed one is generated for the
+	/// hostname (or `localhost`) when starting.
+	pub fn tls_cert(
+		mut self,
+		server_name: Option<&str>,
+		cert: impl Into<PathBuf>,
+		key: impl Into<PathBuf>,
+	) -> Self {
+		match server_name {
+			Some(name) => {
+				self.config.tls_certs.set(name, cert.into());
+				self.config.tls_keys.set(name, key.into());
+			}
+			None => {
+				self.config.tls_certs.set_default(cert.into());
+				self.config.tls_keys.set_default(key.into());
+			}
+		}
+		self
+	}
+
+	/// Offer the ALPN `protocols` for the named service over TLS, rejecting
+	/// clients that only support other protocols
+	pub fn tls_alpn(mut self, name: &str, protocols: &[&str]) -> Self {
+		self.config.tls_alpn.set(name, protocols.join(","));
+		self
+	}
+
+	/// Also serve the named service on a Unix stream socket at `path`, or with
+	/// the name `path` minus its leading `@` in the abstract namespace on Linux
+	///
+	/// A stale socket file already at `path` is replaced, and the file is
+	/// removed again when the server is shut down.
+	pub fn unix(mut self, name: &str, path: &str) -> Self {
+		self.config.unix.set(name, path.to_string());
+		self
+	}
+
+	/// Also serve the named service on a Unix datagram socket at `path`, like
+	/// [`Server::unix`]
+	pub fn unix_dgram(mut self, name: &str, path: &str) -> Self {
+		self.config.unix_dgram.set(name, path.to_string());
+		self
+	}
+
+	/// Set the permissions of Unix socket files to `mode` (e.g. `0o660`)
+	pub fn unix_mode(mut self, mode: u32) -> Self {
+		self.config.unix_mode = Some(mode);
+		self
+	}
+
+	/// Switch to the named user (or numeric UID) once all listeners are bound,
+	/// also switching to its primary group unless [`Server::group`] is set
+	pub fn user(mut self, user: &str) -> Self {
+		self.config.user = Some(user.to_string());
+		self
+	}
+
+	/// Switch to the named group (or numeric GID) once all listeners are bound
+	pub fn group(mut self, group: &str) -> Self {
+		self.config.group = Some(group.to_string());
+		self
+	}
+
+	/// Change the root directory to `path` once all listeners are bound
+	///
+	/// The content directory is opened before, but the access log is reopened
+	/// on reload (as is a content directory at a new path), so they have to be
+	/// inside `path`, given by absolute paths starting with it, or starting
+	/// fails (see [`Server::path_in_root`] for other files). Without them,
+	/// nothing is read while running, so `path` can be empty.
+	pub fn chroot(mut self, path: impl Into<PathBuf>) -> Self {
+		self.config.chroot = Some(path.into());
+		self
+	}
+
+	/// Where the file at `path` can still be read once the root directory is
+	/// changed (see [`Server::chroot`]), or an error if it can't be reached
+	/// from inside it
+	pub fn path_in_root(&self, path: &Path) -> Result<PathBuf, Error> {
+		privileges::in_root(self.config.chroot.as_deref(), path)
+	}
+
+	/// Bind and start the services, which run until the returned [`Running`]
+	/// server is shut down or dropped
+	pub fn start(mut self) -> Result<Running, Error> {
+		privileges::check(&self.config)?;
+		self.config.open_content(None)?;
+		let config = Arc::new(self.config);
+
+		executor::start(config.threads());
+		status::start();
+
+		if config.access_log.is_some() {
+			access_log::open(config.access_log.as_deref())?;
+		}
+
+		let (previous, reusable) = match &config.takeover {
+			Some(path) => Previous::connect(path)?.unzip(),
+			None => (None, None),
+		};
+
+		let mut running = Running::start(Arc::clone(&config), reusable.unwrap_or_default())?;
+
+		for addr in systemd::unused() {
+			warn!("Socket passed by systemd for {addr} isn't used by any service");
+		}
+
+		if let Some(previous) = previous {
+			previous.confirm()?;
+		}
+
+		// The previous server's handover socket is only replaced once it's done
+		if let Some(path) = &config.handover {
+			running.handover = Some(handover::Listener::bind(path)?);
+		}
+
+		// All listeners are bound synchronously above, so none are left waiting
+		// for privileges that are about to be dropped
+		privileges::drop(&config)?;
+
+		Ok(running)
+	}
+
+	/// Serve a single connection to the named service over standard input and
+	/// output, as started by inetd in `nowait` mode
+	pub async fn inetd_tcp(self, service: &str) -> Result<(), Error> {
+		self.inetd(service, false).await
+	}
+
+	/// Serve the named service's datagrams on the UDP socket passed as standard
+	/// input, as started by inetd in `wait` mode
+	pub async fn inetd_udp(self, service: &str) -> Result<(), Error> {
+		self.inetd(service, true).await
+	}
+
+	async fn inetd(mut self, service: &str, udp: bool) -> Result<(), Error> {
+		privileges::check(&self.config)?;
+		self.config.open_content(None)?;
+		let config = Arc::new(self.config);
+
+		privileges::drop(&config)?;
+		executor::start(config.threads());
+		status::start();
+
+		match services::inetd(&config, service, udp).await {
+			Ok(()) => Ok(()),
+			Err(ServiceErr::NoHandler) => Err(anyhow!(
+				"the {service} service isn't available over {}",
+				if udp { "UDP" } else { "TCP" }
+			)),
+			Err(e) => Err(anyhow!("{e}")),
+		}
+	}
+}
+
+/// A running server, which stops listening when shut down or dropped
+///
+/// Connections that were already accepted are not closed on shutdown.
+#[derive(Debug)]
+pub struct Running {
+	config: Arc<Config>,
+	tcp: HashMap<&'static str, Vec<SocketAddr>>,
+	udp: HashMap<&'static str, Vec<SocketAddr>>,
+	tls: HashMap<&'static str, Vec<SocketAddr>>,
+	metrics: Vec<SocketAddr>,
+	tasks: Vec<Task<()>>,
+	/// Copies of the listeners' sockets, kept for restarting services on reload
+	sockets: Vec<Bound>,
+	handover: Option<handover::Listener>,
+	/// Whether a new server took the sockets over, so it owns their files
+	handed_over: AtomicBool,
+}
+
+impl Running {
+	fn start(config: Arc<Config>, reusable: Vec<Bound>) -> Result<Self, Error> {
+		if config.base_port > 0 {
+			info!("Increasing all port numbers by {}", config.base_port);
+		}
+
+		let ((started, metrics), sockets) = reuse::collect(reusable, || {
+			(services::start_all(&config), metrics::serve(&config))
+		});
+
+		let mut running = Self {
+			config: Arc::clone(&config),
+			tcp: HashMap::new(),
+			udp: HashMap::new(),
+			tls: HashMap::new(),
+			metrics: Vec::new(),
+			tasks: Vec::new(),
+			sockets,
+			handover: None,
+			handed_over: AtomicBool::new(false),
+		};
+		let mut names = Vec::new();
+
+		for started in started {
+			names.push(started.name);
+
+			for (protocol, res, addrs) in [
+				("TCP", started.tcp, &mut running.tcp),
+				("UDP", started.udp, &mut running.udp),
+				("TLS", started.tls, &mut running.tls),
+			] {
+				match res {
+					Ok(Handler { addrs: a, tasks }) => {
+						addrs.insert(started.name, a);
+						running.tasks.extend(tasks);
+					}
+					Err(ServiceErr::NoHandler) => (),
+					Err(e) if config.services.is_none() => error!("{e}"),
+					Err(e) => {
+						return Err(anyhow!(
+							"couldn't start the {} service over {protocol}: {e}",
+							started.name
+						));
+					}
+				}
+			}
+
+			for (kind, res) in [
+				("stream", started.unix_stream),
+				("datagram", started.unix_dgram),
+			] {
+				match res {
+					Ok(Handler { tasks, .. }) => running.tasks.extend(tasks),
+					Err(ServiceErr::NoHandler) => (),
+					Err(e) => {
+						return Err(anyhow!(
+							"couldn't start the {} service over a Unix {kind} socket: {e}",
+							started.name
+						));
+					}
+				}
+			}
+		}
+
+		match metrics {
+			Ok(Handler { addrs, tasks }) => {
+				running.metrics = addrs;
+				running.tasks.extend(tasks);
+			}
+			Err(ServiceErr::NoHandler) => (),
+			Err(e) => return Err(anyhow!("couldn't start the metrics endpoint: {e}")),
+		}
+
+		if let Some(unknown) = config
+			.services
+			.iter()
+			.flatten()
+			.find(|name| !names.contains(&name.as_str()))
+		{
+			return Err(anyhow!("unknown or disabled service \"{unknown}\""));
+		}
+
+		Ok(running)
+	}
+
+	/// The addresses the named service is listening on over TCP
+	pub fn tcp_addrs(&self, name: &str) -> &[SocketAddr] {
+		self.tcp.get(name).map_or(&[], Vec::as_slice)
+	}
+
+	/// The addresses the named service is listening on over UDP
+	pub fn udp_addrs(&self, name: &str) -> &[SocketAddr] {
+		self.udp.get(name).map_or(&[], Vec::as_slice)
+	}
+
+	/// The addresses the named service is listening on over TLS
+	pub fn tls_addrs(&self, name: &str) -> &[SocketAddr] {
+		self.tls.get(name).map_or(&[], Vec::as_slice)
+	}
+
+	/// The address metrics are served on, if any
+	pub fn metrics_addr(&self) -> Option<SocketAddr> {
+		self.metrics.first().copied()
+	}
+
+	/// Switch to the configuration of `server`, starting newly enabled
+	/// services, stopping disabled ones, and moving services to their new
+	/// addresses
+	///
+	/// Services keeping their address are restarted on their existing sockets,
+	/// so they keep listening throughout. Connections that were already
+	/// accepted keep the previous configuration. The user, group, and root
+	/// directory can't be changed, and the number of worker threads can only be
+	/// increased. If the new configuration can't be started, the previous one
+	/// keeps running.
+	pub async fn reload(&mut self, server: Server) -> Result<(), Error> {
+		let mut config = server.config;
+
+		if (&config.user, &config.group, &config.chroot)
+			!= (&self.config.user, &self.config.group, &self.config.chroot)
+		{
+			warn!("Changes to the user, group, or root directory only apply after a restart");
+		}
+
+		// The current ones stay in effect, and files are looked up inside the
+		// current root directory
+		config.user.clone_from(&self.config.user);
+		config.group.clone_from(&self.config.group);
+		config.chroot.clone_from(&self.config.chroot);
+
+		privileges::check(&config)?;
+		config.open_content(Some(&self.config))?;
+		let config = Arc::new(config);
+
+		if config.threads() < executor::threads() {
+			warn!("Reducing the number of worker threads only applies after a restart");
+		}
+
+		executor::start(config.threads());
+
+		// Reopen the access log even if it's unchanged, in case it was rotated
+		if config.access_log.is_some() || self.config.access_log.is_some() {
+			let path = match config.access_log.as_deref() {
+				Some(path) if path != Path::new("-") => {
+					Some(privileges::in_root(config.chroot.as_deref(), path)?)
+				}
+				path => path.map(Path::to_path_buf),
+			};
+
+			access_log::open(path.as_deref())?;
+		}
+
+		let reusable = self.sockets.iter().filter_map(Bound::try_clone).collect();
+		let mut next = Self::start(config, reusable)?;
+		next.handover = self.handover.take();
+
+		let previous = mem::replace(self, next);
+		previous.stop(&self.sockets).await;
+
+		info!("Configuration reloaded");
+
+		Ok(())
+	}
+
+	/// Wait until a new server has taken over the listening sockets through
+	/// the handover socket (see [`Server::handover`]), or forever without one
+	///
+	/// The new server is already listening when this returns, so this server
+	/// should then be shut down or drained.
+	pub async fn taken_over(&self) {
+		handover::taken_over(self.handover.as_ref(), &self.sockets).await;
+		self.handed_over.store(true, Ordering::Relaxed);
+	}
+
+	/// Stop listening, then wait for at most `timeout` until all accepted
+	/// connections are closed
+	///
+	/// Connections are counted across all servers in the process.
+	pub async fn drain(self, timeout: Duration) {
+		self.shutdown().await;
+
+		let start = Instant::now();
+		let mut logged = false;
+
+		while tcp::active() > 0 {
+			if start.elapsed() >= timeout {
+				warn!(
+					"Stopped waiting for {} connection(s) to close",
+					tcp::active()
+				);
+				break;
+			}
+
+			if !logged {
+				info!("Waiting for {} connection(s) to close", tcp::active());
+				logged = true;
+			}
+
+			Timer::after(Duration::from_millis(100)).await;
+		}
+	}
+
+	/// Stop listening, waiting until all listeners are closed
+	///
+	/// The files of Unix domain sockets are removed, unless a new server has
+	/// taken them over.
+	pub async fn shutdown(self) {
+		self.stop(&[]).await;
+	}
+
+	/// Stop listening, removing the files of Unix domain sockets that aren't
+	/// among the `kept` sockets of the server replacing this one
+	async fn stop(self, kept: &[Bound]) {
+		for task in self.tasks {
+			task.cancel().await;
+		}
+
+		if !self.handed_over.load(Ordering::Relaxed) {
+			unix::remove_files(&self.sockets, kept, self.config.chroot.as_deref());
+		}
+	}
+
+	/// Stop listening, blocking until all listeners are closed
+	pub fn shutdown_blocking(self) {
+		block_on(self.shutdown());
+	}
+}
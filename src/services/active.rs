@@ -1,22 +1,17 @@
 //! The Active Users Protocol ([RFC 865](https://datatracker.ietf.org/doc/html/rfc866))
 
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 use const_format::str_split;
 use log::{info, warn};
 use rand::{Rng, seq::IndexedRandom};
-use smol::{
-	channel::{self, Sender},
-	io::AsyncWriteExt,
-	net::TcpStream,
-	spawn,
-};
+use smol::{channel, io::AsyncWriteExt};
 
 use crate::{
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	udp::Listener as UdpListener,
-	utils::FmtMaybeAddr,
+	executor::spawn,
+	services::{Config, Future, Handler, ServiceErr, SimpleService},
+	tcp::{Listener as TcpListener, Stream},
+	udp::{self, Datagram, Listener as UdpListener},
 };
 
 pub const PORT: u16 = 11;
@@ -30,64 +25,66 @@ const USERNAME_END: &[u8] = b"\r\n";
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "active",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("active", PORT)?;
 
 		info!("starting active service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
-
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("active", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New active users connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New active users connection from {}", incoming.peer());
 				spawn(handle_tcp(incoming)).detach();
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
 	}
 
-	fn udp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "active",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn udp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("active", PORT)?;
 
 		info!("starting active service on UDP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		Self::serve_udp(
+			config,
+			UdpListener::bind("active", config.ip, mapped_port, config.reuse_port)?,
+		)
+	}
 
-			UdpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+	fn serve_tcp(
+		_: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Ok(handle_tcp(stream))
+	}
+
+	fn serve_udp(config: &Arc<Config>, listener: UdpListener) -> Result<Handler, ServiceErr> {
+		let (sender, receiver) = channel::bounded::<Datagram>(udp::QUEUE_LEN);
 
-			loop {
-				let incoming: (Vec<u8>, SocketAddr, Sender<Vec<u8>>) =
-					receiver.recv().await.expect("UDP channel closed");
-				info!("New active users datagram from {}", incoming.1);
-				spawn(handle_udp(incoming)).detach();
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New active users datagram from {}", incoming.peer);
+				handle_udp(incoming).await;
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(sender, config.udp_limits("active"), config.proxies()),
+			handler,
+		))
 	}
 }
 
-async fn handle_tcp(mut stream: TcpStream) {
+async fn handle_tcp(mut stream: impl Stream) {
 	let usernames = USERNAMES.choose_multiple(&mut rand::rng(), rand::rng().random_range(5..500));
 
 	let mut buf = Vec::with_capacity(512);
@@ -100,13 +97,10 @@ async fn handle_tcp(mut stream: TcpStream) {
 		warn!("error writing data: {e}")
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
 }
 
-async fn handle_udp((_, _, reply): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
+async fn handle_udp(Datagram { reply, .. }: Datagram) {
 	let usernames = USERNAMES.choose_multiple(&mut rand::rng(), rand::rng().random_range(5..500));
 
 	let mut buf = Vec::with_capacity(512);
@@ -115,7 +109,5 @@ async fn handle_udp((_, _, reply): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
 		buf.extend(USERNAME_END);
 	}
 
-	if reply.send(buf).await.is_err() {
-		warn!("UDP channel closed");
-	};
+	reply.send(&buf).await;
 }
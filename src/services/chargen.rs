@@ -1,16 +1,16 @@
 //! The Character Generator Protocol ([RFC 864](https://datatracker.ietf.org/doc/html/rfc864))
 
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 use log::{info, warn};
 use rand::Rng;
-use smol::{channel, channel::Sender, io::AsyncWriteExt, net::TcpStream, spawn};
+use smol::{channel, io::AsyncWriteExt};
 
 use crate::{
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	udp::Listener as UdpListener,
-	utils::FmtMaybeAddr,
+	executor::spawn,
+	services::{Config, Future, Handler, ServiceErr, SimpleService},
+	tcp::{Listener as TcpListener, Stream},
+	udp::{self, Datagram, Listener as UdpListener},
 };
 
 pub const PORT: u16 = 19;
@@ -21,63 +21,66 @@ const CHARACTERS: &str = r##"!"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRS
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "chargen",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("chargen", PORT)?;
 
 		info!("starting chargen service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("chargen", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
-
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New CHARGEN connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New CHARGEN connection from {}", incoming.peer());
 				spawn(handle_tcp(incoming)).detach();
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
 	}
 
-	fn udp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "chargen",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn udp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("chargen", PORT)?;
 
 		info!("starting chargen service on UDP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		Self::serve_udp(
+			config,
+			UdpListener::bind("chargen", config.ip, mapped_port, config.reuse_port)?,
+		)
+	}
+
+	fn serve_tcp(
+		_: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Ok(handle_tcp(stream))
+	}
 
-			UdpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+	fn serve_udp(config: &Arc<Config>, listener: UdpListener) -> Result<Handler, ServiceErr> {
+		let (sender, receiver) = channel::bounded::<Datagram>(udp::QUEUE_LEN);
 
-			loop {
-				let incoming = receiver.recv().await.expect("UDP channel closed");
-				info!("New CHARGEN datagram from {}", incoming.1);
-				spawn(handle_udp(incoming)).detach();
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New CHARGEN datagram from {}", incoming.peer);
+				handle_udp(incoming).await;
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(sender, config.udp_limits("chargen"), config.proxies()),
+			handler,
+		))
 	}
 }
 
-async fn handle_tcp(mut stream: TcpStream) {
+async fn handle_tcp(mut stream: impl Stream) {
 	const CHARACTERS_2: &[u8] = const_format::concatcp!(CHARACTERS, CHARACTERS).as_bytes();
 
 	let mut buf = [0; LINE_LEN + LINE_END.len()];
@@ -92,13 +95,10 @@ async fn handle_tcp(mut stream: TcpStream) {
 		};
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
 }
 
-async fn handle_udp((_, _, reply): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
+async fn handle_udp(Datagram { reply, .. }: Datagram) {
 	const CHARACTERS_512: &[u8; 512] = b"\
 		!\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefgh\r\n\
 		\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghi\r\n\
@@ -110,7 +110,5 @@ async fn handle_udp((_, _, reply): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
 	";
 
 	let len = rand::rng().random_range(1..512);
-	if reply.send(CHARACTERS_512[..len].to_vec()).await.is_err() {
-		warn!("UDP channel closed");
-	};
+	reply.send(&CHARACTERS_512[..len]).await;
 }
@@ -1,21 +1,16 @@
 //! The Daytime Protocol ([RFC 867](https://datatracker.ietf.org/doc/html/rfc867))
 
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 use log::{info, warn};
-use smol::{
-	channel::{self, Sender},
-	io::AsyncWriteExt,
-	net::TcpStream,
-	spawn,
-};
+use smol::{channel, io::AsyncWriteExt};
 use time::{OffsetDateTime, format_description::well_known::Rfc3339};
 
 use crate::{
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	udp::Listener as UdpListener,
-	utils::FmtMaybeAddr,
+	executor::spawn,
+	services::{Config, Future, Handler, ServiceErr, SimpleService},
+	tcp::{Listener as TcpListener, Stream},
+	udp::{self, Datagram, Listener as UdpListener},
 };
 
 pub const PORT: u16 = 13;
@@ -23,63 +18,66 @@ pub const PORT: u16 = 13;
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "daytime",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("daytime", PORT)?;
 
 		info!("starting daytime service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
-
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("daytime", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New daytime connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New daytime connection from {}", incoming.peer());
 				spawn(handle_tcp(incoming)).detach();
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
 	}
 
-	fn udp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "daytime",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn udp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("daytime", PORT)?;
 
 		info!("starting daytime service on UDP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		Self::serve_udp(
+			config,
+			UdpListener::bind("daytime", config.ip, mapped_port, config.reuse_port)?,
+		)
+	}
 
-			UdpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+	fn serve_tcp(
+		_: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Ok(handle_tcp(stream))
+	}
+
+	fn serve_udp(config: &Arc<Config>, listener: UdpListener) -> Result<Handler, ServiceErr> {
+		let (sender, receiver) = channel::bounded::<Datagram>(udp::QUEUE_LEN);
 
-			loop {
-				let incoming = receiver.recv().await.expect("UDP channel closed");
-				info!("New daytime datagram from {}", incoming.1);
-				spawn(handle_udp(incoming)).detach();
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New daytime datagram from {}", incoming.peer);
+				handle_udp(incoming).await;
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(sender, config.udp_limits("daytime"), config.proxies()),
+			handler,
+		))
 	}
 }
 
-async fn handle_tcp(mut stream: TcpStream) {
+async fn handle_tcp(mut stream: impl Stream) {
 	let now = OffsetDateTime::now_utc()
 		.format(&Rfc3339)
 		.expect("RFC3339 format is invalid");
@@ -88,18 +86,13 @@ async fn handle_tcp(mut stream: TcpStream) {
 		warn!("error writing data: {e}")
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
 }
 
-async fn handle_udp((_, _, reply): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
+async fn handle_udp(Datagram { reply, .. }: Datagram) {
 	let now = OffsetDateTime::now_utc()
 		.format(&Rfc3339)
 		.expect("RFC3339 format is invalid");
 
-	if reply.send(now.into_bytes()).await.is_err() {
-		warn!("UDP channel closed");
-	};
+	reply.send(now.as_bytes()).await;
 }
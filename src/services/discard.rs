@@ -1,16 +1,16 @@
 //! The Discard Protocol ([RFC 863](https://datatracker.ietf.org/doc/html/rfc863))
 
-use std::net::SocketAddr;
+use std::sync::Arc;
 
 use futures::AsyncReadExt;
 use log::{info, warn};
-use smol::{channel, channel::Sender, net::TcpStream, spawn};
+use smol::channel;
 
 use crate::{
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	udp::Listener as UdpListener,
-	utils::FmtMaybeAddr,
+	executor::spawn,
+	services::{Config, Future, Handler, ServiceErr, SimpleService},
+	tcp::{Listener as TcpListener, Stream},
+	udp::{self, Datagram, Listener as UdpListener},
 };
 
 pub const PORT: u16 = 9;
@@ -18,72 +18,72 @@ pub const PORT: u16 = 9;
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "discard",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("discard", PORT)?;
 
 		info!("starting discard service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("discard", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
-
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New Discard connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New Discard connection from {}", incoming.peer());
 				spawn(handle_tcp(incoming)).detach();
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
 	}
 
-	fn udp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "discard",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn udp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("discard", PORT)?;
 
 		info!("starting discard service on UDP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		Self::serve_udp(
+			config,
+			UdpListener::bind("discard", config.ip, mapped_port, config.reuse_port)?,
+		)
+	}
+
+	fn serve_tcp(
+		_: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Ok(handle_tcp(stream))
+	}
 
-			UdpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+	fn serve_udp(config: &Arc<Config>, listener: UdpListener) -> Result<Handler, ServiceErr> {
+		let (sender, receiver) = channel::bounded::<Datagram>(udp::QUEUE_LEN);
 
-			loop {
-				let incoming = receiver.recv().await.expect("UDP channel closed");
-				info!("New Discard datagram from {}", incoming.1);
-				spawn(handle_udp(incoming)).detach();
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New Discard datagram from {}", incoming.peer);
+				handle_udp(incoming).await;
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(sender, config.udp_limits("discard"), config.proxies()),
+			handler,
+		))
 	}
 }
 
-async fn handle_tcp(mut stream: TcpStream) {
+async fn handle_tcp(mut stream: impl Stream) {
 	let mut buf = [0; 512];
 
 	loop {
 		match stream.read(&mut buf).await {
 			Ok(0) => break,
-			Ok(bytes) => info!(
-				"Discarding {bytes} bytes of data from {}",
-				FmtMaybeAddr(&stream.peer_addr())
-			),
+			Ok(bytes) => info!("Discarding {bytes} bytes of data from {}", stream.peer()),
 			Err(e) => {
 				warn!("error reading data: {e}");
 				break;
@@ -91,12 +91,9 @@ async fn handle_tcp(mut stream: TcpStream) {
 		};
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
 }
 
-async fn handle_udp((data, addr, _): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
-	info!("Discarding {} bytes of data from {addr}", data.len());
+async fn handle_udp(Datagram { data, peer, .. }: Datagram) {
+	info!("Discarding {} bytes of data from {peer}", data.len());
 }
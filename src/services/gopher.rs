@@ -4,6 +4,8 @@ use std::{
 	borrow::Cow,
 	fmt::{Debug, Display, Formatter, Result as FmtResult},
 	io::Write,
+	str,
+	sync::Arc,
 };
 
 use futures::AsyncReadExt;
@@ -11,98 +13,175 @@ use log::{debug, info, warn};
 use smol::{
 	channel::{self},
 	io::AsyncWriteExt,
-	net::TcpStream,
-	spawn,
 };
+use time::OffsetDateTime;
 
 use crate::{
-	fs::{self, Entry},
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	utils::{FmtAsciiIsh, FmtMaybeAddr},
+	executor::spawn,
+	fs::{Contents, Entry, Kind, Root},
+	search::search,
+	services::{
+		Config, Future, Handler, ServiceErr, SimpleService,
+		gophermap::{self, Line},
+	},
+	tcp::{Listener as TcpListener, Stream},
+	utils::{FmtAsciiIsh, Peer},
 };
 
 pub const PORT: u16 = 70;
 
+/// The selector of the full-text search of the embedded file system
+const SEARCH_SELECTOR: &[u8] = b"search";
+
+/// The name of the files describing the menus of their directories
+const GOPHERMAP: &str = "gophermap";
+
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "gopher",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
-
-		let hostname = config.hostname.as_ref().ok_or(ServiceErr::MissingConfig {
-			service_name: "gopher",
-			config_name: "hostname",
-		})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("gopher", PORT)?;
+
+		hostname(config)?;
 
 		info!("starting gopher service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("gopher", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+		let handler = {
+			let config = Arc::clone(config);
 
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New Gopher connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
-				spawn(handle(incoming, hostname)).detach();
+			async move {
+				while let Ok(incoming) = receiver.recv().await {
+					info!("New Gopher connection from {}", incoming.peer());
+					spawn(serve(incoming, Arc::clone(&config))).detach();
+				}
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
+	}
+
+	fn serve_tcp(
+		config: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		hostname(config)?;
+
+		Ok(serve(stream, Arc::clone(config)))
+	}
+}
+
+/// Get the hostname clients should use to reach the server, which is required
+fn hostname(config: &Config) -> Result<&str, ServiceErr> {
+	config.hostname.as_deref().ok_or(ServiceErr::MissingConfig {
+		service_name: "gopher",
+		config_name: "hostname",
+	})
+}
+
+/// Serve a connection with the hostname and content of `config`, keeping the
+/// configuration alive until the connection is closed
+///
+/// Menus link to the port the connection was accepted on (e.g. the TLS port
+/// for connections over TLS), or to the configured TCP port for connections
+/// without one, like over Unix domain sockets.
+async fn serve(stream: impl Stream, config: Arc<Config>) {
+	let port = match stream.local() {
+		Peer::Inet(addr) => Ok(addr.port()),
+		_ => config.port("gopher", PORT),
+	};
+
+	match (hostname(&config), port) {
+		(Ok(hostname), Ok(port)) => handle(stream, hostname, port, config.content()).await,
+		(Err(e), _) | (_, Err(e)) => warn!("{e}"),
 	}
 }
 
 #[derive(Debug)]
 enum Selected {
-	/// An unknown non-empty selector was requested
-	Unknown,
+	/// An unknown non-empty selector was requested, with the kind of the file
+	/// system error
+	Unknown(&'static str),
 	/// The contained file was selected
-	File(&'static str),
+	File(Contents<'static>, Kind),
 	/// The contained directory was selected (for the empty selector this is the
 	/// root entry)
-	Directory(&'static [Entry<'static>]),
+	Directory(Cow<'static, [Entry<'static>]>),
+	/// The search was selected, finding the contained paths
+	Search(Vec<&'static str>),
 }
 
 impl Selected {
-	pub fn get(selector: &[u8]) -> Self {
-		if selector.is_empty() {
-			Self::Directory(fs::root_entries())
-		} else if let Ok(entry) = fs::read(selector) {
-			match entry {
-				Entry::File { contents, .. } => Self::File(contents),
-				Entry::Directory { entries, .. } => Self::Directory(entries),
-			}
-		} else {
-			Self::Unknown
+	pub async fn get(root: Root<'_>, selector: &[u8], query: Option<&[u8]>) -> Self {
+		if selector == SEARCH_SELECTOR && root == Root::Embedded {
+			return Self::Search(
+				query.map_or_else(Vec::new, |query| search(&String::from_utf8_lossy(query))),
+			);
+		}
+
+		match root
+			.read(if selector.is_empty() { b"/" } else { selector })
+			.await
+		{
+			Ok(Entry::File { contents, kind, .. }) => Self::File(contents, kind),
+			Ok(Entry::Directory { entries, .. }) => Self::Directory(entries),
+			Err(e) => Self::Unknown(e.kind()),
 		}
 	}
 }
 
 /// Gopher item types supported by this server
-#[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ItemType {
-	File = b'0',
-	Directory = b'1',
-	Error = b'3',
+	File,
+	Directory,
+	Error,
+	Search,
+	Binary,
+	Gif,
+	Image,
+	/// Informational text, which isn't a link
+	Info,
+	/// Any other type, from a gophermap
+	Other(u8),
 }
 
 impl ItemType {
+	/// The character identifying the type in menus
+	pub fn code(self) -> u8 {
+		match self {
+			Self::File => b'0',
+			Self::Directory => b'1',
+			Self::Error => b'3',
+			Self::Search => b'7',
+			Self::Binary => b'9',
+			Self::Gif => b'g',
+			Self::Image => b'I',
+			Self::Info => b'i',
+			Self::Other(code) => code,
+		}
+	}
+
 	pub fn for_entry(entry: &Entry<'_>) -> Self {
-		match (entry.is_file(), entry.is_directory()) {
-			(true, false) => Self::File,
-			(false, true) => Self::Directory,
-			_ => Self::Error,
+		match entry {
+			Entry::File {
+				kind: Kind::Text, ..
+			} => Self::File,
+			Entry::File {
+				kind: Kind::Binary, ..
+			} => match entry.mime() {
+				Some("image/gif") => Self::Gif,
+				Some(mime) if mime.starts_with("image/") => Self::Image,
+				_ => Self::Binary,
+			},
+			Entry::Directory { .. } => Self::Directory,
 		}
 	}
 }
@@ -121,12 +200,16 @@ impl Display for Item<'_> {
 		write!(
 			f,
 			"{}{}\t{}\t{}\t{}\r\n",
-			self.kind as u8 as char, self.name, self.selector, self.host, self.port
+			self.kind.code() as char,
+			self.name,
+			self.selector,
+			self.host,
+			self.port
 		)
 	}
 }
 
-async fn handle(mut stream: TcpStream, hostname: &str) {
+async fn handle(mut stream: impl Stream, hostname: &str, port: u16, root: Root<'_>) {
 	let mut buf = [0u8; 512];
 	let mut n = 0;
 
@@ -151,6 +234,7 @@ async fn handle(mut stream: TcpStream, hostname: &str) {
 		}
 	}) else {
 		warn!("error parsing selector line");
+		stream.error("invalid_selector");
 		return;
 	};
 
@@ -159,50 +243,288 @@ async fn handle(mut stream: TcpStream, hostname: &str) {
 	let selector = selector.strip_suffix(b"\t").unwrap_or(selector);
 	let selector = if selector == b"/" { b"" } else { selector };
 
+	// Search queries follow the selector after a tab
+	let query = (buf[selector_end] == b'\t').then(|| {
+		let query = &buf[selector_end + 1..n];
+		let end = query
+			.iter()
+			.position(|&b| b == b'\t' || b == b'\r')
+			.unwrap_or(query.len());
+		&query[..end]
+	});
+
 	debug!("Selector is \"{}\"", FmtAsciiIsh(selector));
+	stream.field("selector", String::from_utf8_lossy(selector).into_owned());
+
+	if selector == SEARCH_SELECTOR {
+		if let Some(query) = query {
+			stream.field("query", String::from_utf8_lossy(query).into_owned());
+		}
+	}
 
-	let response = Selected::get(selector);
+	let response = Selected::get(root, selector, query).await;
 	let mut res = Vec::new();
 
 	let _ = match response {
-		Selected::File(contents) => Write::write_fmt(&mut res, format_args!("{contents}.\r\n")),
+		// Text ends with a period on a line by itself, binary files just with
+		// the connection
+		Selected::File(contents, Kind::Text) => Write::write_all(&mut res, contents.bytes())
+			.and_then(|()| Write::write_all(&mut res, b".\r\n")),
+		Selected::File(contents, Kind::Binary) => Write::write_all(&mut res, contents.bytes()),
 		Selected::Directory(entries) => {
-			for entry in entries {
+			let dir = str::from_utf8(selector)
+				.expect("the input was a valid path, so it's also a valid string");
+
+			let map_path = format!("{dir}/{GOPHERMAP}");
+			let map = if entries
+				.iter()
+				.any(|entry| entry.is_file() && entry.name() == GOPHERMAP)
+			{
+				root.read(map_path.as_bytes()).await.ok()
+			} else {
+				None
+			};
+			let map = match map {
+				Some(Entry::File {
+					contents,
+					kind: Kind::Text,
+					..
+				}) => Some(contents),
+				_ => None,
+			};
+
+			// The gophermap itself is left out of the listing it's replacing
+			let mut listing = Vec::new();
+			for entry in entries
+				.iter()
+				.filter(|entry| map.is_none() || entry.name() != GOPHERMAP)
+			{
 				let _ = Write::write_fmt(
-					&mut res,
+					&mut listing,
 					format_args!("{}", Item {
 						kind: ItemType::for_entry(entry),
-						name: entry.name().into(),
-						selector: (String::from_utf8(selector.to_vec())
-							.expect("the input was a valid path, so it's also a valid string")
-							+ "/" + entry.name())
-						.into(),
+						name: describe(entry).into(),
+						selector: format!("{dir}/{}", entry.name()).into(),
+						host: hostname.into(),
+						port
+					}),
+				);
+			}
+
+			if selector.is_empty() && root == Root::Embedded {
+				let _ = Write::write_fmt(
+					&mut listing,
+					format_args!("{}", Item {
+						kind: ItemType::Search,
+						name: "Search the files".into(),
+						selector: String::from_utf8_lossy(SEARCH_SELECTOR),
 						host: hostname.into(),
-						port: PORT
+						port
 					}),
 				);
 			}
 
+			match map
+				.as_ref()
+				.and_then(|map| str::from_utf8(map.bytes()).ok())
+			{
+				Some(map) => write_gophermap(&mut res, map, dir, hostname, port, &listing),
+				None => res.extend_from_slice(&listing),
+			}
+
 			Write::write_all(&mut res, b".\r\n")
 		}
-		Selected::Unknown => Write::write_fmt(
-			&mut res,
-			format_args!("{}.\r\n", Item {
-				kind: ItemType::Error,
-				name: "not found".into(),
-				selector: "".into(),
-				host: hostname.into(),
-				port: PORT
-			}),
-		),
+		Selected::Search(paths) => {
+			for path in paths {
+				let Ok(entry) = root.read(path.as_bytes()).await else {
+					continue;
+				};
+
+				let _ = Write::write_fmt(
+					&mut res,
+					format_args!("{}", Item {
+						kind: ItemType::for_entry(&entry),
+						name: path.into(),
+						selector: path.into(),
+						host: hostname.into(),
+						port
+					}),
+				);
+			}
+
+			Write::write_all(&mut res, b".\r\n")
+		}
+		Selected::Unknown(kind) => {
+			stream.error(kind);
+			Write::write_fmt(
+				&mut res,
+				format_args!("{}.\r\n", Item {
+					kind: ItemType::Error,
+					name: "not found".into(),
+					selector: "".into(),
+					host: hostname.into(),
+					port
+				}),
+			)
+		}
 	};
 
 	if let Err(e) = stream.write_all(&res).await {
 		warn!("error writing data: {e}")
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
+}
+
+/// Describe a directory entry in a menu, with its size (for files) and the
+/// date it was last modified, e.g. `notes.txt (1.5 KiB, 2024-03-15)`
+fn describe(entry: &Entry<'_>) -> String {
+	let date = OffsetDateTime::from(entry.meta().modified()).date();
+
+	if entry.is_file() {
+		format!("{} ({}, {date})", entry.name(), fmt_size(entry.meta().size))
+	} else {
+		format!("{} ({date})", entry.name())
+	}
+}
+
+/// Format a size in bytes with a binary unit, e.g. `1.5 KiB`
+fn fmt_size(size: u64) -> String {
+	const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+
+	if size < 1024 {
+		return format!("{size} B");
+	}
+
+	let mut size = size as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit + 1 < UNITS.len() {
+		size /= 1024.0;
+		unit += 1;
+	}
+
+	format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Write the menu described by the gophermap `map` of the directory `dir` (its
+/// selector) to `res`, with `*` lines replaced by the automatic `listing`
+///
+/// Items default to this server's `hostname` and `port`. Invalid lines are
+/// skipped, but the build script rejects gophermaps with any.
+fn write_gophermap(
+	res: &mut Vec<u8>,
+	map: &str,
+	dir: &str,
+	hostname: &str,
+	port: u16,
+	listing: &[u8],
+) {
+	for line in map.lines() {
+		let item = match gophermap::parse(line) {
+			Ok(Line::End) => break,
+			Ok(Line::Listing) => {
+				res.extend_from_slice(listing);
+				continue;
+			}
+			Ok(Line::Comment) => continue,
+			Ok(Line::Info(text)) => Item {
+				kind: ItemType::Info,
+				name: text.into(),
+				selector: "".into(),
+				host: hostname.into(),
+				port,
+			},
+			Ok(Line::Item(item)) => Item {
+				kind: ItemType::Other(item.code),
+				name: item.name.into(),
+				selector: item.selector(dir),
+				host: item.host.unwrap_or(hostname).into(),
+				port: item.port.unwrap_or(port),
+			},
+			Err(reason) => {
+				warn!("Skipping invalid gophermap line in {dir} ({reason}): {line}");
+				continue;
+			}
+		};
+
+		let _ = Write::write_fmt(res, format_args!("{item}"));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::fs::Metadata;
+
+	#[test]
+	fn describe_entries() {
+		assert_eq!(fmt_size(0), "0 B");
+		assert_eq!(fmt_size(1023), "1023 B");
+		assert_eq!(fmt_size(1536), "1.5 KiB");
+		assert_eq!(fmt_size(5 * 1024 * 1024), "5.0 MiB");
+		assert_eq!(fmt_size(3 << 40), "3072.0 GiB");
+
+		let meta = Metadata {
+			size: 2048,
+			mtime: 1_000_000_000,
+			mode: 0o644,
+		};
+
+		assert_eq!(
+			describe(&Entry::File {
+				name: "notes.txt".into(),
+				contents: b""[..].into(),
+				kind: Kind::Text,
+				meta,
+			}),
+			"notes.txt (2.0 KiB, 2001-09-09)"
+		);
+		assert_eq!(
+			describe(&Entry::Directory {
+				name: "docs".into(),
+				entries: Cow::Borrowed(&[]),
+				meta,
+			}),
+			"docs (2001-09-09)"
+		);
+	}
+
+	#[test]
+	fn gophermap() {
+		let map = "Welcome!\r\n\
+			# A comment\n\
+			iAn info line\t\n\
+			0Read me\treadme.txt\n\
+			1Sources\t/src\n\
+			0notes.txt\t\n\
+			1Elsewhere\t/\tgopher.example.com\t7070\n\
+			hWeb\tURL:https://example.com/\n\
+			*\n\
+			0Invalid port\tx\t\tnope\n\
+			\tNo type\n\
+			.\n\
+			iNot shown\t\n";
+
+		let mut res = Vec::new();
+		write_gophermap(
+			&mut res,
+			map,
+			"/dir",
+			"localhost",
+			7000,
+			b"1listing\t/dir/listing\tlocalhost\t7000\r\n",
+		);
+
+		assert_eq!(
+			String::from_utf8(res).unwrap(),
+			"iWelcome!\t\tlocalhost\t7000\r\n\
+			iAn info line\t\tlocalhost\t7000\r\n\
+			0Read me\t/dir/readme.txt\tlocalhost\t7000\r\n\
+			1Sources\t/src\tlocalhost\t7000\r\n\
+			0notes.txt\t/dir/notes.txt\tlocalhost\t7000\r\n\
+			1Elsewhere\t/\tgopher.example.com\t7070\r\n\
+			hWeb\tURL:https://example.com/\tlocalhost\t7000\r\n\
+			1listing\t/dir/listing\tlocalhost\t7000\r\n"
+		);
+	}
 }
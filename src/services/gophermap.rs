@@ -0,0 +1,136 @@
+//! The line grammar of gophermaps, the files describing the Gopher menus of
+//! their directories
+//!
+//! The build script includes this module too, to reject embedded gophermaps
+//! with lines the Gopher service would skip.
+
+use std::borrow::Cow;
+
+/// A line of a gophermap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Line<'l> {
+	/// `.`, ending the menu early
+	End,
+	/// `*`, replaced by the automatic listing of the directory
+	Listing,
+	/// A line starting with `#`
+	Comment,
+	/// A line without a tab, shown as informational text
+	Info(&'l str),
+	Item(Item<'l>),
+}
+
+/// An item of a gophermap, with its type, name, selector, host, and port
+/// separated by tabs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Item<'l> {
+	/// The character identifying the item type
+	pub code: u8,
+	pub name: &'l str,
+	/// The selector as written, see [`Item::selector`]
+	pub raw_selector: &'l str,
+	/// The host, if it isn't this server
+	pub host: Option<&'l str>,
+	/// The port, if it isn't this server's
+	pub port: Option<u16>,
+}
+
+impl<'l> Item<'l> {
+	/// The selector of the item in the gophermap of the directory `dir` (its
+	/// selector)
+	///
+	/// It defaults to the name (apart from informational text), and is
+	/// relative to the directory unless it starts with `/` (or `URL:`) or the
+	/// item is on another host.
+	pub fn selector(&self, dir: &str) -> Cow<'l, str> {
+		match self.raw_selector {
+			"" if self.code == b'i' => Cow::Borrowed(""),
+			"" => format!("{dir}/{}", self.name).into(),
+			selector
+				if self.host.is_some()
+					|| selector.starts_with('/')
+					|| selector.starts_with("URL:") =>
+			{
+				selector.into()
+			}
+			selector => format!("{dir}/{selector}").into(),
+		}
+	}
+}
+
+/// Parse a line of a gophermap, or tell why it's invalid
+pub fn parse(line: &str) -> Result<Line<'_>, String> {
+	match line {
+		"." => return Ok(Line::End),
+		"*" => return Ok(Line::Listing),
+		_ if line.starts_with('#') => return Ok(Line::Comment),
+		_ => {}
+	}
+
+	let Some((first, rest)) = line.split_once('\t') else {
+		return Ok(Line::Info(line));
+	};
+
+	let mut fields = rest.split('\t');
+	let raw_selector = fields.next().unwrap_or_default();
+	let host = fields.next().filter(|host| !host.is_empty());
+	let port = match fields.next() {
+		None | Some("") => None,
+		Some(port) => Some(
+			port.parse()
+				.ok()
+				.filter(|&port| port != 0)
+				.ok_or_else(|| format!("`{port}` isn't a port"))?,
+		),
+	};
+	if fields.next().is_some() {
+		return Err("more than 4 fields".to_string());
+	}
+
+	let code = match first.as_bytes().first() {
+		Some(&code) if code.is_ascii_graphic() => code,
+		_ => return Err("no item type".to_string()),
+	};
+
+	Ok(Line::Item(Item {
+		code,
+		name: &first[1..],
+		raw_selector,
+		host,
+		port,
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_lines() {
+		assert_eq!(parse("."), Ok(Line::End));
+		assert_eq!(parse("*"), Ok(Line::Listing));
+		assert_eq!(parse("# A comment\twith a tab"), Ok(Line::Comment));
+		assert_eq!(parse("Welcome!"), Ok(Line::Info("Welcome!")));
+
+		let Ok(Line::Item(item)) = parse("1Elsewhere\t/\tgopher.example.com\t7070") else {
+			panic!("not an item");
+		};
+		assert_eq!(item.name, "Elsewhere");
+		assert_eq!(item.host, Some("gopher.example.com"));
+		assert_eq!(item.port, Some(7070));
+		assert_eq!(item.selector("/dir"), "/");
+
+		let Ok(Line::Item(item)) = parse("0Read me\treadme.txt\t\t") else {
+			panic!("not an item");
+		};
+		assert_eq!((item.host, item.port), (None, None));
+		assert_eq!(item.selector("/dir"), "/dir/readme.txt");
+
+		assert_eq!(parse("0x\tx\t\t0"), Err("`0` isn't a port".to_string()));
+		assert_eq!(
+			parse("0x\tx\th\t70\tmore"),
+			Err("more than 4 fields".to_string())
+		);
+		assert_eq!(parse("\tNo type"), Err("no item type".to_string()));
+	}
+}
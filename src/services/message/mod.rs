@@ -8,23 +8,19 @@ mod v2;
 use std::{
 	borrow::Cow,
 	fmt::{Display, Formatter, Result as FmtResult},
-	net::SocketAddr,
+	sync::Arc,
 };
 
 use futures::AsyncReadExt;
 use log::{info, warn};
-use smol::{
-	channel::{self, Sender},
-	io::AsyncWriteExt,
-	net::TcpStream,
-	spawn,
-};
+use smol::{channel, io::AsyncWriteExt};
 
 use crate::{
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	udp::Listener as UdpListener,
-	utils::{FmtMaybeAddr, FmtMaybeUtf8},
+	executor::spawn,
+	services::{Config, Future, Handler, ServiceErr, SimpleService},
+	tcp::{Listener as TcpListener, Stream},
+	udp::{self, Datagram, Listener as UdpListener},
+	utils::FmtMaybeUtf8,
 };
 
 pub const PORT: u16 = 18;
@@ -32,59 +28,62 @@ pub const PORT: u16 = 18;
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "message",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("message", PORT)?;
 
 		info!("starting message service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
-
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("message", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New Message Send connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New Message Send connection from {}", incoming.peer());
 				spawn(handle_tcp(incoming)).detach();
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
 	}
 
-	fn udp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "message",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn udp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("message", PORT)?;
 
 		info!("starting message service on UDP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		Self::serve_udp(
+			config,
+			UdpListener::bind("message", config.ip, mapped_port, config.reuse_port)?,
+		)
+	}
+
+	fn serve_tcp(
+		_: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Ok(handle_tcp(stream))
+	}
 
-			UdpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+	fn serve_udp(config: &Arc<Config>, listener: UdpListener) -> Result<Handler, ServiceErr> {
+		let (sender, receiver) = channel::bounded::<Datagram>(udp::QUEUE_LEN);
 
-			loop {
-				let incoming = receiver.recv().await.expect("UDP channel closed");
-				info!("New Message Send datagram from {}", incoming.1);
-				spawn(handle_udp(incoming)).detach();
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New Message Send datagram from {}", incoming.peer);
+				handle_udp(incoming).await;
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(sender, config.udp_limits("message"), config.proxies()),
+			handler,
+		))
 	}
 }
 
@@ -108,6 +107,18 @@ enum Message<'a> {
 	},
 }
 
+impl Message<'_> {
+	/// The user the message is for
+	fn recipient(&self) -> String {
+		match self {
+			#[cfg(feature = "message-1")]
+			Message::A { username, .. } => FmtMaybeUtf8(username).to_string(),
+			#[cfg(feature = "message-2")]
+			Message::B { recipient, .. } => recipient.to_string(),
+		}
+	}
+}
+
 impl Display for Message<'_> {
 	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
 		match self {
@@ -141,7 +152,7 @@ impl Display for Message<'_> {
 	}
 }
 
-async fn handle_tcp(mut stream: TcpStream) {
+async fn handle_tcp(mut stream: impl Stream) {
 	let mut buf = [0; 512];
 
 	loop {
@@ -150,7 +161,7 @@ async fn handle_tcp(mut stream: TcpStream) {
 			Ok(bytes) => {
 				info!(
 					"Received {bytes} bytes of message data from {}",
-					FmtMaybeAddr(&stream.peer_addr())
+					stream.peer()
 				);
 				bytes
 			}
@@ -172,6 +183,7 @@ async fn handle_tcp(mut stream: TcpStream) {
 		match msg {
 			Ok(msg) => {
 				info!("new message received {msg}");
+				stream.field("recipient", msg.recipient());
 
 				if let Some(reply) = reply {
 					if let Err(e) = stream.write_all(&reply).await {
@@ -181,6 +193,7 @@ async fn handle_tcp(mut stream: TcpStream) {
 			}
 			Err(err) => {
 				warn!("error handling message: {err}");
+				stream.error("invalid_message");
 
 				if let Some(reply) = reply {
 					if let Err(e) = stream.write_all(&reply).await {
@@ -191,14 +204,17 @@ async fn handle_tcp(mut stream: TcpStream) {
 		}
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
 }
 
-async fn handle_udp((data, addr, replier): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
-	info!("Received {} bytes of message data from {addr}", data.len());
+async fn handle_udp(
+	Datagram {
+		data,
+		peer,
+		reply: replier,
+	}: Datagram,
+) {
+	info!("Received {} bytes of message data from {peer}", data.len());
 
 	let (msg, reply) = match data.first() {
 		#[cfg(feature = "message-1")]
@@ -212,20 +228,18 @@ async fn handle_udp((data, addr, replier): (Vec<u8>, SocketAddr, Sender<Vec<u8>>
 	match msg {
 		Ok(msg) => {
 			info!("new message received {msg}");
+			replier.field("recipient", msg.recipient());
 
 			if let Some(reply) = reply {
-				if replier.send(reply.into_owned()).await.is_err() {
-					warn!("UDP channel closed");
-				};
+				replier.send(&reply).await;
 			}
 		}
 		Err(err) => {
 			warn!("error handling message: {err}");
+			replier.error("invalid_message");
 
 			if let Some(reply) = reply {
-				if replier.send(reply.into_owned()).await.is_err() {
-					warn!("UDP channel closed");
-				};
+				replier.send(&reply).await;
 			}
 		}
 	}
@@ -3,12 +3,29 @@
 pub use std::future::Future;
 use std::{
 	fmt::{Display, Formatter, Result as FmtResult},
-	pin::Pin,
-	task::{Context, Poll},
+	future::Pending,
+	net::{IpAddr, SocketAddr},
+	num::NonZeroUsize,
+	path::PathBuf,
+	str::FromStr,
+	sync::Arc,
 };
 
-use log::info;
+use anyhow::anyhow;
+use log::{debug, warn};
 use pico_args::Arguments;
+use smol::{Task, io::AsyncWriteExt};
+
+use crate::{
+	executor::spawn,
+	fs::{HostDir, Root},
+	inetd, privileges,
+	proxy::IpNet,
+	tcp::Stream,
+	tls,
+	udp::{Limits as UdpLimits, Listener as UdpListener, RateLimit},
+	unix,
+};
 
 // Declare the modules here because rust-analyzer wasn't too happy with
 // declaring them inside of the `service` macro
@@ -24,6 +41,8 @@ mod discard;
 mod echo;
 #[cfg(feature = "gopher")]
 mod gopher;
+#[cfg(feature = "gopher")]
+mod gophermap;
 #[cfg(any(feature = "message-1", feature = "message-2"))]
 mod message;
 #[cfg(feature = "qotd")]
@@ -31,34 +50,318 @@ mod qotd;
 #[cfg(feature = "time")]
 mod time;
 
-#[derive(Debug, Clone, Copy)]
-pub enum ServiceRet {}
+/// The names of all services, including ones not compiled into the crate
+pub const NAMES: &[&str] = &[
+	"active", "chargen", "daytime", "discard", "echo", "gopher", "message", "qotd", "time",
+];
+
+/// A started service handler over one protocol
+#[derive(Debug)]
+pub struct Handler {
+	/// The addresses the handler's listener is bound to
+	pub addrs: Vec<SocketAddr>,
+	/// The listener tasks and the task handling incoming connections or
+	/// datagrams, which stop when dropped
+	pub tasks: Vec<Task<()>>,
+}
+
+impl Handler {
+	/// Spawn `handler` alongside the already-spawned listener `tasks`
+	pub fn new(
+		addrs: Vec<SocketAddr>,
+		mut tasks: Vec<Task<()>>,
+		handler: impl Future<Output = ()> + Send + 'static,
+	) -> Self {
+		tasks.push(spawn(handler));
+		Self { addrs, tasks }
+	}
+}
 
-#[derive(Debug, Clone, Copy)]
-pub enum NoFuture {}
+/// A configuration value with an optional default and per-service overrides
+///
+/// On the command line this is a repeatable option, where each occurrence is
+/// either `VALUE` (setting the default for all services) or `SERVICE=VALUE`
+/// (overriding the value for just that service)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerService<T> {
+	default: Option<T>,
+	overrides: Vec<(String, T)>,
+}
 
-impl Future for NoFuture {
-	type Output = ServiceRet;
+impl<T> PerService<T> {
+	/// Set the value for all services without an override
+	pub fn set_default(&mut self, value: T) {
+		self.default = Some(value);
+	}
 
-	fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
-		Poll::Pending
+	/// Override the value for just the named service
+	pub fn set(&mut self, service: &str, value: T) {
+		self.overrides.push((service.to_string(), value));
+	}
+
+	/// Get a reference to the value for the named service, if there is one
+	pub fn get_ref(&self, service: &str) -> Option<&T> {
+		self.overrides
+			.iter()
+			.rev()
+			.find(|(name, _)| name == service)
+			.map(|(_, value)| value)
+			.or(self.default.as_ref())
+	}
+
+	/// Get the value for all services without an override, if there is one
+	#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+	pub fn default_value(&self) -> Option<&T> {
+		self.default.as_ref()
+	}
+
+	/// Iterate over the overridden names and their values, in the order they
+	/// were set
+	#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+	pub fn overrides(&self) -> impl Iterator<Item = (&str, &T)> {
+		self.overrides
+			.iter()
+			.map(|(name, value)| (name.as_str(), value))
 	}
 }
 
-#[derive(Debug)]
+impl<T: Copy> PerService<T> {
+	/// Get the value for the named service, if there is one
+	pub fn get(&self, service: &str) -> Option<T> {
+		self.get_ref(service).copied()
+	}
+}
+
+impl<T> Default for PerService<T> {
+	fn default() -> Self {
+		Self {
+			default: None,
+			overrides: Vec::new(),
+		}
+	}
+}
+
+impl<T: FromStr> PerService<T>
+where
+	T::Err: Display,
+{
+	fn from_args(args: &mut Arguments, key: &'static str) -> Result<Self, anyhow::Error> {
+		let mut res = Self::default();
+
+		for value in args.values_from_str::<_, String>(key)? {
+			let parse = |v: &str| {
+				v.parse::<T>()
+					.map_err(|e| anyhow!("invalid value for \"{key}\" ({value:?}): {e}"))
+			};
+
+			match value.split_once('=') {
+				Some((service, v)) => res.set(service, parse(v)?),
+				None => res.set_default(parse(&value)?),
+			}
+		}
+
+		Ok(res)
+	}
+}
+
+#[derive(Debug, Default)]
 pub struct Config {
+	/// The file to write the access log to (`-` for standard output)
+	pub access_log: Option<PathBuf>,
 	pub base_port: u16,
+	/// The directory to change the root directory to after binding
+	pub chroot: Option<PathBuf>,
+	/// The opened `content_dir`, see [`Config::open_content`]
+	pub content: Option<HostDir>,
+	/// The host directory to serve files from instead of the embedded file
+	/// system
+	pub content_dir: Option<PathBuf>,
+	/// The group (name or GID) to switch to after binding
+	pub group: Option<String>,
+	/// The Unix socket new servers can take over the listening sockets from
+	pub handover: Option<PathBuf>,
 	pub hostname: Option<String>,
+	/// The address to listen on, or all IPv4 and IPv6 addresses if `None`
+	pub ip: Option<IpAddr>,
+	/// The address to serve metrics over HTTP on
+	pub metrics: Option<SocketAddr>,
+	/// Ports overriding the usual port plus `base_port`
+	pub ports: PerService<u16>,
+	/// Trusted proxies, whose connections and datagrams start with a PROXY
+	/// protocol header
+	pub proxy_from: Vec<IpNet>,
+	/// Whether to set `SO_REUSEPORT` and bind one socket per worker thread
+	pub reuse_port: bool,
+	/// The services to start, or all of them if `None`
+	pub services: Option<Vec<String>>,
+	/// The handover socket of a running server to take the listening sockets
+	/// over from
+	pub takeover: Option<PathBuf>,
+	/// The number of worker threads running the services, or one if `None`
+	pub threads: Option<NonZeroUsize>,
+	/// Comma-separated ALPN protocols offered by TLS services
+	pub tls_alpn: PerService<String>,
+	/// TLS certificate chain files, by the server name they're used for
+	pub tls_certs: PerService<PathBuf>,
+	/// TLS private key files, by the server name they're used for
+	pub tls_keys: PerService<PathBuf>,
+	/// Ports to also serve services over TLS on
+	pub tls_ports: PerService<u16>,
+	/// Per-source rate limit for UDP responses
+	pub udp_rate_limit: Option<RateLimit>,
+	/// Maximum ratio between the size of a UDP response and its request
+	pub udp_max_ratio: PerService<f64>,
+	/// Maximum size of a UDP request
+	pub udp_max_payload: PerService<usize>,
+	/// Paths of Unix stream sockets to also serve services on
+	pub unix: PerService<String>,
+	/// Paths of Unix datagram sockets to also serve services on
+	pub unix_dgram: PerService<String>,
+	/// The permissions of Unix socket files
+	pub unix_mode: Option<u32>,
+	/// The user (name or UID) to switch to after binding
+	pub user: Option<String>,
 }
 
 impl Config {
-	pub fn from_args(mut args: Arguments) -> Result<&'static Self, anyhow::Error> {
-		let cfg = Self {
+	pub fn from_args(mut args: Arguments) -> Result<Self, anyhow::Error> {
+		let udp_rate_limit = match (
+			args.opt_value_from_str::<_, f64>("--udp-rate-limit")?,
+			args.opt_value_from_str::<_, f64>("--udp-rate-burst")?,
+		) {
+			(Some(per_second), burst) => Some(RateLimit::new(per_second, burst)?),
+			(None, Some(_)) => Err(anyhow!(
+				"\"--udp-rate-burst\" requires \"--udp-rate-limit\" to also be set"
+			))?,
+			(None, None) => None,
+		};
+
+		let services = args.values_from_str::<_, String>("--service")?;
+
+		let unix = PerService::from_args(&mut args, "--unix")?;
+		let unix_dgram = PerService::from_args(&mut args, "--unix-dgram")?;
+
+		for (key, paths) in [("--unix", &unix), ("--unix-dgram", &unix_dgram)] {
+			if paths.default.is_some() {
+				return Err(anyhow!(
+					"\"{key}\" requires a service name, as in \"{key} SERVICE=PATH\""
+				));
+			}
+		}
+
+		let ports = PerService::from_args(&mut args, "--port")?;
+		let tls_ports = PerService::from_args(&mut args, "--tls-port")?;
+
+		// Every service would try to bind the same port
+		for (key, ports) in [("--port", &ports), ("--tls-port", &tls_ports)] {
+			if ports.default.is_some_and(|port| port != 0) && services.len() != 1 {
+				return Err(anyhow!(
+					"\"{key}\" requires a service name unless exactly one service is selected, as \
+					 in \"{key} SERVICE=PORT\""
+				));
+			}
+		}
+
+		Ok(Self {
+			access_log: args.opt_value_from_str("--access-log")?,
 			base_port: args.opt_value_from_str("--base-port")?.unwrap_or(0),
+			chroot: args.opt_value_from_str("--chroot")?,
+			content: None,
+			content_dir: args.opt_value_from_str("--content-dir")?,
+			group: args.opt_value_from_str("--group")?,
+			handover: args.opt_value_from_str("--handover")?,
 			hostname: args.opt_value_from_str("--hostname")?,
+			ip: args.opt_value_from_str("--ip")?,
+			metrics: args.opt_value_from_str("--metrics")?,
+			ports,
+			proxy_from: args.values_from_str("--proxy-from")?,
+			reuse_port: args.contains("--reuse-port"),
+			services: (!services.is_empty()).then_some(services),
+			takeover: args.opt_value_from_str("--takeover")?,
+			threads: args.opt_value_from_str("--threads")?,
+			tls_alpn: PerService::from_args(&mut args, "--tls-alpn")?,
+			tls_certs: PerService::from_args(&mut args, "--tls-cert")?,
+			tls_keys: PerService::from_args(&mut args, "--tls-key")?,
+			tls_ports,
+			udp_rate_limit,
+			udp_max_ratio: PerService::from_args(&mut args, "--udp-max-ratio")?,
+			udp_max_payload: PerService::from_args(&mut args, "--udp-max-payload")?,
+			unix,
+			unix_dgram,
+			unix_mode: args.opt_value_from_fn("--unix-mode", |s| u32::from_str_radix(s, 8))?,
+			user: args.opt_value_from_str("--user")?,
+		})
+	}
+
+	/// The number of worker threads running the services
+	pub fn threads(&self) -> usize {
+		self.threads.map_or(1, NonZeroUsize::get)
+	}
+
+	/// The trusted proxies, to be shared by a service's listeners
+	pub fn proxies(&self) -> Arc<[IpNet]> {
+		self.proxy_from.as_slice().into()
+	}
+
+	/// Whether the named service should be started
+	pub fn enabled(&self, service: &str) -> bool {
+		self.services
+			.as_ref()
+			.is_none_or(|services| services.iter().any(|s| s == service))
+	}
+
+	/// Get the port for the named service, which usually runs on `usual_port`
+	pub fn port(&self, service: &'static str, usual_port: u16) -> Result<u16, ServiceErr> {
+		if let Some(port) = self.ports.get(service) {
+			return Ok(port);
+		}
+
+		usual_port
+			.checked_add(self.base_port)
+			.ok_or(ServiceErr::PortTooHigh {
+				service_name: service,
+				usual_port,
+				base_port: self.base_port,
+			})
+	}
+
+	/// Open the content directory (if any) before the root directory is
+	/// changed, or take it over from the `previous` configuration if it's at
+	/// the same path
+	///
+	/// Otherwise, once the root directory of `previous` was changed, the
+	/// content directory is opened at its path inside of it.
+	pub fn open_content(&mut self, previous: Option<&Self>) -> Result<(), anyhow::Error> {
+		self.content = match (&self.content_dir, previous) {
+			(None, _) => None,
+			(Some(path), Some(previous)) if previous.content_dir.as_ref() == Some(path) => {
+				previous.content.clone()
+			}
+			(Some(path), Some(previous)) => Some(HostDir::open(privileges::in_root(
+				previous.chroot.as_deref(),
+				path,
+			)?)?),
+			(Some(path), None) => Some(HostDir::open(path)?),
 		};
 
-		Ok(Box::leak(Box::new(cfg)))
+		Ok(())
+	}
+
+	/// Where to serve files from
+	pub fn content(&self) -> Root<'_> {
+		self.content
+			.as_ref()
+			.map_or(Root::Embedded, Root::Directory)
+	}
+
+	/// Get the UDP anti-amplification limits for the named service
+	pub fn udp_limits(&self, service: &'static str) -> UdpLimits {
+		UdpLimits {
+			service,
+			rate: self.udp_rate_limit,
+			max_ratio: self.udp_max_ratio.get(service),
+			max_payload: self.udp_max_payload.get(service),
+		}
 	}
 }
 
@@ -123,61 +426,227 @@ impl Display for ServiceErr {
 }
 
 pub trait SimpleService {
-	fn tcp(_: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		Result::<NoFuture, _>::Err(ServiceErr::NoHandler)
+	fn tcp(_: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		Err(ServiceErr::NoHandler)
+	}
+
+	fn udp(_: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		Err(ServiceErr::NoHandler)
 	}
 
-	fn udp(_: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		Result::<NoFuture, _>::Err(ServiceErr::NoHandler)
+	/// Serve a single connection over an already connected stream
+	fn serve_tcp(_: &Arc<Config>, _: impl Stream) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Result::<Pending<()>, _>::Err(ServiceErr::NoHandler)
+	}
+
+	/// Serve datagrams received by an already bound listener
+	fn serve_udp(_: &Arc<Config>, _: UdpListener) -> Result<Handler, ServiceErr> {
+		Err(ServiceErr::NoHandler)
 	}
 }
 
+/// Serve a single connection to `S` over `stream`, closing it afterwards (which
+/// matters for protocols like TLS that signal the end of the data themselves)
+async fn serve_connection<S: SimpleService>(config: Arc<Config>, mut stream: impl Stream) {
+	let peer = stream.peer();
+
+	match S::serve_tcp(&config, &mut stream) {
+		Ok(handler) => handler.await,
+		Err(e) => warn!("error serving connection from {peer}: {e}"),
+	}
+
+	if let Err(e) = stream.close().await {
+		debug!("error closing connection with {peer}: {e}");
+	}
+}
+
+/// The result of starting a service over each protocol
+pub struct Started {
+	pub name: &'static str,
+	pub tcp: Result<Handler, ServiceErr>,
+	pub udp: Result<Handler, ServiceErr>,
+	pub tls: Result<Handler, ServiceErr>,
+	pub unix_stream: Result<Handler, ServiceErr>,
+	pub unix_dgram: Result<Handler, ServiceErr>,
+}
+
 macro_rules! service {
-	(if $($feature:literal)||+serve $name:ident($cfg:ident)) => {
+	(if $($feature:literal)||+serve $name:ident($cfg:ident, $tls:ident, $started:ident)) => {
 		#[cfg(any($(feature = $feature),+))]
-		{
+		if $cfg.enabled(stringify!($name)) {
 			use $name::Service;
 
-			let tcp = Service::tcp($cfg);
-			let udp = Service::udp($cfg);
-
-			match tcp {
-				Ok(service) => {
-					::smol::spawn(service).detach();
-				}
-				Err(ServiceErr::NoHandler) => (),
-				Err(e) => {
-					::log::error!("{}", e);
-				}
-			}
+			$started.push(Started {
+				name: stringify!($name),
+				tcp: Service::tcp($cfg),
+				udp: Service::udp($cfg),
+				tls: tls::serve($cfg, &$tls, stringify!($name), {
+					let config = Arc::clone($cfg);
+					move |stream| serve_connection::<Service>(Arc::clone(&config), stream)
+				}),
+				unix_stream: unix::serve_stream($cfg, stringify!($name), {
+					let config = Arc::clone($cfg);
+					move |stream| serve_connection::<Service>(Arc::clone(&config), stream)
+				}),
+				unix_dgram: unix::serve_dgram($cfg, stringify!($name), |listener| {
+					Service::serve_udp($cfg, listener)
+				}),
+			});
+		}
+	};
+}
 
-			match udp {
-				Ok(service) => {
-					::smol::spawn(service).detach();
-				}
-				Err(ServiceErr::NoHandler) => (),
-				Err(e) => {
-					::log::error!("{}", e);
-				}
-			}
+/// Start all enabled services
+pub fn start_all(config: &Arc<Config>) -> Vec<Started> {
+	let mut started = Vec::new();
+	let tls = tls::Context::new(config);
+
+	service!(if "active" serve active(config, tls, started));
+	service!(if "chargen" serve chargen(config, tls, started));
+	service!(if "daytime" serve daytime(config, tls, started));
+	service!(if "discard" serve discard(config, tls, started));
+	service!(if "echo" serve echo(config, tls, started));
+	service!(if "gopher" serve gopher(config, tls, started));
+	service!(if "message-1" || "message-2" serve message(config, tls, started));
+	service!(if "qotd" serve qotd(config, tls, started));
+	service!(if "time" serve time(config, tls, started));
+
+	started
+}
+
+macro_rules! inetd {
+	(if $($feature:literal)||+serve $name:ident($cfg:ident, $service:ident, $udp:ident)) => {
+		#[cfg(any($(feature = $feature),+))]
+		if $service == stringify!($name) {
+			return if $udp {
+				inetd::serve_udp::<$name::Service>($cfg).await
+			} else {
+				inetd::serve_tcp::<$name::Service>($cfg).await
+			};
 		}
 	};
 }
 
-pub fn spawn_all(args: Arguments) {
-	let config = Config::from_args(args).expect("argument parsing");
+/// Serve the named service over standard I/O as started by inetd, either a
+/// single TCP connection or datagrams on a UDP socket if `udp` is `true`
+pub async fn inetd(config: &Arc<Config>, service: &str, udp: bool) -> Result<(), ServiceErr> {
+	inetd!(if "active" serve active(config, service, udp));
+	inetd!(if "chargen" serve chargen(config, service, udp));
+	inetd!(if "daytime" serve daytime(config, service, udp));
+	inetd!(if "discard" serve discard(config, service, udp));
+	inetd!(if "echo" serve echo(config, service, udp));
+	inetd!(if "gopher" serve gopher(config, service, udp));
+	inetd!(if "message-1" || "message-2" serve message(config, service, udp));
+	inetd!(if "qotd" serve qotd(config, service, udp));
+	inetd!(if "time" serve time(config, service, udp));
+
+	Err(anyhow!("unknown or disabled service \"{service}\"").into())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::OsString;
 
-	if config.base_port > 0 {
-		info!("Increasing all port numbers by {}", config.base_port);
+	use super::*;
+
+	fn args(args: &[&str]) -> Arguments {
+		Arguments::from_vec(args.iter().map(OsString::from).collect())
 	}
 
-	service!(if "active" serve active(config));
-	service!(if "chargen" serve chargen(config));
-	service!(if "daytime" serve daytime(config));
-	service!(if "discard" serve discard(config));
-	service!(if "echo" serve echo(config));
-	service!(if "gopher" serve gopher(config));
-	service!(if "message-1" || "message-2" serve message(config));
-	service!(if "qotd" serve qotd(config));
-	service!(if "time" serve time(config));
+	#[test]
+	fn per_service() {
+		let values = PerService::<f64>::from_args(
+			&mut args(&[
+				"--ratio",
+				"chargen=2",
+				"--ratio",
+				"4",
+				"--ratio",
+				"active=0.5",
+				"--ratio",
+				"chargen=3",
+			]),
+			"--ratio",
+		)
+		.unwrap();
+
+		assert_eq!(values.get("echo"), Some(4.0));
+		assert_eq!(values.get("active"), Some(0.5));
+		assert_eq!(values.get("chargen"), Some(3.0));
+
+		let values =
+			PerService::<u16>::from_args(&mut args(&["--port", "echo=7"]), "--port").unwrap();
+
+		assert_eq!(values.get("echo"), Some(7));
+		assert_eq!(values.get("discard"), None);
+
+		let values = PerService::<u16>::from_args(&mut args(&[]), "--port").unwrap();
+		assert_eq!(values, PerService::default());
+
+		assert!(
+			PerService::<f64>::from_args(&mut args(&["--ratio", "echo=x"]), "--ratio").is_err()
+		);
+		assert!(PerService::<f64>::from_args(&mut args(&["--ratio", "x"]), "--ratio").is_err());
+	}
+
+	#[test]
+	fn port() {
+		let mut config = Config {
+			base_port: 1000,
+			..Config::default()
+		};
+
+		assert_eq!(config.port("echo", 7).unwrap(), 1007);
+
+		config.ports.set("echo", 0);
+		assert_eq!(config.port("echo", 7).unwrap(), 0);
+		assert_eq!(config.port("time", 37).unwrap(), 1037);
+
+		config.ports.set_default(2000);
+		assert_eq!(config.port("echo", 7).unwrap(), 0);
+		assert_eq!(config.port("time", 37).unwrap(), 2000);
+
+		config.ports = PerService::default();
+		config.base_port = 65530;
+		assert!(matches!(
+			config.port("time", 37),
+			Err(ServiceErr::PortTooHigh {
+				service_name: "time",
+				usual_port: 37,
+				base_port: 65530,
+			})
+		));
+	}
+
+	#[test]
+	fn default_port() {
+		for key in ["--port", "--tls-port"] {
+			assert!(Config::from_args(args(&[key, "7000"])).is_err());
+			assert!(
+				Config::from_args(args(&[
+					"--service",
+					"echo",
+					"--service",
+					"time",
+					key,
+					"7000"
+				]))
+				.is_err()
+			);
+			assert!(Config::from_args(args(&["--service", "echo", key, "7000"])).is_ok());
+			assert!(Config::from_args(args(&[key, "echo=7000"])).is_ok());
+			// Ephemeral ports don't clash
+			assert!(Config::from_args(args(&[key, "0"])).is_ok());
+		}
+	}
+
+	#[test]
+	fn enabled() {
+		let mut config = Config::default();
+		assert!(config.enabled("echo"));
+
+		config.services = Some(vec!["time".to_string()]);
+		assert!(!config.enabled("echo"));
+		assert!(config.enabled("time"));
+	}
 }
@@ -1,94 +1,87 @@
 //! The Quote of the Day Protocol ([RFC 865](https://datatracker.ietf.org/doc/html/rfc865))
 
-use std::net::SocketAddr;
+use std::sync::Arc;
 
-use const_format::str_split;
 use log::{info, warn};
-use rand::seq::IndexedRandom;
-use smol::{
-	channel::{self, Sender},
-	io::AsyncWriteExt,
-	net::TcpStream,
-	spawn,
-};
+use smol::{channel, io::AsyncWriteExt};
 
 use crate::{
-	services::{Config, Future, ServiceErr, ServiceRet, SimpleService},
-	tcp::Listener as TcpListener,
-	udp::Listener as UdpListener,
-	utils::FmtMaybeAddr,
+	executor::spawn,
+	services::{Config, Future, Handler, ServiceErr, SimpleService},
+	tcp::{Listener as TcpListener, Stream},
+	udp::{self, Datagram, Listener as UdpListener},
+	utils,
 };
 
 pub const PORT: u16 = 17;
 
-const QUOTES: &[&str] = &str_split!(include_str!(concat!(env!("OUT_DIR"), "/quotes.txt")), "\n");
 const QUOTE_END: &[u8] = b"\r\n";
 
 pub struct Service;
 
 impl SimpleService for Service {
-	fn tcp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "qotd",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn tcp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("qotd", PORT)?;
 
 		info!("starting qotd service on TCP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
-
-			TcpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+		let (sender, receiver) = channel::unbounded();
+		let listener =
+			TcpListener::bind("qotd", config.ip, mapped_port, config.reuse_port, sender)?;
 
-			loop {
-				let incoming = receiver.recv().await.expect("TCP channel closed");
-				info!(
-					"New QOTD connection from {}",
-					FmtMaybeAddr(&incoming.peer_addr())
-				);
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New QOTD connection from {}", incoming.peer());
 				spawn(handle_tcp(incoming)).detach();
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(config.proxies()),
+			handler,
+		))
 	}
 
-	fn udp(config: &'static Config) -> Result<impl Future<Output = ServiceRet>, ServiceErr> {
-		let mapped_port = PORT
-			.checked_add(config.base_port)
-			.ok_or(ServiceErr::PortTooHigh {
-				service_name: "qotd",
-				usual_port: PORT,
-				base_port: config.base_port,
-			})?;
+	fn udp(config: &Arc<Config>) -> Result<Handler, ServiceErr> {
+		let mapped_port = config.port("qotd", PORT)?;
 
 		info!("starting qotd service on UDP port {mapped_port}");
 
-		Ok(async move {
-			let (sender, receiver) = channel::unbounded();
+		Self::serve_udp(
+			config,
+			UdpListener::bind("qotd", config.ip, mapped_port, config.reuse_port)?,
+		)
+	}
 
-			UdpListener::spawn(mapped_port, sender)
-				.await
-				.expect("error creating listener");
+	fn serve_tcp(
+		_: &Arc<Config>,
+		stream: impl Stream,
+	) -> Result<impl Future<Output = ()>, ServiceErr> {
+		Ok(handle_tcp(stream))
+	}
 
-			loop {
-				let incoming = receiver.recv().await.expect("UDP channel closed");
-				info!("New QOTD datagram from {}", incoming.1);
-				spawn(handle_udp(incoming)).detach();
+	fn serve_udp(config: &Arc<Config>, listener: UdpListener) -> Result<Handler, ServiceErr> {
+		let (sender, receiver) = channel::bounded::<Datagram>(udp::QUEUE_LEN);
+
+		let handler = async move {
+			while let Ok(incoming) = receiver.recv().await {
+				info!("New QOTD datagram from {}", incoming.peer);
+				handle_udp(incoming).await;
 			}
-		})
+		};
+
+		Ok(Handler::new(
+			listener.local_addrs(),
+			listener.spawn(sender, config.udp_limits("qotd"), config.proxies()),
+			handler,
+		))
 	}
 }
 
-async fn handle_tcp(mut stream: TcpStream) {
+async fn handle_tcp(mut stream: impl Stream) {
 	let mut buf = [0; 512];
-	let quote = QUOTES
-		.choose(&mut rand::rng())
-		.expect("there are not quotes")
-		.as_bytes();
+	let quote = utils::quote().as_bytes();
 	buf[..quote.len()].copy_from_slice(quote);
 	buf[quote.len()..quote.len() + QUOTE_END.len()].copy_from_slice(QUOTE_END);
 
@@ -99,26 +92,14 @@ async fn handle_tcp(mut stream: TcpStream) {
 		warn!("error writing data: {e}")
 	}
 
-	info!(
-		"Connection with {} closing",
-		FmtMaybeAddr(&stream.peer_addr())
-	);
+	info!("Connection with {} closing", stream.peer());
 }
 
-async fn handle_udp((_, _, reply): (Vec<u8>, SocketAddr, Sender<Vec<u8>>)) {
+async fn handle_udp(Datagram { reply, .. }: Datagram) {
 	let mut buf = [0; 512];
-	let quote = QUOTES
-		.choose(&mut rand::rng())
-		.expect("there are not quotes")
-		.as_bytes();
+	let quote = utils::quote().as_bytes();
 	buf[..quote.len()].copy_from_slice(quote);
 	buf[quote.len()..quote.len() + QUOTE_END.len()].copy_from_slice(QUOTE_END);
 
-	if reply
-		.send(buf[..quote.len() + QUOTE_END.len()].to_vec())
-		.await
-		.is_err()
-	{
-		warn!("UDP channel closed");
-	};
+	reply.send(&buf[..quote.len() + QUOTE_END.len()]).await;
 }
@@ -0,0 +1,159 @@
+//! Live server status, served as virtual files in the `/.status/` directory of
+//! the file system, which are generated whenever they're read
+
+use std::{
+	borrow::Cow,
+	fmt::Write as _,
+	sync::OnceLock,
+	time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+	fs::{Entry, Kind, Metadata},
+	metrics, utils,
+};
+
+/// The path of the virtual directory
+pub const DIRECTORY: &[u8] = b"/.status";
+
+/// When the first server of the process was started
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// A function generating the contents of a virtual file
+type Generate = fn() -> String;
+
+/// The virtual files, sorted by name, with the functions generating them
+const FILES: &[(&str, Generate)] = &[
+	("connections", connections),
+	("quote", quote),
+	("services", services),
+	("uptime", uptime),
+];
+
+/// Start counting the uptime, if it isn't already counting
+pub fn start() {
+	STARTED.get_or_init(Instant::now);
+}
+
+/// Whether the normalized `path` is [`DIRECTORY`] or in it
+pub fn contains(path: &[u8]) -> bool {
+	path.strip_prefix(DIRECTORY)
+		.is_some_and(|rest| rest.is_empty() || rest.starts_with(b"/"))
+}
+
+/// Read the virtual entry at the normalized `path` (which is either
+/// [`DIRECTORY`] or a path in it), or `None` if there is no such file
+pub fn read(path: &[u8]) -> Option<Entry<'static>> {
+	let mtime = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |time| time.as_secs());
+
+	let file = |&(name, generate): &(&'static str, Generate)| {
+		let contents = generate().into_bytes();
+
+		Entry::File {
+			name: Cow::Borrowed(name),
+			meta: Metadata {
+				size: contents.len() as u64,
+				mtime,
+				mode: 0o444,
+			},
+			contents: contents.into(),
+			kind: Kind::Text,
+		}
+	};
+
+	if path == DIRECTORY {
+		return Some(Entry::Directory {
+			name: Cow::Borrowed(".status"),
+			entries: FILES.iter().map(file).collect(),
+			meta: Metadata {
+				size: 0,
+				mtime,
+				mode: 0o555,
+			},
+		});
+	}
+
+	let name = path.strip_prefix(DIRECTORY)?.strip_prefix(b"/")?;
+	FILES.iter().find(|(n, _)| n.as_bytes() == name).map(file)
+}
+
+/// Whole seconds since the first server was started
+fn uptime() -> String {
+	let uptime = STARTED
+		.get()
+		.map_or(0, |started| started.elapsed().as_secs());
+	format!("{uptime}\n")
+}
+
+/// Every service and transport started so far
+fn services() -> String {
+	let mut out = String::new();
+
+	for (service, transport, ..) in metrics::connections() {
+		let _ = writeln!(out, "{service} {}", transport.name());
+	}
+
+	out
+}
+
+/// The open and accepted connections of every service and stream transport
+fn connections() -> String {
+	let mut out = String::new();
+
+	for (service, transport, open, accepted) in metrics::connections() {
+		if transport.is_stream() {
+			let _ = writeln!(
+				out,
+				"{service} {} {open} open {accepted} accepted",
+				transport.name()
+			);
+		}
+	}
+
+	out
+}
+
+/// A random quote, like the quote of the day service's
+fn quote() -> String {
+	format!("{}\n", utils::quote())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn read_status() {
+		let Some(Entry::Directory { entries, .. }) = read(DIRECTORY) else {
+			panic!();
+		};
+
+		assert_eq!(entries.iter().map(Entry::name).collect::<Vec<_>>(), [
+			"connections",
+			"quote",
+			"services",
+			"uptime"
+		]);
+
+		let Some(Entry::File { contents, meta, .. }) = read(b"/.status/uptime") else {
+			panic!();
+		};
+
+		let uptime = std::str::from_utf8(contents.bytes()).unwrap();
+		assert!(uptime.trim_end().parse::<u64>().is_ok());
+		assert_eq!(meta.size, uptime.len() as u64);
+
+		let Some(Entry::File { contents, .. }) = read(b"/.status/quote") else {
+			panic!();
+		};
+
+		assert!(contents.bytes().len() > 1);
+		assert!(contents.bytes().ends_with(b"\n"));
+
+		assert_eq!(read(b"/.status/nothing"), None);
+		assert_eq!(read(b"/.statusuptime"), None);
+		assert_eq!(read(b"/src"), None);
+	}
+}
@@ -0,0 +1,244 @@
+//! systemd socket activation and service readiness notification
+
+#[cfg(unix)]
+use std::{env, ffi::OsStr, io::Error as IoError, process};
+use std::{mem, net::SocketAddr, sync::Mutex};
+
+use log::warn;
+use socket2::{Socket, Type};
+
+use crate::services;
+
+/// A socket passed by systemd that no service has taken yet
+#[derive(Debug)]
+struct Inherited {
+	/// The name from `LISTEN_FDNAMES`, if any
+	name: Option<String>,
+	socket: Socket,
+	ty: Type,
+	addr: SocketAddr,
+}
+
+/// The sockets passed by systemd, or `None` if they haven't been looked at yet
+static INHERITED: Mutex<Option<Vec<Inherited>>> = Mutex::new(None);
+
+/// Take the sockets of type `ty` passed by systemd for the named service
+///
+/// Sockets are matched by their name in `LISTEN_FDNAMES` if any are named after
+/// the service, and otherwise by `port`, ignoring sockets named after other
+/// services
+pub fn take(service: &str, port: u16, ty: Type) -> Vec<Socket> {
+	let mut inherited = INHERITED.lock().expect("systemd socket lock poisoned");
+	take_from(inherited.get_or_insert_with(listen_fds), service, port, ty)
+}
+
+fn take_from(inherited: &mut Vec<Inherited>, service: &str, port: u16, ty: Type) -> Vec<Socket> {
+	let by_name = inherited
+		.iter()
+		.any(|s| s.ty == ty && s.name.as_deref() == Some(service));
+
+	let matches = |s: &Inherited| {
+		let name = s.name.as_deref();
+
+		if by_name {
+			name == Some(service)
+		} else {
+			s.addr.port() == port && !name.is_some_and(|name| services::NAMES.contains(&name))
+		}
+	};
+
+	let (taken, rest) = mem::take(inherited)
+		.into_iter()
+		.partition::<Vec<_>, _>(|s| s.ty == ty && matches(s));
+
+	*inherited = rest;
+	taken.into_iter().map(|s| s.socket).collect()
+}
+
+/// The addresses of sockets passed by systemd that weren't taken by any service
+pub fn unused() -> Vec<SocketAddr> {
+	INHERITED
+		.lock()
+		.expect("systemd socket lock poisoned")
+		.iter()
+		.flatten()
+		.map(|s| s.addr)
+		.collect()
+}
+
+/// Get the sockets passed by systemd according to `LISTEN_PID`, `LISTEN_FDS`,
+/// and `LISTEN_FDNAMES`
+#[cfg(unix)]
+fn listen_fds() -> Vec<Inherited> {
+	use std::os::fd::{FromRawFd, RawFd};
+
+	/// The first file descriptor passed by systemd
+	const SD_LISTEN_FDS_START: RawFd = 3;
+
+	if env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) != Some(process::id()) {
+		return Vec::new();
+	}
+
+	let Some(n) = env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok()) else {
+		return Vec::new();
+	};
+
+	let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+	let mut names = names.split(':');
+
+	(SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(n))
+		.filter_map(|fd| {
+			let name = names.next().filter(|name| !name.is_empty());
+
+			// SAFETY: systemd passes these file descriptors to this process, and they're
+			// only taken once, guarded by `INHERITED`
+			let socket = unsafe { Socket::from_raw_fd(fd) };
+
+			let (Ok(ty), Ok(Some(addr))) = (
+				socket.r#type(),
+				socket.local_addr().map(|addr| addr.as_socket()),
+			) else {
+				warn!("Ignoring non-IP socket passed by systemd as file descriptor {fd}");
+				return None;
+			};
+
+			if let Err(e) = socket.set_nonblocking(true) {
+				warn!("Ignoring socket passed by systemd for {addr}: {e}");
+				return None;
+			}
+
+			Some(Inherited {
+				name: name.map(str::to_string),
+				socket,
+				ty,
+				addr,
+			})
+		})
+		.collect()
+}
+
+#[cfg(not(unix))]
+fn listen_fds() -> Vec<Inherited> {
+	Vec::new()
+}
+
+/// Send a state update (e.g. `READY=1`) to systemd, if it expects any
+pub fn notify(state: &str) {
+	#[cfg(unix)]
+	if let Some(path) = env::var_os("NOTIFY_SOCKET") {
+		if let Err(e) = send_notification(&path, state) {
+			warn!("Couldn't notify systemd of \"{state}\": {e}");
+		}
+	}
+
+	#[cfg(not(unix))]
+	let _ = state;
+}
+
+#[cfg(unix)]
+fn send_notification(path: &OsStr, state: &str) -> Result<(), IoError> {
+	use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+	let socket = UnixDatagram::unbound()?;
+
+	#[cfg(target_os = "linux")]
+	if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+		use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+		socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+		return Ok(());
+	}
+
+	socket.send_to(state.as_bytes(), path)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::Ipv4Addr;
+
+	use socket2::{Domain, Protocol};
+
+	use super::*;
+
+	fn inherited(name: Option<&str>, ty: Type) -> Inherited {
+		let protocol = if ty == Type::STREAM {
+			Protocol::TCP
+		} else {
+			Protocol::UDP
+		};
+
+		let socket = Socket::new(Domain::IPV4, ty, Some(protocol)).unwrap();
+		socket
+			.bind(&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0).into())
+			.unwrap();
+		let addr = socket.local_addr().unwrap().as_socket().unwrap();
+
+		Inherited {
+			name: name.map(str::to_string),
+			socket,
+			ty,
+			addr,
+		}
+	}
+
+	#[test]
+	fn take_by_name() {
+		let mut sockets = vec![
+			inherited(Some("echo"), Type::STREAM),
+			inherited(Some("echo"), Type::DGRAM),
+			inherited(Some("time"), Type::STREAM),
+		];
+		let port = sockets[2].addr.port();
+
+		let taken = take_from(&mut sockets, "echo", port, Type::STREAM);
+		assert_eq!(taken.len(), 1);
+		assert_eq!(taken[0].r#type().unwrap(), Type::STREAM);
+		assert_eq!(sockets.len(), 2);
+
+		// Sockets named after another service aren't taken, even on the same port
+		assert!(take_from(&mut sockets, "discard", port, Type::STREAM).is_empty());
+		assert_eq!(take_from(&mut sockets, "time", 0, Type::STREAM).len(), 1);
+		assert_eq!(take_from(&mut sockets, "echo", 0, Type::DGRAM).len(), 1);
+		assert!(sockets.is_empty());
+	}
+
+	#[test]
+	fn take_by_port() {
+		let mut sockets = vec![
+			inherited(None, Type::STREAM),
+			inherited(Some("unrelated"), Type::DGRAM),
+		];
+		let tcp_port = sockets[0].addr.port();
+		let udp_port = sockets[1].addr.port();
+
+		assert!(take_from(&mut sockets, "echo", tcp_port, Type::DGRAM).is_empty());
+		assert_eq!(
+			take_from(&mut sockets, "echo", tcp_port, Type::STREAM).len(),
+			1
+		);
+		assert_eq!(
+			take_from(&mut sockets, "echo", udp_port, Type::DGRAM).len(),
+			1
+		);
+		assert!(sockets.is_empty());
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn notification() {
+		use std::os::unix::net::UnixDatagram;
+
+		let path = env::temp_dir().join(format!("simple-protocols-notify-{}", process::id()));
+		let _ = std::fs::remove_file(&path);
+		let socket = UnixDatagram::bind(&path).unwrap();
+
+		send_notification(path.as_os_str(), "READY=1").unwrap();
+
+		let mut buf = [0; 64];
+		let n = socket.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..n], b"READY=1");
+
+		std::fs::remove_file(&path).unwrap();
+	}
+}
@@ -2,67 +2,285 @@
 
 use std::{
 	ffi::c_int,
-	net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6, TcpListener as StdListener},
+	io::Error as IoError,
+	net::{IpAddr, SocketAddr, TcpListener as StdListener},
+	pin::Pin,
+	sync::{
+		Arc,
+		atomic::{AtomicUsize, Ordering},
+	},
+	task::{Context, Poll},
+	time::Duration,
 };
 
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use log::{debug, warn};
 use smol::{
-	Async,
+	Async, Task, Timer,
 	channel::Sender,
+	future,
+	io::{AsyncRead, AsyncWrite},
 	net::{TcpListener, TcpStream},
-	spawn,
 };
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use socket2::{Protocol, Type};
+
+use crate::{
+	executor::spawn,
+	metrics::{self, Metered, Metrics, Transport},
+	proxy::{self, IpNet},
+	utils::{self, Peer},
+};
 
 const TCP_BACKLOG: c_int = 1024;
 
+/// How long trusted proxies have to send their PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of accepted connections that are still open
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the number of accepted connections (over TCP, TLS, or Unix domain
+/// sockets) that are still open
+pub fn active() -> usize {
+	ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Counts an accepted connection as open until dropped
+#[derive(Debug)]
+pub struct Active(());
+
+impl Active {
+	/// Start counting a newly accepted connection
+	pub fn track() -> Self {
+		ACTIVE.fetch_add(1, Ordering::Relaxed);
+		Self(())
+	}
+}
+
+impl Drop for Active {
+	fn drop(&mut self) {
+		ACTIVE.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// A connected byte stream that TCP services can be served over
+pub trait Stream: AsyncRead + AsyncWrite + Unpin {
+	/// The other end of the connection
+	fn peer(&self) -> Peer;
+
+	/// This end of the connection, i.e. the address it was accepted on
+	#[cfg_attr(not(feature = "gopher"), allow(dead_code))]
+	fn local(&self) -> Peer;
+
+	/// Count an error of the given kind (e.g. `"not_found"`) in the metrics and
+	/// the access log, if the connection is counted
+	fn error(&mut self, kind: &'static str);
+
+	/// Record a protocol-specific field of the access log entry (e.g. the
+	/// Gopher selector), if the connection is logged
+	fn field(&mut self, key: &'static str, value: String);
+}
+
+impl Stream for TcpStream {
+	fn peer(&self) -> Peer {
+		self.peer_addr().into()
+	}
+
+	fn local(&self) -> Peer {
+		self.local_addr().into()
+	}
+
+	fn error(&mut self, _: &'static str) {}
+
+	fn field(&mut self, _: &'static str, _: String) {}
+}
+
+impl<S: Stream + ?Sized> Stream for &mut S {
+	fn peer(&self) -> Peer {
+		(**self).peer()
+	}
+
+	fn local(&self) -> Peer {
+		(**self).local()
+	}
+
+	fn error(&mut self, kind: &'static str) {
+		(**self).error(kind);
+	}
+
+	fn field(&mut self, key: &'static str, value: String) {
+		(**self).field(key, value);
+	}
+}
+
+/// An accepted TCP connection, along with the client's address (as passed on
+/// by a trusted proxy if the connection came through one)
+pub struct Connection {
+	stream: Metered<TcpStream>,
+	peer: Peer,
+	_active: Active,
+}
+
+impl AsyncRead for Connection {
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut [u8],
+	) -> Poll<Result<usize, IoError>> {
+		Pin::new(&mut self.stream).poll_read(cx, buf)
+	}
+}
+
+impl AsyncWrite for Connection {
+	fn poll_write(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<Result<usize, IoError>> {
+		Pin::new(&mut self.stream).poll_write(cx, buf)
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Pin::new(&mut self.stream).poll_flush(cx)
+	}
+
+	fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+		Pin::new(&mut self.stream).poll_close(cx)
+	}
+}
+
+impl Stream for Connection {
+	fn peer(&self) -> Peer {
+		self.peer.clone()
+	}
+
+	fn local(&self) -> Peer {
+		self.stream.local()
+	}
+
+	fn error(&mut self, kind: &'static str) {
+		self.stream.error(kind);
+	}
+
+	fn field(&mut self, key: &'static str, value: String) {
+		self.stream.field(key, value);
+	}
+}
+
 pub struct Listener {
-	listener: TcpListener,
-	channel: Sender<TcpStream>,
+	listeners: Vec<TcpListener>,
+	channel: Sender<Connection>,
+	metrics: &'static Metrics,
 }
 
 impl Listener {
-	pub async fn spawn(port: u16, channel: Sender<TcpStream>) -> Result<(), Error> {
-		let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
-		socket.set_tcp_nodelay(true)?;
-		socket.set_nonblocking(true)?;
-		socket.bind(&SockAddr::from(SocketAddrV4::new(
-			Ipv4Addr::UNSPECIFIED,
-			port,
-		)))?;
-		socket.listen(TCP_BACKLOG)?;
+	/// Bind to `port` on `ip`, or on all IPv4 and IPv6 addresses if `ip` is
+	/// `None`, for the named service, sending accepted connections to `channel`
+	/// once spawned (see [`utils::bind`] for `reuse_port`)
+	pub fn bind(
+		service: &'static str,
+		ip: Option<IpAddr>,
+		port: u16,
+		reuse_port: bool,
+		channel: Sender<Connection>,
+	) -> Result<Self, Error> {
+		let metrics = metrics::get(service, Transport::Tcp);
+		Self::bind_as(service, metrics, ip, port, reuse_port, channel)
+	}
 
-		let listener = TcpListener::from(Async::new_nonblocking(StdListener::from(socket))?);
-		let listener_v4 = Self {
-			listener,
-			channel: channel.clone(),
-		};
+	/// Bind like [`Listener::bind`], for the named service's connections over
+	/// TLS
+	#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+	pub fn bind_tls(
+		service: &'static str,
+		ip: Option<IpAddr>,
+		port: u16,
+		reuse_port: bool,
+		channel: Sender<Connection>,
+	) -> Result<Self, Error> {
+		let metrics = metrics::get(service, Transport::Tls);
+		Self::bind_as(
+			&format!("{service}-tls"),
+			metrics,
+			ip,
+			port,
+			reuse_port,
+			channel,
+		)
+	}
 
-		let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
-		socket.set_tcp_nodelay(true)?;
-		socket.set_nonblocking(true)?;
-		socket.set_only_v6(true)?;
-		socket.bind(&SockAddr::from(SocketAddrV6::new(
-			Ipv6Addr::UNSPECIFIED,
+	fn bind_as(
+		name: &str,
+		metrics: &'static Metrics,
+		ip: Option<IpAddr>,
+		port: u16,
+		reuse_port: bool,
+		channel: Sender<Connection>,
+	) -> Result<Self, Error> {
+		let listeners = utils::bind(
+			name,
+			ip,
 			port,
-			0,
-			0,
-		)))?;
-		socket.listen(TCP_BACKLOG)?;
+			reuse_port,
+			Type::STREAM,
+			Protocol::TCP,
+			|socket| socket.set_tcp_nodelay(true),
+		)?
+		.into_iter()
+		.map(|socket| {
+			socket.listen(TCP_BACKLOG)?;
+			Ok(TcpListener::from(Async::new_nonblocking(
+				StdListener::from(socket),
+			)?))
+		})
+		.collect::<Result<_, Error>>()?;
+
+		Ok(Self {
+			listeners,
+			channel,
+			metrics,
+		})
+	}
 
-		let listener = TcpListener::from(Async::new_nonblocking(StdListener::from(socket))?);
-		let listener_v6 = Self { listener, channel };
+	/// The addresses this listener is bound to
+	pub fn local_addrs(&self) -> Vec<SocketAddr> {
+		let mut addrs = self
+			.listeners
+			.iter()
+			.map(|l| l.local_addr().expect("unknown local socket address"))
+			.collect::<Vec<_>>();
 
-		spawn(listener_v4.listen()).detach();
-		spawn(listener_v6.listen()).detach();
+		// Sockets sharing their address with `SO_REUSEPORT` are next to each other
+		addrs.dedup();
+		addrs
+	}
 
-		Ok(())
+	/// Start accepting connections, until the returned tasks are dropped
+	///
+	/// Connections from `proxies` must start with a PROXY protocol header, and
+	/// the client's address from it is used instead of the proxy's.
+	pub fn spawn(self, proxies: Arc<[IpNet]>) -> Vec<Task<()>> {
+		self.listeners
+			.into_iter()
+			.map(|listener| {
+				spawn(Self::listen(
+					listener,
+					self.channel.clone(),
+					self.metrics,
+					Arc::clone(&proxies),
+				))
+			})
+			.collect()
 	}
 
-	async fn listen(self) -> ! {
+	async fn listen(
+		listener: TcpListener,
+		channel: Sender<Connection>,
+		metrics: &'static Metrics,
+		proxies: Arc<[IpNet]>,
+	) {
 		loop {
-			let (stream, addr) = match self.listener.accept().await {
+			let (stream, addr) = match listener.accept().await {
 				Ok((stream, addr)) => (stream, addr),
 				Err(e) => {
 					warn!("TCP `accept` error: {e}");
@@ -72,12 +290,61 @@ impl Listener {
 
 			debug!(
 				"New connection {addr} -> {}",
-				self.listener
-					.local_addr()
-					.expect("unknown local socket address")
+				listener.local_addr().expect("unknown local socket address")
 			);
 
-			self.channel.send(stream).await.expect("TCP channel closed");
+			if proxy::is_trusted(&proxies, addr.ip()) {
+				// Read the header separately, so a slow proxy doesn't hold up others
+				spawn(Self::proxied(stream, addr, channel.clone(), metrics)).detach();
+				continue;
+			}
+
+			let local = listener.local_addr().into();
+			let connection = Connection {
+				stream: Metered::new(stream, metrics, local, Peer::Inet(addr)),
+				peer: Peer::Inet(addr),
+				_active: Active::track(),
+			};
+
+			if channel.send(connection).await.is_err() {
+				debug!("TCP channel closed, no longer accepting connections");
+				break;
+			}
 		}
 	}
+
+	async fn proxied(
+		mut stream: TcpStream,
+		addr: SocketAddr,
+		channel: Sender<Connection>,
+		metrics: &'static Metrics,
+	) {
+		let timeout = async {
+			Timer::after(PROXY_HEADER_TIMEOUT).await;
+			Err(anyhow!("timed out"))
+		};
+
+		let client = match future::or(proxy::read(&mut stream), timeout).await {
+			Ok(client) => client,
+			Err(e) => {
+				warn!("Invalid PROXY protocol header from {addr}: {e}");
+				metrics.error("proxy_header");
+				return;
+			}
+		};
+
+		if let Some(client) = client {
+			debug!("Connection from {client} proxied by {addr}");
+		}
+
+		let peer = Peer::Inet(client.unwrap_or(addr));
+		let local = stream.local_addr().into();
+		let connection = Connection {
+			stream: Metered::new(stream, metrics, local, peer.clone()),
+			peer,
+			_active: Active::track(),
+		};
+
+		let _ = channel.send(connection).await;
+	}
 }
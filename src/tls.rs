@@ -0,0 +1,307 @@
+//! TLS listeners
+
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::{cell::OnceCell, path::Path, time::Duration};
+
+#[cfg(not(feature = "tls"))]
+use anyhow::anyhow;
+#[cfg(feature = "tls")]
+use anyhow::{Error, anyhow};
+#[cfg(feature = "tls")]
+use futures_rustls::{
+	TlsAcceptor,
+	rustls::{
+		ServerConfig,
+		crypto::{CryptoProvider, ring},
+		pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+		server::{ClientHello, ResolvesServerCert},
+		sign::CertifiedKey,
+	},
+	server::TlsStream,
+};
+#[cfg(feature = "tls")]
+use log::{info, warn};
+#[cfg(feature = "tls")]
+use smol::{Timer, channel, future};
+
+use crate::services::{Config, Future, Handler, ServiceErr};
+#[cfg(feature = "tls")]
+use crate::{
+	executor::spawn,
+	metrics::{self, Transport},
+	tcp::{Connection, Listener as TcpListener, Stream},
+	utils::Peer,
+};
+
+/// How long clients have to complete the TLS handshake
+#[cfg(feature = "tls")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "tls")]
+impl Stream for TlsStream<Connection> {
+	fn peer(&self) -> Peer {
+		self.get_ref().0.peer()
+	}
+
+	fn local(&self) -> Peer {
+		self.get_ref().0.local()
+	}
+
+	fn error(&mut self, kind: &'static str) {
+		self.get_mut().0.error(kind);
+	}
+
+	fn field(&mut self, key: &'static str, value: String) {
+		self.get_mut().0.field(key, value);
+	}
+}
+
+/// Certificates selected by the server name requested by the client (SNI)
+#[cfg(feature = "tls")]
+#[derive(Debug)]
+struct Certs {
+	/// Certificates for specific server names
+	named: Vec<(String, Arc<CertifiedKey>)>,
+	/// The certificate for any other server name, or if none was requested
+	default: Arc<CertifiedKey>,
+}
+
+#[cfg(feature = "tls")]
+impl Certs {
+	/// Load the configured certificates, or generate a self-signed one for the
+	/// configured hostname if there aren't any
+	fn load(config: &Config, provider: &CryptoProvider) -> Result<Self, Error> {
+		let load = |name: Option<&str>, cert: &Path| {
+			let key = match name {
+				Some(name) => config.tls_keys.get_ref(name),
+				None => config.tls_keys.default_value(),
+			}
+			.ok_or_else(|| anyhow!("no TLS key configured for {}", cert.display()))?;
+
+			let chain = CertificateDer::pem_file_iter(cert)
+				.and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+				.map_err(|e| anyhow!("couldn't read {}: {e}", cert.display()))?;
+			let key = PrivateKeyDer::from_pem_file(key)
+				.map_err(|e| anyhow!("couldn't read {}: {e}", key.display()))?;
+
+			Ok::<_, Error>(Arc::new(CertifiedKey::from_der(chain, key, provider)?))
+		};
+
+		let named = config
+			.tls_certs
+			.overrides()
+			.map(|(name, cert)| Ok((name.to_string(), load(Some(name), cert)?)))
+			.collect::<Result<Vec<_>, Error>>()?;
+
+		let default = match (config.tls_certs.default_value(), named.first()) {
+			(Some(cert), _) => load(None, cert)?,
+			(None, Some((_, cert))) => Arc::clone(cert),
+			(None, None) => {
+				let hostname = config.hostname.as_deref().unwrap_or("localhost");
+				let generated = rcgen::generate_simple_self_signed([hostname.to_string()])?;
+
+				info!("No TLS certificate configured, generated a self-signed one for {hostname}");
+
+				Arc::new(CertifiedKey::from_der(
+					vec![generated.cert.der().clone()],
+					PrivateKeyDer::try_from(generated.signing_key.serialize_der())
+						.map_err(|e| anyhow!("{e}"))?,
+					provider,
+				)?)
+			}
+		};
+
+		Ok(Self { named, default })
+	}
+}
+
+#[cfg(feature = "tls")]
+impl ResolvesServerCert for Certs {
+	fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+		let named = hello.server_name().and_then(|requested| {
+			self.named
+				.iter()
+				.find(|(name, _)| name.eq_ignore_ascii_case(requested))
+		});
+
+		Some(Arc::clone(named.map_or(&self.default, |(_, cert)| cert)))
+	}
+}
+
+/// The TLS certificates shared by all services started together, loaded when
+/// the first TLS listener is started
+#[cfg(feature = "tls")]
+pub struct Context {
+	config: Arc<Config>,
+	certs: OnceCell<Result<Arc<Certs>, String>>,
+}
+
+#[cfg(feature = "tls")]
+impl Context {
+	pub fn new(config: &Arc<Config>) -> Self {
+		Self {
+			config: Arc::clone(config),
+			certs: OnceCell::new(),
+		}
+	}
+
+	fn certs(&self) -> Result<Arc<Certs>, Error> {
+		self.certs
+			.get_or_init(|| {
+				Certs::load(&self.config, &ring::default_provider())
+					.map(Arc::new)
+					.map_err(|e| format!("couldn't load the TLS certificates: {e}"))
+			})
+			.clone()
+			.map_err(Error::msg)
+	}
+}
+
+/// Start serving the named service over TLS on its configured TLS port, with
+/// `serve` handling each connection once the handshake is done
+#[cfg(feature = "tls")]
+pub fn serve<F: Future<Output = ()> + Send + 'static>(
+	config: &Config,
+	tls: &Context,
+	service: &'static str,
+	serve: impl Fn(TlsStream<Connection>) -> F + Send + Sync + 'static,
+) -> Result<Handler, ServiceErr> {
+	let Some(port) = config.tls_ports.get(service) else {
+		return Err(ServiceErr::NoHandler);
+	};
+
+	let mut server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()?
+		.with_no_client_auth()
+		.with_cert_resolver(tls.certs()?);
+
+	server.alpn_protocols = config
+		.tls_alpn
+		.get_ref(service)
+		.map(|protocols| {
+			protocols
+				.split(',')
+				.map(|p| p.as_bytes().to_vec())
+				.collect()
+		})
+		.unwrap_or_default();
+
+	let acceptor = TlsAcceptor::from(Arc::new(server));
+	let serve = Arc::new(serve);
+
+	info!("starting {service} service on TLS port {port}");
+
+	let (sender, receiver) = channel::unbounded();
+	let listener = TcpListener::bind_tls(service, config.ip, port, config.reuse_port, sender)?;
+
+	let handler = async move {
+		while let Ok(incoming) = receiver.recv().await {
+			let peer = incoming.peer();
+			let accept = acceptor.accept(incoming).into_fallible();
+			let serve = Arc::clone(&serve);
+
+			spawn(async move {
+				let timeout = async {
+					Timer::after(HANDSHAKE_TIMEOUT).await;
+					Err((std::io::ErrorKind::TimedOut.into(), None))
+				};
+				let accept = async { accept.await.map_err(|(e, incoming)| (e, Some(incoming))) };
+
+				let mut stream = match future::or(accept, timeout).await {
+					Ok(stream) => stream,
+					Err((e, incoming)) => {
+						warn!("TLS handshake with {peer} failed: {e}");
+
+						// The connection is gone if the handshake timed out
+						match incoming {
+							Some(mut incoming) => incoming.error("tls_handshake"),
+							None => metrics::error(service, Transport::Tls, "tls_handshake"),
+						}
+
+						return;
+					}
+				};
+
+				let (_, session) = stream.get_ref();
+				let server_name = session.server_name().map(str::to_string);
+				let alpn = session
+					.alpn_protocol()
+					.map(|p| String::from_utf8_lossy(p).into_owned());
+
+				info!(
+					"New TLS {service} connection from {peer} (server name {}, ALPN protocol {})",
+					server_name.as_deref().unwrap_or("[none]"),
+					alpn.as_deref().unwrap_or("[none]")
+				);
+
+				if let Some(server_name) = server_name {
+					stream.field("server_name", server_name);
+				}
+				if let Some(alpn) = alpn {
+					stream.field("alpn", alpn);
+				}
+
+				serve(stream).await;
+			})
+			.detach();
+		}
+	};
+
+	Ok(Handler::new(
+		listener.local_addrs(),
+		listener.spawn(config.proxies()),
+		handler,
+	))
+}
+
+/// The TLS certificates shared by all services started together (none without
+/// the `tls` feature)
+#[cfg(not(feature = "tls"))]
+pub struct Context;
+
+#[cfg(not(feature = "tls"))]
+impl Context {
+	pub fn new(_: &Arc<Config>) -> Self {
+		Self
+	}
+}
+
+/// TLS is only supported with the `tls` feature, so fail if any TLS ports are
+/// configured (`serve` only takes a TCP stream to keep the signatures alike)
+#[cfg(not(feature = "tls"))]
+pub fn serve<F: Future<Output = ()> + Send + 'static>(
+	config: &Config,
+	_: &Context,
+	service: &'static str,
+	_: impl Fn(smol::net::TcpStream) -> F,
+) -> Result<Handler, ServiceErr> {
+	match config.tls_ports.get(service) {
+		Some(_) => Err(ServiceErr::Other(anyhow!(
+			"TLS is only supported when built with the \"tls\" feature"
+		))),
+		None => Err(ServiceErr::NoHandler),
+	}
+}
+
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn load_certs() {
+		let provider = ring::default_provider();
+
+		let config = Config::default();
+		let certs = Certs::load(&config, &provider).unwrap();
+		assert!(certs.named.is_empty());
+		assert!(certs.default.keys_match().is_ok());
+
+		let mut config = Config::default();
+		config.tls_certs.set("example.com", "cert.pem".into());
+		assert!(Certs::load(&config, &provider).is_err());
+
+		config.tls_keys.set_default("/nonexistent/key.pem".into());
+		assert!(Certs::load(&config, &provider).is_err());
+	}
+}
@@ -1,113 +1,634 @@
 //! UDP listeners
 
 use std::{
-	net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket as StdSocket},
-	sync::Arc,
+	collections::HashMap,
+	mem::MaybeUninit,
+	net::{IpAddr, SocketAddr, UdpSocket as StdSocket},
+	sync::{
+		Arc, Mutex, MutexGuard,
+		atomic::{AtomicU64, Ordering},
+	},
+	time::{Duration, Instant},
 };
 
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use log::{debug, trace, warn};
-use smol::{
-	Async,
-	channel::{self, Sender},
-	net::UdpSocket,
-	spawn,
+use smol::{Async, Task, channel::Sender};
+use socket2::{MaybeUninitSlice, Protocol, SockAddr, Socket as OsSocket, Type};
+
+use crate::{
+	access_log::Entry,
+	executor::spawn,
+	metrics::{self, Metrics, Transport},
+	proxy::{self, IpNet},
+	utils::{self, FmtAsciiIsh, Peer},
 };
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
-use crate::utils::FmtAsciiIsh;
+/// The size of the receive buffer, large enough for the largest possible UDP
+/// payload (65 507 bytes over IPv4, 65 527 bytes over IPv6 without jumbograms)
+const BUF_SIZE: usize = 65_527;
 
-const BUF_SIZE: usize = 1024;
+/// The number of received datagrams that may be waiting for their service
+/// before the listener stops receiving more
+pub const QUEUE_LEN: usize = 1024;
 
-pub struct Listener {
-	socket: UdpSocket,
-	channel: Sender<(Vec<u8>, SocketAddr, Sender<Vec<u8>>)>,
+/// The maximum number of sources tracked by a rate limiter, any further ones
+/// share a single bucket until idle sources are forgotten
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// How often a full rate limiter may look for idle sources to forget
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counters of UDP responses dropped by each service's listeners
+static STATS: Mutex<Vec<(&'static str, Arc<Stats>)>> = Mutex::new(Vec::new());
+
+/// A per-source token bucket rate limit for UDP responses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+	/// Responses per second each source is allowed on average
+	per_second: f64,
+	/// Maximum number of responses each source is allowed in a burst
+	burst: f64,
 }
 
-impl Listener {
-	pub async fn spawn(
-		port: u16,
-		channel: Sender<(Vec<u8>, SocketAddr, Sender<Vec<u8>>)>,
-	) -> Result<(), Error> {
-		let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-		socket.set_nonblocking(true)?;
-		socket.bind(&SockAddr::from(SocketAddrV4::new(
-			Ipv4Addr::UNSPECIFIED,
-			port,
-		)))?;
+impl RateLimit {
+	/// Create a new rate limit, with the burst size defaulting to one second's
+	/// worth of responses
+	pub fn new(per_second: f64, burst: Option<f64>) -> Result<Self, Error> {
+		let burst = burst.unwrap_or(per_second.max(1.0));
 
-		let listener = UdpSocket::from(Async::new_nonblocking(StdSocket::from(socket))?);
-		let listener_v4 = Self {
-			socket: listener,
-			channel: channel.clone(),
-		};
+		if !(per_second.is_finite() && per_second > 0.0) {
+			Err(anyhow!(
+				"the UDP rate limit must be a positive number, but it was {per_second}"
+			))
+		} else if !(burst.is_finite() && burst >= 1.0) {
+			Err(anyhow!(
+				"the UDP rate limit burst must be at least 1, but it was {burst}"
+			))
+		} else {
+			Ok(Self { per_second, burst })
+		}
+	}
+}
 
-		let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
-		socket.set_nonblocking(true)?;
-		socket.set_only_v6(true)?;
-		socket.bind(&SockAddr::from(SocketAddrV6::new(
-			Ipv6Addr::UNSPECIFIED,
-			port,
-			0,
-			0,
-		)))?;
-
-		let listener = UdpSocket::from(Async::new_nonblocking(StdSocket::from(socket))?);
-		let listener_v6 = Self {
-			socket: listener,
-			channel,
+/// Anti-amplification limits applied to a service's UDP responses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+	/// The name of the service (used for logging and statistics)
+	pub service: &'static str,
+	/// Per-source response rate limit
+	pub rate: Option<RateLimit>,
+	/// Maximum ratio of response bytes to request bytes
+	pub max_ratio: Option<f64>,
+	/// Maximum size of a request, larger datagrams are dropped
+	pub max_payload: Option<usize>,
+}
+
+/// Counters of dropped UDP responses
+#[derive(Debug, Default)]
+pub struct Stats {
+	/// Responses dropped because the source exceeded its rate limit
+	pub rate_limited: AtomicU64,
+	/// Responses dropped because they were too large relative to the request
+	pub too_large: AtomicU64,
+}
+
+/// Get a snapshot of the counters of dropped UDP responses for every service,
+/// as `(service, rate_limited, too_large)`
+pub fn dropped() -> Vec<(&'static str, u64, u64)> {
+	STATS
+		.lock()
+		.expect("UDP statistics lock poisoned")
+		.iter()
+		.map(|(service, stats)| {
+			(
+				*service,
+				stats.rate_limited.load(Ordering::Relaxed),
+				stats.too_large.load(Ordering::Relaxed),
+			)
+		})
+		.collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+/// The buckets of a rate limiter's sources
+#[derive(Debug)]
+struct Buckets {
+	sources: HashMap<IpAddr, Bucket>,
+	/// The bucket shared by sources that aren't tracked because there are too
+	/// many
+	overflow: Bucket,
+	/// When idle sources were last forgotten
+	pruned: Option<Instant>,
+}
+
+/// A token bucket rate limiter keyed by source IP address
+#[derive(Debug)]
+struct RateLimiter {
+	limit: RateLimit,
+	buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+	fn new(limit: RateLimit) -> Self {
+		Self {
+			limit,
+			buckets: Mutex::new(Buckets {
+				sources: HashMap::new(),
+				overflow: Bucket {
+					tokens: limit.burst,
+					updated: Instant::now(),
+				},
+				pruned: None,
+			}),
+		}
+	}
+
+	/// Try to take a token for a response to `ip` at `now`, returning whether
+	/// the response is allowed
+	fn check(&self, ip: IpAddr, now: Instant) -> bool {
+		let limit = self.limit;
+		let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+		let Buckets {
+			sources,
+			overflow,
+			pruned,
+		} = &mut *buckets;
+
+		let full = sources.len() >= MAX_TRACKED_SOURCES && !sources.contains_key(&ip);
+		if full
+			&& pruned.is_none_or(|pruned| now.saturating_duration_since(pruned) >= PRUNE_INTERVAL)
+		{
+			// Sources with a full bucket behave just like untracked ones
+			sources.retain(|_, b| Self::refill(limit, *b, now).tokens < limit.burst);
+			*pruned = Some(now);
+		}
+
+		let bucket = if sources.len() < MAX_TRACKED_SOURCES || sources.contains_key(&ip) {
+			sources.entry(ip).or_insert(Bucket {
+				tokens: limit.burst,
+				updated: now,
+			})
+		} else {
+			overflow
 		};
 
-		spawn(Arc::new(listener_v4).listen()).detach();
-		spawn(Arc::new(listener_v6).listen()).detach();
+		*bucket = Self::refill(self.limit, *bucket, now);
 
-		Ok(())
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
 	}
 
-	async fn listen(self: Arc<Self>) -> ! {
-		loop {
-			let mut buf = vec![0; BUF_SIZE];
+	fn refill(limit: RateLimit, bucket: Bucket, now: Instant) -> Bucket {
+		let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+
+		Bucket {
+			tokens: (bucket.tokens + elapsed * limit.per_second).min(limit.burst),
+			updated: now,
+		}
+	}
+}
+
+/// A received datagram, along with a handle for replying to it
+pub struct Datagram {
+	/// The payload, copied out of the listener's receive buffer so the buffer
+	/// can take the next datagram while this one waits in the service's queue
+	pub data: Vec<u8>,
+	/// The sender of the datagram
+	pub peer: Peer,
+	pub reply: Reply,
+}
+
+/// A handle for replying to a received datagram directly on the socket it was
+/// received on
+pub struct Reply {
+	socket: Arc<Socket>,
+	/// Where to send the response (the proxy if the request came through one)
+	addr: SockAddr,
+	/// The client the response is for, which limits are applied to
+	client: SockAddr,
+	request_len: usize,
+	/// The datagram's access log entry, written once the reply is dropped
+	entry: Mutex<Entry>,
+}
+
+impl Reply {
+	/// Count an error of the given kind (e.g. `"invalid_message"`) in the
+	/// metrics and the access log
+	pub fn error(&self, kind: &'static str) {
+		self.socket.metrics.error(kind);
+		self.entry().error(kind);
+	}
+
+	/// Record a protocol-specific field of the datagram's access log entry
+	pub fn field(&self, key: &'static str, value: String) {
+		self.entry().field(key, value);
+	}
+
+	fn entry(&self) -> MutexGuard<'_, Entry> {
+		self.entry.lock().expect("access log entry lock poisoned")
+	}
+
+	/// Send a response datagram, unless it's prevented by the service's limits
+	pub async fn send(&self, buf: &[u8]) {
+		let Self {
+			socket,
+			addr,
+			client,
+			request_len,
+			entry: _,
+		} = self;
+
+		if !socket.limiter.allow(client, *request_len, buf.len()) {
+			self.entry().error("dropped");
+			return;
+		}
+
+		trace!(
+			"Sending {} -> {}: \"{}\"",
+			socket.local,
+			Peer::from(client),
+			FmtAsciiIsh(buf)
+		);
+
+		match socket.socket.write_with(|s| s.send_to(buf, addr)).await {
+			Ok(_) => {
+				socket.metrics.sent(buf.len());
+				self.entry().bytes_out += buf.len() as u64;
+			}
+			Err(e) => {
+				warn!("UDP `send` error: {e}");
+				self.error("io");
+			}
+		}
+	}
+}
+
+/// The limits of a service's UDP listener, shared between all of its sockets
+struct Limiter {
+	limits: Limits,
+	rate_limiter: Option<RateLimiter>,
+	stats: Arc<Stats>,
+}
+
+impl Limiter {
+	/// Check whether a response of `len` bytes to a request of `request_len`
+	/// bytes from `addr` may be sent, updating the statistics if it may not
+	///
+	/// Only IP sources are rate limited, as Unix domain sockets can't be
+	/// spoofed.
+	fn allow(&self, addr: &SockAddr, request_len: usize, len: usize) -> bool {
+		if let Some(max_ratio) = self.limits.max_ratio {
+			if len as f64 > request_len as f64 * max_ratio {
+				let n = self.stats.too_large.fetch_add(1, Ordering::Relaxed) + 1;
+				debug!(
+					"Dropping {len}-byte {} response to {} for a {request_len}-byte request \
+					 (maximum ratio {max_ratio}, {n} dropped so far)",
+					self.limits.service,
+					Peer::from(addr)
+				);
+				return false;
+			}
+		}
+
+		if let (Some(rate_limiter), Some(addr)) = (&self.rate_limiter, addr.as_socket()) {
+			if !rate_limiter.check(addr.ip(), Instant::now()) {
+				let n = self.stats.rate_limited.fetch_add(1, Ordering::Relaxed) + 1;
+				debug!(
+					"Dropping {} response to rate-limited {addr} ({n} dropped so far)",
+					self.limits.service
+				);
+				return false;
+			}
+		}
+
+		true
+	}
+}
+
+/// A single bound UDP (or Unix datagram) socket
+struct Socket {
+	socket: Async<OsSocket>,
+	local: Peer,
+	limiter: Arc<Limiter>,
+	metrics: &'static Metrics,
+	/// Sources whose datagrams start with a PROXY protocol header
+	proxies: Arc<[IpNet]>,
+}
 
-			let (n, addr) = match self.socket.recv_from(&mut buf).await {
-				Ok((stream, addr)) => (stream, addr),
+impl Socket {
+	/// Receive a datagram into `buf`, returning its length, sender, and whether
+	/// it was truncated because it didn't fit
+	async fn recv(&self, buf: &mut [u8]) -> Result<(usize, SockAddr, bool), Error> {
+		let (n, flags, addr) = self
+			.socket
+			.read_with(|socket| {
+				// SAFETY: `[u8]` and `[MaybeUninit<u8>]` have the same layout, and the
+				// socket only ever writes initialized bytes to the buffer
+				let buf = unsafe { &mut *(&raw mut *buf as *mut [MaybeUninit<u8>]) };
+				socket.recv_from_vectored(&mut [MaybeUninitSlice::new(buf)])
+			})
+			.await?;
+
+		Ok((n, addr, flags.is_truncated()))
+	}
+
+	async fn listen(self: Arc<Self>, channel: Sender<Datagram>) {
+		let limits = self.limiter.limits;
+		let max_payload = limits.max_payload.unwrap_or(BUF_SIZE).min(BUF_SIZE);
+		let mut buf = vec![0; max_payload];
+
+		loop {
+			let (n, addr) = match self.recv(&mut buf).await {
+				Ok((_, addr, true)) => {
+					warn!(
+						"Dropping {} datagram from {} larger than the maximum payload of \
+						 {max_payload} bytes",
+						limits.service,
+						Peer::from(&addr)
+					);
+					self.metrics.error("oversized");
+					continue;
+				}
+				Ok((n, addr, false)) => (n, addr),
 				Err(e) => {
 					warn!("UDP `recv` error: {e}");
+					self.metrics.error("io");
 					continue;
 				}
 			};
 
-			let local_addr = self
-				.socket
-				.local_addr()
-				.expect("unknown local socket address");
+			let (client, data) = match addr.as_socket() {
+				Some(proxy) if proxy::is_trusted(&self.proxies, proxy.ip()) => {
+					match proxy::parse_v2(&buf[..n]) {
+						Ok((client, len)) => {
+							if let Some(client) = client {
+								debug!("Datagram from {client} proxied by {proxy}");
+							}
 
-			debug!("New datagram {addr} -> {local_addr}");
+							(
+								client.map_or_else(|| addr.clone(), SockAddr::from),
+								&buf[len..n],
+							)
+						}
+						Err(e) => {
+							warn!("Invalid PROXY protocol header from {proxy}: {e}");
+							self.metrics.error("proxy_header");
+							continue;
+						}
+					}
+				}
+				_ => (addr.clone(), &buf[..n]),
+			};
+
+			let peer = Peer::from(&client);
+			self.metrics.received(data.len());
+
+			debug!("New datagram {peer} -> {}", self.local);
 			trace!(
-				"Received {addr} -> {local_addr}: \"{}\"",
-				FmtAsciiIsh(&buf[..n])
+				"Received {peer} -> {}: \"{}\"",
+				self.local,
+				FmtAsciiIsh(data)
 			);
 
-			buf.truncate(n);
-			let (sender, receiver) = channel::unbounded::<Vec<_>>();
-			let res = (buf, addr, sender);
-			self.channel.send(res).await.expect("UDP channel closed");
-
-			let arc_self = Arc::clone(&self);
-			spawn(async move {
-				loop {
-					if let Ok(buf) = receiver.recv().await {
-						trace!("Sending {local_addr} -> {addr}: \"{}\"", FmtAsciiIsh(&buf));
-
-						if let Err(e) = arc_self.socket.send_to(&buf, addr).await {
-							warn!("UDP `send` error: {e}");
-						};
-					} else {
-						debug!("End of UDP responses from {local_addr} to {addr}");
-						break;
-					}
-				}
+			let mut entry = self.metrics.entry(self.local.clone(), peer.clone());
+			entry.bytes_in = data.len() as u64;
+
+			// Copying the payload is deliberate: handing out the receive buffer
+			// itself would stop receiving until the service is done with it
+			let datagram = Datagram {
+				data: data.to_vec(),
+				peer,
+				reply: Reply {
+					socket: Arc::clone(&self),
+					addr,
+					client,
+					request_len: data.len(),
+					entry: Mutex::new(entry),
+				},
+			};
+
+			if channel.send(datagram).await.is_err() {
+				debug!("UDP channel closed, no longer receiving datagrams");
+				break;
+			}
+		}
+	}
+}
+
+pub struct Listener {
+	sockets: Vec<(Async<OsSocket>, SockAddr)>,
+}
+
+impl Listener {
+	/// Bind to `port` on `ip`, or on all IPv4 and IPv6 addresses if `ip` is
+	/// `None`, for the named service (see [`utils::bind`] for `reuse_port`)
+	pub fn bind(
+		service: &str,
+		ip: Option<IpAddr>,
+		port: u16,
+		reuse_port: bool,
+	) -> Result<Self, Error> {
+		Self::from_sockets(utils::bind(
+			service,
+			ip,
+			port,
+			reuse_port,
+			Type::DGRAM,
+			Protocol::UDP,
+			|_| Ok(()),
+		)?)
+	}
+
+	/// Use an already bound socket (e.g. one inherited from a parent process)
+	pub fn from_std(socket: StdSocket) -> Result<Self, Error> {
+		Self::from_sockets([socket.into()])
+	}
+
+	/// Use already bound datagram sockets of any domain (e.g. Unix domain)
+	pub fn from_sockets(sockets: impl IntoIterator<Item = OsSocket>) -> Result<Self, Error> {
+		let sockets = sockets
+			.into_iter()
+			.map(|socket| {
+				let local_addr = socket.local_addr()?;
+				Ok((Async::new(socket)?, local_addr))
 			})
-			.detach();
+			.collect::<Result<_, Error>>()?;
+
+		Ok(Self { sockets })
+	}
+
+	/// The IP addresses this listener is bound to
+	pub fn local_addrs(&self) -> Vec<SocketAddr> {
+		let mut addrs = self
+			.sockets
+			.iter()
+			.filter_map(|(_, addr)| addr.as_socket())
+			.collect::<Vec<_>>();
+
+		// Sockets sharing their address with `SO_REUSEPORT` are next to each other
+		addrs.dedup();
+		addrs
+	}
+
+	/// Start receiving datagrams and sending them to `channel`, with responses
+	/// subject to `limits`, until the returned tasks are dropped
+	///
+	/// Datagrams from `proxies` must start with a version 2 PROXY protocol
+	/// header, and the client's address from it is used instead of the proxy's
+	/// (responses are still sent back through the proxy).
+	pub fn spawn(
+		self,
+		channel: Sender<Datagram>,
+		limits: Limits,
+		proxies: Arc<[IpNet]>,
+	) -> Vec<Task<()>> {
+		// Restarted services keep counting where they left off
+		let stats = {
+			let mut all = STATS.lock().expect("UDP statistics lock poisoned");
+
+			if let Some((_, stats)) = all.iter().find(|(s, _)| *s == limits.service) {
+				Arc::clone(stats)
+			} else {
+				let stats = Arc::new(Stats::default());
+				all.push((limits.service, Arc::clone(&stats)));
+				stats
+			}
+		};
+
+		let limiter = Arc::new(Limiter {
+			limits,
+			rate_limiter: limits.rate.map(RateLimiter::new),
+			stats,
+		});
+
+		self.sockets
+			.into_iter()
+			.map(|(socket, local_addr)| {
+				let transport = if local_addr.as_socket().is_some() {
+					Transport::Udp
+				} else {
+					Transport::UnixDgram
+				};
+
+				let socket = Arc::new(Socket {
+					socket,
+					local: Peer::from(&local_addr),
+					limiter: Arc::clone(&limiter),
+					metrics: metrics::get(limits.service, transport),
+					proxies: Arc::clone(&proxies),
+				});
+
+				spawn(socket.listen(channel.clone()))
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{Ipv4Addr, Ipv6Addr};
+
+	use super::*;
+
+	#[test]
+	fn rate_limit_new() {
+		assert_eq!(RateLimit::new(10.0, None).unwrap(), RateLimit {
+			per_second: 10.0,
+			burst: 10.0
+		});
+		assert_eq!(RateLimit::new(0.5, None).unwrap(), RateLimit {
+			per_second: 0.5,
+			burst: 1.0
+		});
+		assert_eq!(RateLimit::new(2.0, Some(5.0)).unwrap(), RateLimit {
+			per_second: 2.0,
+			burst: 5.0
+		});
+
+		assert!(RateLimit::new(0.0, None).is_err());
+		assert!(RateLimit::new(-1.0, None).is_err());
+		assert!(RateLimit::new(f64::NAN, None).is_err());
+		assert!(RateLimit::new(f64::INFINITY, None).is_err());
+		assert!(RateLimit::new(1.0, Some(0.5)).is_err());
+	}
+
+	#[test]
+	fn rate_limiter() {
+		let limiter = RateLimiter::new(RateLimit::new(2.0, Some(3.0)).unwrap());
+		let a = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+		let b = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+		let start = Instant::now();
+
+		// The burst is available immediately ...
+		assert!(limiter.check(a, start));
+		assert!(limiter.check(a, start));
+		assert!(limiter.check(a, start));
+		// ... but after that the source has to wait
+		assert!(!limiter.check(a, start));
+
+		// Other sources are unaffected
+		assert!(limiter.check(b, start));
+
+		// Two tokens are added every second
+		assert!(!limiter.check(a, start + Duration::from_millis(250)));
+		assert!(limiter.check(a, start + Duration::from_millis(500)));
+		assert!(!limiter.check(a, start + Duration::from_millis(500)));
+
+		// The bucket never holds more than the burst size
+		let later = start + Duration::from_secs(60);
+		assert!(limiter.check(a, later));
+		assert!(limiter.check(a, later));
+		assert!(limiter.check(a, later));
+		assert!(!limiter.check(a, later));
+	}
+
+	#[test]
+	fn rate_limiter_forgets_idle_sources() {
+		let limiter = RateLimiter::new(RateLimit::new(1.0, None).unwrap());
+		let start = Instant::now();
+
+		for i in 0..MAX_TRACKED_SOURCES as u32 {
+			assert!(limiter.check(IpAddr::V4(Ipv4Addr::from_bits(i)), start));
+		}
+
+		let later = start + Duration::from_secs(1);
+		assert!(limiter.check(IpAddr::V4(Ipv4Addr::BROADCAST), later));
+		assert_eq!(limiter.buckets.lock().unwrap().sources.len(), 1);
+	}
+
+	#[test]
+	fn rate_limiter_is_bounded() {
+		let limiter = RateLimiter::new(RateLimit::new(1.0, Some(2.0)).unwrap());
+		let ip = |i: usize| IpAddr::V4(Ipv4Addr::from_bits(i as u32));
+		let start = Instant::now();
+
+		for i in 0..MAX_TRACKED_SOURCES {
+			assert!(limiter.check(ip(i), start));
 		}
+
+		// None of the tracked sources are idle yet, so a flood of new sources
+		// shares a single bucket instead of being tracked
+		let later = start + Duration::from_millis(500);
+		let allowed = (MAX_TRACKED_SOURCES..3 * MAX_TRACKED_SOURCES)
+			.filter(|&i| limiter.check(ip(i), later))
+			.count();
+		assert_eq!(allowed, 2);
+		assert_eq!(
+			limiter.buckets.lock().unwrap().sources.len(),
+			MAX_TRACKED_SOURCES
+		);
+
+		// Tracked sources keep their own buckets
+		assert!(limiter.check(ip(0), later));
 	}
 }
@@ -0,0 +1,343 @@
+//! Unix domain socket listeners
+
+#[cfg(unix)]
+use std::{
+	ffi::OsString,
+	fs::{self, Permissions},
+	io::Error as IoError,
+	os::{
+		fd::{AsFd, OwnedFd},
+		unix::{
+			ffi::OsStringExt,
+			fs::{FileTypeExt, PermissionsExt},
+			net::{UnixListener, UnixStream},
+		},
+	},
+	path::Path,
+};
+
+use anyhow::anyhow;
+#[cfg(unix)]
+use log::{info, warn};
+#[cfg(unix)]
+use smol::Async;
+#[cfg(unix)]
+use socket2::{Domain, SockAddr, SockRef, Socket};
+
+#[cfg(unix)]
+use crate::{
+	executor::spawn,
+	metrics::{self, Metered, Transport},
+	privileges,
+	reuse::{self, Bound},
+	tcp::{Active, Stream},
+	utils::PeerCred,
+};
+use crate::{
+	services::{Config, Future, Handler, ServiceErr},
+	udp::Listener as UdpListener,
+	utils::Peer,
+};
+
+#[cfg(unix)]
+const UNIX_BACKLOG: i32 = 1024;
+
+#[cfg(unix)]
+impl Stream for Async<UnixStream> {
+	fn peer(&self) -> Peer {
+		let socket = SockRef::from(self.get_ref());
+		socket
+			.peer_addr()
+			.map_or(Peer::Unknown, |addr| with_cred(Peer::from(&addr), &*socket))
+	}
+
+	fn local(&self) -> Peer {
+		SockRef::from(self.get_ref())
+			.local_addr()
+			.map_or(Peer::Unknown, |addr| Peer::from(&addr))
+	}
+
+	fn error(&mut self, _: &'static str) {}
+
+	fn field(&mut self, _: &'static str, _: String) {}
+}
+
+/// Add the credentials of the process on the other end of a connected Unix
+/// domain socket to its `peer` description, if they're available
+#[cfg(unix)]
+pub fn with_cred(peer: Peer, socket: &impl AsFd) -> Peer {
+	let Peer::Unix { path, .. } = peer else {
+		return peer;
+	};
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	let cred = nix::sys::socket::getsockopt(socket, nix::sys::socket::sockopt::PeerCredentials)
+		.ok()
+		.map(|cred| PeerCred {
+			pid: cred.pid(),
+			uid: cred.uid(),
+			gid: cred.gid(),
+		});
+
+	#[cfg(not(any(target_os = "linux", target_os = "android")))]
+	let cred = {
+		let _ = socket;
+		None::<PeerCred>
+	};
+
+	Peer::Unix { path, cred }
+}
+
+/// Get the address of a Unix domain socket path, where paths starting with `@`
+/// are in the abstract namespace (only on Linux)
+#[cfg(unix)]
+fn address(path: &str) -> Result<SockAddr, IoError> {
+	match path.strip_prefix('@') {
+		#[cfg(any(target_os = "linux", target_os = "android"))]
+		Some(name) => SockAddr::unix(OsString::from_vec([b"\0", name.as_bytes()].concat())),
+		#[cfg(not(any(target_os = "linux", target_os = "android")))]
+		Some(_) => Err(IoError::new(
+			std::io::ErrorKind::Unsupported,
+			"abstract Unix domain socket names are only supported on Linux",
+		)),
+		None => SockAddr::unix(path),
+	}
+}
+
+/// Create a non-blocking Unix domain socket of type `ty` bound to `path` for
+/// the named service, with the permissions set to `mode` if given
+///
+/// A stale socket file already at `path` is replaced. If the named service is
+/// being restarted with the same path, its previous socket is used instead.
+#[cfg(unix)]
+fn bind(
+	service: &str,
+	path: &str,
+	ty: socket2::Type,
+	mode: Option<u32>,
+) -> Result<Socket, IoError> {
+	let addr = address(path)?;
+
+	if let Some(socket) = reuse::take_unix(service, &addr, ty).pop() {
+		return Ok(socket);
+	}
+
+	if let Some(path) = addr.as_pathname() {
+		if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+			fs::remove_file(path)?;
+		}
+	}
+
+	let socket = Socket::new(Domain::UNIX, ty, None)?;
+	socket.set_nonblocking(true)?;
+	socket.bind(&addr)?;
+
+	if let (Some(mode), Some(path)) = (mode, addr.as_pathname()) {
+		fs::set_permissions(path, Permissions::from_mode(mode))?;
+	}
+
+	reuse::record(service, ty, std::slice::from_ref(&socket), false);
+	Ok(socket)
+}
+
+/// Remove the files of the Unix domain sockets among `sockets` that were bound
+/// by this server and aren't among the `kept` ones (e.g. of a restarted
+/// server), looking them up inside the changed root directory `root` (if any)
+#[cfg(unix)]
+pub fn remove_files(sockets: &[Bound], kept: &[Bound], root: Option<&Path>) {
+	fn path(bound: &Bound) -> Option<&Path> {
+		(!bound.inherited())
+			.then(|| bound.addr().as_pathname())
+			.flatten()
+	}
+
+	for removed in sockets.iter().filter_map(path) {
+		if kept.iter().filter_map(path).any(|path| path == removed) {
+			continue;
+		}
+
+		let res = privileges::in_root(root, removed)
+			.and_then(|path| fs::remove_file(path).map_err(Into::into));
+
+		if let Err(e) = res {
+			warn!("Couldn't remove the socket file {}: {e}", removed.display());
+		}
+	}
+}
+
+/// Start serving the named service on its configured Unix stream socket, with
+/// `serve` handling each connection
+#[cfg(unix)]
+pub fn serve_stream<F: Future<Output = ()> + Send + 'static>(
+	config: &Config,
+	service: &'static str,
+	serve: impl Fn(Metered<Async<UnixStream>>) -> F + Send + 'static,
+) -> Result<Handler, ServiceErr> {
+	let Some(path) = config.unix.get_ref(service) else {
+		return Err(ServiceErr::NoHandler);
+	};
+
+	info!("starting {service} service on Unix stream socket {path}");
+
+	let socket = bind(service, path, socket2::Type::STREAM, config.unix_mode)?;
+	socket.listen(UNIX_BACKLOG)?;
+	let listener = Async::new(UnixListener::from(OwnedFd::from(socket)))?;
+	let metrics = metrics::get(service, Transport::Unix);
+	let local = Peer::from(&address(path)?);
+
+	let task = spawn(async move {
+		loop {
+			let stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					warn!("Unix `accept` error: {e}");
+					continue;
+				}
+			};
+
+			let peer = stream.peer();
+			info!("New {service} connection from {peer}");
+
+			let active = Active::track();
+			let serve = serve(Metered::new(stream, metrics, local.clone(), peer));
+			spawn(async move {
+				serve.await;
+				drop(active);
+			})
+			.detach();
+		}
+	});
+
+	Ok(Handler {
+		addrs: Vec::new(),
+		tasks: vec![task],
+	})
+}
+
+/// Start serving the named service on its configured Unix datagram socket,
+/// with `serve` handling the received datagrams
+#[cfg(unix)]
+pub fn serve_dgram(
+	config: &Config,
+	service: &'static str,
+	serve: impl FnOnce(UdpListener) -> Result<Handler, ServiceErr>,
+) -> Result<Handler, ServiceErr> {
+	let Some(path) = config.unix_dgram.get_ref(service) else {
+		return Err(ServiceErr::NoHandler);
+	};
+
+	info!("starting {service} service on Unix datagram socket {path}");
+
+	let socket = bind(service, path, socket2::Type::DGRAM, config.unix_mode)?;
+
+	match serve(UdpListener::from_sockets([socket])?) {
+		Err(ServiceErr::NoHandler) => Err(ServiceErr::Other(anyhow!(
+			"the {service} service isn't available over datagrams"
+		))),
+		res => res,
+	}
+}
+
+/// Unix domain sockets are only supported on Unix, so fail if any are
+/// configured (`serve` only takes a TCP stream to keep the signatures alike)
+#[cfg(not(unix))]
+pub fn serve_stream<F: Future<Output = ()> + Send + 'static>(
+	config: &Config,
+	service: &'static str,
+	_: impl Fn(smol::net::TcpStream) -> F,
+) -> Result<Handler, ServiceErr> {
+	unsupported(config.unix.get_ref(service))
+}
+
+/// Unix domain sockets are only supported on Unix, so fail if any are
+/// configured
+#[cfg(not(unix))]
+pub fn serve_dgram(
+	config: &Config,
+	service: &'static str,
+	_: impl FnOnce(UdpListener) -> Result<Handler, ServiceErr>,
+) -> Result<Handler, ServiceErr> {
+	unsupported(config.unix_dgram.get_ref(service))
+}
+
+/// There are no Unix domain socket files to remove on other platforms
+#[cfg(not(unix))]
+pub fn remove_files(
+	_: &[crate::reuse::Bound],
+	_: &[crate::reuse::Bound],
+	_: Option<&std::path::Path>,
+) {
+}
+
+#[cfg(not(unix))]
+fn unsupported(path: Option<&String>) -> Result<Handler, ServiceErr> {
+	match path {
+		Some(_) => Err(ServiceErr::Other(anyhow!(
+			"Unix domain sockets are only supported on Unix"
+		))),
+		None => Err(ServiceErr::NoHandler),
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use std::os::unix::net::UnixDatagram;
+
+	use super::*;
+
+	#[test]
+	fn addresses() {
+		let addr = address("/run/echo.sock").unwrap();
+		assert_eq!(addr.as_pathname(), Some("/run/echo.sock".as_ref()));
+		assert_eq!(Peer::from(&addr).to_string(), "unix:/run/echo.sock");
+
+		#[cfg(target_os = "linux")]
+		{
+			let addr = address("@echo").unwrap();
+			assert_eq!(addr.as_abstract_namespace(), Some(&b"echo"[..]));
+			assert_eq!(Peer::from(&addr).to_string(), "unix:@echo");
+		}
+	}
+
+	#[test]
+	fn bind_replaces_sockets_only() {
+		let path =
+			std::env::temp_dir().join(format!("simple-protocols-{}-test", std::process::id()));
+		let path_str = path.to_str().unwrap();
+
+		drop(UnixDatagram::bind(&path).unwrap());
+		let socket = bind("test", path_str, socket2::Type::DGRAM, Some(0o640)).unwrap();
+		assert_eq!(
+			fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+			0o640
+		);
+		drop(socket);
+
+		fs::remove_file(&path).unwrap();
+		fs::write(&path, "not a socket").unwrap();
+		assert!(bind("test", path_str, socket2::Type::DGRAM, None).is_err());
+		fs::remove_file(&path).unwrap();
+	}
+
+	#[cfg(any(target_os = "linux", target_os = "android"))]
+	#[test]
+	fn credentials() {
+		let (a, _b) = UnixStream::pair().unwrap();
+
+		let peer = with_cred(
+			Peer::Unix {
+				path: None,
+				cred: None,
+			},
+			&a,
+		);
+		assert_eq!(peer, Peer::Unix {
+			path: None,
+			cred: Some(PeerCred {
+				pid: std::process::id() as i32,
+				uid: nix::unistd::getuid().as_raw(),
+				gid: nix::unistd::getgid().as_raw(),
+			}),
+		});
+	}
+}
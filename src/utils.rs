@@ -3,10 +3,157 @@
 use std::{
 	borrow::Cow,
 	fmt::{Debug, Display, Formatter, Result as FmtResult},
-	net::SocketAddr,
+	io::{Error as IoError, ErrorKind},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 	str,
 };
 
+use const_format::str_split;
+use log::{debug, info};
+use rand::seq::IndexedRandom;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::{executor, reuse, systemd};
+
+/// The quotes embedded by the build script, one per line
+const QUOTES: &[&str] = &str_split!(include_str!(concat!(env!("OUT_DIR"), "/quotes.txt")), "\n");
+
+/// How many times to retry finding an ephemeral port that's free on both IPv4
+/// and IPv6
+const EPHEMERAL_PORT_ATTEMPTS: usize = 16;
+
+/// Create non-blocking sockets bound to `port` on `ip`, or on all IPv4 and IPv6
+/// addresses if `ip` is `None`, calling `setup` on each socket before binding
+///
+/// If `port` is 0 and both IPv4 and IPv6 sockets are created, they are bound to
+/// the same ephemeral port. With `reuse_port`, `SO_REUSEPORT` is set, and each
+/// address gets one socket per worker thread for the kernel to spread clients
+/// between. If the named service is being restarted with the same address, or
+/// systemd passed any sockets for it, those are used instead.
+pub fn bind(
+	service: &str,
+	ip: Option<IpAddr>,
+	port: u16,
+	reuse_port: bool,
+	ty: Type,
+	protocol: Protocol,
+	setup: impl Fn(&Socket) -> Result<(), IoError>,
+) -> Result<Vec<Socket>, IoError> {
+	let reused = reuse::take(service, ip, port, ty);
+	if !reused.is_empty() {
+		debug!(
+			"Reusing {} socket(s) of the previous {service} service",
+			reused.len()
+		);
+
+		for socket in &reused {
+			setup(socket)?;
+		}
+
+		return Ok(reused);
+	}
+
+	let inherited = systemd::take(service, port, ty);
+	if !inherited.is_empty() {
+		info!(
+			"Using {} socket(s) passed by systemd for the {service} service",
+			inherited.len()
+		);
+
+		for socket in &inherited {
+			setup(socket)?;
+		}
+
+		reuse::record(service, ty, &inherited, true);
+		return Ok(inherited);
+	}
+
+	let sockets = bind_new(ip, port, reuse_port, ty, protocol, setup)?;
+	reuse::record(service, ty, &sockets, false);
+	Ok(sockets)
+}
+
+fn bind_new(
+	ip: Option<IpAddr>,
+	port: u16,
+	reuse_port: bool,
+	ty: Type,
+	protocol: Protocol,
+	setup: impl Fn(&Socket) -> Result<(), IoError>,
+) -> Result<Vec<Socket>, IoError> {
+	let bind = |addr: SocketAddr| {
+		let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+		socket.set_nonblocking(true)?;
+		if addr.is_ipv6() {
+			socket.set_only_v6(true)?;
+		}
+		if reuse_port {
+			#[cfg(unix)]
+			socket.set_reuse_port(true)?;
+			#[cfg(not(unix))]
+			return Err(IoError::new(
+				ErrorKind::Unsupported,
+				"SO_REUSEPORT is only supported on Unix",
+			));
+		}
+		setup(&socket)?;
+		socket.bind(&addr.into())?;
+		Ok::<_, IoError>(socket)
+	};
+
+	// Add the other worker threads' sockets for the address `first` is bound to
+	let shard = |first: Socket| {
+		let shards = if reuse_port { executor::threads() } else { 1 };
+		let addr = first
+			.local_addr()?
+			.as_socket()
+			.expect("IP socket without an IP address");
+
+		let mut sockets = vec![first];
+		for _ in 1..shards {
+			sockets.push(bind(addr)?);
+		}
+
+		Ok::<_, IoError>(sockets)
+	};
+
+	if let Some(ip) = ip {
+		return shard(bind(SocketAddr::new(ip, port))?);
+	}
+
+	let mut attempts = 0;
+	loop {
+		let v4 = bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))?;
+		let v4_port = v4
+			.local_addr()?
+			.as_socket()
+			.map_or(port, |addr| addr.port());
+
+		match bind(SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), v4_port)) {
+			Ok(v6) => {
+				let mut sockets = shard(v4)?;
+				sockets.extend(shard(v6)?);
+				return Ok(sockets);
+			}
+			Err(e)
+				if port == 0
+					&& e.kind() == ErrorKind::AddrInUse
+					&& attempts < EPHEMERAL_PORT_ATTEMPTS =>
+			{
+				attempts += 1;
+			}
+			Err(e) => return Err(e),
+		}
+	}
+}
+
+/// Pick a random quote (of at most 510 ASCII characters)
+pub fn quote() -> &'static str {
+	QUOTES
+		.choose(&mut rand::rng())
+		.expect("there are not quotes")
+}
+
 /// Decode an ISO/IES 8859-1 string
 pub fn decode_iso_8859_1(s: &[u8]) -> Result<Cow<'_, str>, usize> {
 	if s.is_ascii() {
@@ -85,37 +232,152 @@ impl Display for FmtMaybeUtf8<'_> {
 	}
 }
 
-/// Format a socket address, if it's known
-pub struct FmtMaybeAddr<'a, E>(pub &'a Result<SocketAddr, E>);
+/// The other end of a connection or datagram, for logging
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Peer {
+	/// An IP socket address
+	Inet(SocketAddr),
+	/// A Unix domain socket, with its path (`None` if unnamed) and the
+	/// credentials of the connected process (if known)
+	Unix {
+		path: Option<String>,
+		cred: Option<PeerCred>,
+	},
+	/// The address couldn't be determined
+	Unknown,
+}
 
-impl<E: Debug> Debug for FmtMaybeAddr<'_, E> {
-	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-		match self.0 {
-			Ok(addr) => write!(f, "{addr:?}"),
-			Err(err) => write!(f, "{err:?}"),
+/// The credentials of the process on the other end of a Unix domain socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+	pub pid: i32,
+	pub uid: u32,
+	pub gid: u32,
+}
+
+impl<E> From<Result<SocketAddr, E>> for Peer {
+	fn from(addr: Result<SocketAddr, E>) -> Self {
+		addr.map_or(Self::Unknown, Self::Inet)
+	}
+}
+
+impl From<&SockAddr> for Peer {
+	fn from(addr: &SockAddr) -> Self {
+		if let Some(addr) = addr.as_socket() {
+			return Self::Inet(addr);
 		}
+
+		#[cfg(unix)]
+		if addr.is_unix() {
+			let path = if let Some(path) = addr.as_pathname() {
+				Some(path.display().to_string())
+			} else {
+				addr.as_abstract_namespace()
+					.map(|name| format!("@{}", String::from_utf8_lossy(name)))
+			};
+
+			return Self::Unix { path, cred: None };
+		}
+
+		Self::Unknown
 	}
 }
 
-impl<E> Display for FmtMaybeAddr<'_, E> {
+impl Display for Peer {
 	fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-		if let Ok(addr) = self.0 {
-			write!(f, "{addr}")
-		} else {
-			write!(f, "[address unknown]")
+		match self {
+			Self::Inet(addr) => write!(f, "{addr}"),
+			Self::Unix { path, cred } => {
+				write!(f, "unix:{}", path.as_deref().unwrap_or("[unnamed]"))?;
+
+				if let Some(PeerCred { pid, uid, gid }) = cred {
+					write!(f, " (pid {pid}, uid {uid}, gid {gid})")?;
+				}
+
+				Ok(())
+			}
+			Self::Unknown => write!(f, "[address unknown]"),
 		}
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use std::{
-		array,
-		net::{IpAddr, Ipv4Addr, Ipv6Addr},
-	};
+	use std::array;
 
 	use super::*;
 
+	#[test]
+	fn bind_ephemeral() {
+		let sockets = bind("test", None, 0, false, Type::DGRAM, Protocol::UDP, |_| {
+			Ok(())
+		})
+		.unwrap();
+		let addrs = sockets
+			.iter()
+			.map(|s| s.local_addr().unwrap().as_socket().unwrap())
+			.collect::<Vec<_>>();
+
+		assert_eq!(addrs.len(), 2);
+		assert!(addrs[0].is_ipv4());
+		assert!(addrs[1].is_ipv6());
+		assert_ne!(addrs[0].port(), 0);
+		assert_eq!(addrs[0].port(), addrs[1].port());
+	}
+
+	#[test]
+	fn bind_ip() {
+		let sockets = bind(
+			"test",
+			Some(Ipv4Addr::LOCALHOST.into()),
+			0,
+			false,
+			Type::STREAM,
+			Protocol::TCP,
+			|s| s.set_tcp_nodelay(true),
+		)
+		.unwrap();
+
+		assert_eq!(sockets.len(), 1);
+		assert!(sockets[0].tcp_nodelay().unwrap());
+
+		let addr = sockets[0].local_addr().unwrap().as_socket().unwrap();
+		assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+		assert_ne!(addr.port(), 0);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn bind_reuse_port() {
+		executor::start(2);
+
+		let bind = |port| {
+			bind(
+				"test",
+				Some(Ipv4Addr::LOCALHOST.into()),
+				port,
+				true,
+				Type::STREAM,
+				Protocol::TCP,
+				|_| Ok(()),
+			)
+			.unwrap()
+		};
+
+		// One socket per worker thread, all on the same address
+		let sockets = bind(0);
+		assert_eq!(sockets.len(), executor::threads());
+
+		let addr = sockets[0].local_addr().unwrap().as_socket().unwrap();
+		for socket in &sockets {
+			assert!(socket.reuse_port().unwrap());
+			assert_eq!(socket.local_addr().unwrap().as_socket(), Some(addr));
+		}
+
+		// Other sockets with `SO_REUSEPORT` can bind the same address too
+		assert_eq!(bind(addr.port()).len(), executor::threads());
+	}
+
 	#[test]
 	fn decode_iso_8859_1() {
 		assert_eq!(
@@ -285,11 +547,11 @@ mod tests {
 	}
 
 	#[test]
-	fn fmt_maybe_addr_display() {
+	fn peer_display() {
 		assert_eq!(
 			format!(
 				"a {} c",
-				FmtMaybeAddr(&Ok::<_, ()>(SocketAddr::new(
+				Peer::from(Ok::<_, ()>(SocketAddr::new(
 					IpAddr::V4(Ipv4Addr::LOCALHOST),
 					80
 				)))
@@ -299,7 +561,7 @@ mod tests {
 		assert_eq!(
 			format!(
 				"a {} c",
-				FmtMaybeAddr(&Ok::<_, ()>(SocketAddr::new(
+				Peer::from(&SockAddr::from(SocketAddr::new(
 					IpAddr::V6(Ipv6Addr::LOCALHOST),
 					80
 				)))
@@ -307,38 +569,32 @@ mod tests {
 			r#"a [::1]:80 c"#
 		);
 		assert_eq!(
-			format!("a {} c", FmtMaybeAddr(&Err(()))),
+			format!("a {} c", Peer::from(Err::<SocketAddr, _>("b"))),
 			r#"a [address unknown] c"#
 		);
 		assert_eq!(
-			format!("a {} c", FmtMaybeAddr(&Err("b"))),
-			r#"a [address unknown] c"#
+			format!("a {} c", Peer::Unix {
+				path: None,
+				cred: Some(PeerCred {
+					pid: 1,
+					uid: 2,
+					gid: 3
+				})
+			}),
+			r#"a unix:[unnamed] (pid 1, uid 2, gid 3) c"#
 		);
 	}
 
 	#[test]
-	fn fmt_maybe_addr_debug() {
+	#[cfg(unix)]
+	fn peer_unix() {
 		assert_eq!(
-			format!(
-				"a {:?} c",
-				FmtMaybeAddr(&Ok::<_, ()>(SocketAddr::new(
-					IpAddr::V4(Ipv4Addr::LOCALHOST),
-					80
-				)))
-			),
-			r#"a 127.0.0.1:80 c"#
+			Peer::from(&SockAddr::unix("/run/echo.sock").unwrap()).to_string(),
+			"unix:/run/echo.sock"
 		);
 		assert_eq!(
-			format!(
-				"a {:?} c",
-				FmtMaybeAddr(&Ok::<_, ()>(SocketAddr::new(
-					IpAddr::V6(Ipv6Addr::LOCALHOST),
-					80
-				)))
-			),
-			r#"a [::1]:80 c"#
+			Peer::from(&SockAddr::unix("\0echo").unwrap()).to_string(),
+			"unix:@echo"
 		);
-		assert_eq!(format!("a {:?} c", FmtMaybeAddr(&Err(()))), r#"a () c"#);
-		assert_eq!(format!("a {:?} c", FmtMaybeAddr(&Err("b"))), r#"a "b" c"#);
 	}
 }
@@ -0,0 +1,74 @@
+use std::{
+	io::{Read, Write},
+	net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+	time::Duration,
+};
+
+use simple_protocols::Server;
+
+#[test]
+fn access_log() {
+	let path = std::env::temp_dir().join(format!(
+		"simple-protocols-{}-access.log",
+		std::process::id()
+	));
+	let _ = std::fs::remove_file(&path);
+
+	let server = Server::new()
+		.service("gopher")
+		.service("message")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.access_log(&path)
+		.start()
+		.unwrap();
+
+	let gopher_addr = server.tcp_addrs("gopher")[0];
+	let mut tcp = TcpStream::connect_timeout(&gopher_addr, Duration::from_secs(1)).unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	write!(tcp, "/access-log-test\r\n").unwrap();
+	let mut res = Vec::new();
+	tcp.read_to_end(&mut res).unwrap();
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	udp.send_to(
+		b"Aaccess-log-user\0\0hello\0",
+		server.udp_addrs("message")[0],
+	)
+	.unwrap();
+	let mut buf = [0; 512];
+	let _ = udp.recv(&mut buf);
+
+	// Entries are written once their connection or datagram is fully handled
+	let find = |needle: &str| {
+		for _ in 0..50 {
+			let log = std::fs::read_to_string(&path).unwrap_or_default();
+			if let Some(line) = log.lines().find(|line| line.contains(needle)) {
+				return line.to_string();
+			}
+			std::thread::sleep(Duration::from_millis(20));
+		}
+		panic!("no access log entry containing {needle}");
+	};
+
+	let gopher = find("\"selector\":\"/access-log-test\"");
+	assert!(gopher.starts_with("{\"service\":\"gopher\",\"transport\":\"tcp\","));
+	assert!(gopher.contains(&format!("\"local\":\"{gopher_addr}\"")));
+	assert!(gopher.contains(&format!("\"peer\":\"{}\"", tcp.local_addr().unwrap())));
+	assert!(gopher.contains(&format!(
+		"\"bytes_in\":18,\"bytes_out\":{},\"outcome\":\"not_found\"",
+		res.len()
+	)));
+	assert!(gopher.ends_with('}'));
+
+	let message = find("\"recipient\":\"access-log-user\"");
+	assert!(message.starts_with("{\"service\":\"message\",\"transport\":\"udp\","));
+	assert!(message.contains(&format!("\"peer\":\"{}\"", udp.local_addr().unwrap())));
+	assert!(message.contains("\"bytes_in\":24,"));
+	assert!(message.contains("\"outcome\":\"ok\""));
+
+	server.shutdown_blocking();
+	let _ = std::fs::remove_file(&path);
+}
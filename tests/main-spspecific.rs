@@ -1,6 +1,6 @@
 use std::{
-	io::{Error as IoError, Read, Write},
-	net::{Ipv4Addr, SocketAddr, TcpStream},
+	io::{Error as IoError, ErrorKind, Read, Write},
+	net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
 	ops::{Deref, DerefMut},
 	process::{Child, Command, Stdio},
 	thread,
@@ -337,3 +337,805 @@ fn port_overflow() {
 	assert!(stderr.contains("overflow"));
 	assert!(!stderr.contains("starting gopher service"));
 }
+
+#[test]
+fn udp_max_ratio() {
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "debug"])
+		.args(["--base-port", "17000"])
+		.args(["--udp-max-ratio", "1"])
+		.args(["--udp-max-ratio", "time=4"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut buf = vec![0; 1024];
+
+	// Echo responses are exactly as large as the request
+	udp.send_to(b"Hello, World!", (Ipv4Addr::LOCALHOST, 17007))
+		.unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	// The 4-byte time response is within the time-specific limit
+	udp.send_to(b"?", (Ipv4Addr::LOCALHOST, 17037)).unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(n, 4);
+
+	// Quotes are always longer than 1 byte
+	udp.send_to(b"?", (Ipv4Addr::LOCALHOST, 17017)).unwrap();
+	assert!(
+		matches!(udp.recv(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+	);
+
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains("Dropped 0 rate-limited and 1 too large UDP qotd responses"));
+}
+
+#[test]
+fn udp_rate_limit() {
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "debug"])
+		.args(["--base-port", "18000"])
+		.args(["--udp-rate-limit", "0.1"])
+		.args(["--udp-rate-burst", "2"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	udp.connect((Ipv4Addr::LOCALHOST, 18007)).unwrap();
+	let mut buf = vec![0; 1024];
+
+	// The burst is answered ...
+	for _ in 0..2 {
+		udp.send(b"Hello, World!").unwrap();
+		let n = udp.recv(&mut buf).unwrap();
+		assert_eq!(&buf[..n], b"Hello, World!");
+	}
+
+	// ... but anything after that is dropped
+	udp.send(b"Hello, World!").unwrap();
+	assert!(
+		matches!(udp.recv(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+	);
+
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains("Dropped 1 rate-limited and 0 too large UDP echo responses"));
+}
+
+#[test]
+fn udp_max_payload() {
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "debug"])
+		.args(["--base-port", "19000"])
+		.args(["--udp-max-payload", "2000"])
+		.args(["--udp-max-payload", "echo=60000"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut buf = vec![0; 65536];
+
+	// Echo datagrams are sent back in full, not just the first kilobyte
+	let data = (0..60000).map(|i| i as u8).collect::<Vec<_>>();
+	udp.send_to(&data, (Ipv4Addr::LOCALHOST, 19007)).unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], &data[..]);
+
+	// Other services accept datagrams up to the default maximum payload ...
+	udp.send_to(&data[..2000], (Ipv4Addr::LOCALHOST, 19037))
+		.unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(n, 4);
+
+	// ... and drop anything larger
+	udp.send_to(&data[..2001], (Ipv4Addr::LOCALHOST, 19037))
+		.unwrap();
+	assert!(
+		matches!(udp.recv(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+	);
+
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains("larger than the maximum payload of 2000 bytes"));
+}
+
+#[test]
+fn inetd() {
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--inetd", "echo"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	// The connection is served over standard input and output ...
+	let mut stdin = server.stdin.take().unwrap();
+	write!(stdin, "Hello, World!").unwrap();
+	drop(stdin);
+
+	// ... and the server exits once it's closed
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(output.status.success());
+	assert_eq!(output.stdout, b"Hello, World!");
+	assert!(stderr.is_empty());
+}
+
+#[test]
+#[cfg(unix)]
+fn inetd_gopher() {
+	use std::{net::TcpListener, os::fd::OwnedFd};
+
+	let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+	let port = listener.local_addr().unwrap().port();
+	let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+	let (accepted, _) = listener.accept().unwrap();
+
+	let server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdin(OwnedFd::from(accepted.try_clone().unwrap()))
+		.stdout(OwnedFd::from(accepted))
+		.stderr(Stdio::piped())
+		.args(["--hostname", "localhost"])
+		.args(["--inetd", "gopher"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	client
+		.set_read_timeout(Some(Duration::from_secs(1)))
+		.unwrap();
+	write!(client, "\r\n").unwrap();
+	let mut menu = String::new();
+	client.read_to_string(&mut menu).unwrap();
+
+	// Menus link to the port of the socket passed by inetd
+	let items = menu
+		.lines()
+		.filter(|line| line.contains('\t'))
+		.collect::<Vec<_>>();
+	assert!(!items.is_empty());
+	assert!(
+		items
+			.iter()
+			.all(|line| line.ends_with(&format!("\tlocalhost\t{port}")))
+	);
+
+	let output = server.into_child().wait_with_output().unwrap();
+	assert!(output.status.success());
+}
+
+#[test]
+#[cfg(unix)]
+fn inetd_udp() {
+	use std::os::fd::OwnedFd;
+
+	let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+	let addr = socket.local_addr().unwrap();
+
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdin(OwnedFd::from(socket))
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "info"])
+		.args(["--inetd-udp", "time"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut buf = vec![0; 1024];
+
+	// Datagrams are received on the socket passed as standard input
+	udp.send_to(b"?", addr).unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(n, 4);
+
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains(&format!("Serving inetd datagrams on [{addr}]")));
+}
+
+#[test]
+#[cfg(unix)]
+fn inetd_udp_errors() {
+	use std::os::fd::OwnedFd;
+
+	let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+
+	let server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdin(OwnedFd::from(socket))
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "error"])
+		.args(["--inetd-udp", "gopher"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(!output.status.success());
+	assert!(stderr.contains("the gopher service isn't available over UDP"));
+
+	let server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdin(Stdio::null())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "error"])
+		.args(["--inetd-udp", "echo"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(!output.status.success());
+	assert!(stderr.contains("standard input is not a UDP socket"));
+}
+
+#[test]
+#[cfg(unix)]
+fn systemd_socket_activation() {
+	use std::{
+		net::TcpListener,
+		os::{fd::OwnedFd, unix::net::UnixDatagram},
+	};
+
+	let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+	let addr = listener.local_addr().unwrap();
+
+	let notify_path = std::env::temp_dir().join(format!(
+		"simple-protocols-test-notify-{}",
+		std::process::id()
+	));
+	let _ = std::fs::remove_file(&notify_path);
+	let notify = UnixDatagram::bind(&notify_path).unwrap();
+	notify
+		.set_read_timeout(Some(Duration::from_secs(5)))
+		.unwrap();
+
+	// The shell moves the listener to file descriptor 3 and sets `LISTEN_PID` to
+	// its own PID, which the server keeps after `exec`
+	let mut server = Command::new("sh")
+		.args([
+			"-c",
+			"LISTEN_PID=$$ exec ./target/debug/simple-protocols \"$@\" 3<&0 0</dev/null",
+			"sh",
+		])
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.env("LISTEN_FDS", "1")
+		.env("LISTEN_FDNAMES", "echo")
+		.env("NOTIFY_SOCKET", &notify_path)
+		.stdin(OwnedFd::from(listener))
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "info"])
+		.args(["--base-port", "20000"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	// Readiness is reported once all services are started
+	let mut buf = vec![0; 1024];
+	let n = notify.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"READY=1");
+
+	// The echo service accepts connections on the inherited socket
+	let mut tcp = TcpStream::connect_timeout(&addr, Duration::from_secs(1)).unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	write!(tcp, "Hello, World!").unwrap();
+	let n = tcp.read(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	server.kill_gently().unwrap();
+
+	let n = notify.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"STOPPING=1");
+	std::fs::remove_file(&notify_path).unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains("Using 1 socket(s) passed by systemd for the echo service"));
+}
+
+#[test]
+#[cfg(unix)]
+fn drop_privileges() {
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "info"])
+		.args(["--base-port", "21000"])
+		.args(["--user", "65534", "--group", "65534"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	let running = server.try_wait().unwrap().is_none();
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	// Only root can switch users, and anyone else gets an error instead of
+	// continuing with more privileges than requested
+	if nix::unistd::geteuid().is_root() {
+		assert!(running);
+		assert!(stderr.contains("Running with effective UID 65534 and GID 65534"));
+	} else {
+		assert!(!running);
+		assert!(stderr.contains("couldn't set"));
+	}
+}
+
+#[test]
+#[cfg(unix)]
+fn chroot() {
+	// Only root can change the root directory
+	if !nix::unistd::geteuid().is_root() {
+		return;
+	}
+
+	let root = std::env::temp_dir().join(format!(
+		"simple-protocols-test-chroot-{}",
+		std::process::id()
+	));
+	let _ = std::fs::remove_dir_all(&root);
+	std::fs::create_dir_all(root.join("gopher")).unwrap();
+	std::fs::create_dir_all(root.join("moved")).unwrap();
+	std::fs::write(root.join("gopher/hello.txt"), "Hello, Gopher!").unwrap();
+	std::fs::write(root.join("moved/moved.txt"), "Moved!").unwrap();
+
+	let config = root.join("config");
+	let socket = root.join("gopher.sock");
+	let access_log = root.join("access.log");
+	let options = |content_dir: &str| {
+		format!(
+			"--service gopher --hostname localhost --access-log {} --content-dir {}",
+			access_log.display(),
+			root.join(content_dir).display()
+		)
+	};
+	std::fs::write(&config, options("gopher")).unwrap();
+
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "info"])
+		.args(["--base-port", "25000"])
+		.arg("--chroot")
+		.arg(&root)
+		.arg("--config")
+		.arg(&config)
+		.args(["--unix", &format!("gopher={}", socket.display())])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	let select = |selector: &str| {
+		// Over a Unix domain socket, since the server closes the connection
+		// first, leaving its TCP port in TIME-WAIT for the next run
+		let mut unix = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+		unix.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(unix, "{selector}\r\n").unwrap();
+
+		let mut res = String::new();
+		unix.read_to_string(&mut res).unwrap();
+		res
+	};
+
+	// The content directory is opened before changing the root directory ...
+	assert!(select("/hello.txt").starts_with("Hello, Gopher!"));
+
+	// ... and on reload, the configuration file, access log, and a new content
+	// directory are found inside of it
+	std::fs::rename(&access_log, root.join("access.log.1")).unwrap();
+	std::fs::write(&config, options("moved")).unwrap();
+	assert!(
+		Command::new("kill")
+			.args(["-s", "SIGHUP", &server.id().to_string()])
+			.status()
+			.unwrap()
+			.success()
+	);
+	thread::sleep(Duration::from_secs(1));
+
+	assert!(select("/moved.txt").starts_with("Moved!"));
+	thread::sleep(Duration::from_millis(100));
+	assert!(
+		std::fs::read_to_string(&access_log)
+			.unwrap()
+			.contains("\"selector\":\"/moved.txt\"")
+	);
+
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains("Configuration reloaded"));
+
+	// The socket file is removed from inside the new root directory too
+	assert!(!socket.exists());
+
+	// Files outside of the new root directory couldn't be read
+	for (option, path) in [
+		("--content-dir", root.join("moved")),
+		("--access-log", access_log),
+		("--config", config),
+	] {
+		let output = Command::new("./target/debug/simple-protocols")
+			.env_remove("SIMPLE_PROTOCOLS_LOG")
+			.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+			.args(["--base-port", "25000"])
+			.args(["--service", "gopher", "--hostname", "localhost"])
+			.arg("--chroot")
+			.arg(root.join("gopher"))
+			.arg(option)
+			.arg(path)
+			.output()
+			.unwrap();
+		let stderr = String::from_utf8_lossy(&output.stderr);
+
+		dbg!(&stderr);
+
+		assert!(!output.status.success());
+		assert!(stderr.contains("isn't an absolute path inside of it"));
+	}
+
+	std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn reload_on_sighup() {
+	let config = std::env::temp_dir().join(format!(
+		"simple-protocols-test-config-{}",
+		std::process::id()
+	));
+	std::fs::write(
+		&config,
+		"# Only echo and daytime, with no UDP echo responses\n--service echo --service \
+		 daytime\n--udp-max-ratio echo=0.5\n",
+	)
+	.unwrap();
+
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "info"])
+		.args(["--base-port", "22000"])
+		.arg("--config")
+		.arg(&config)
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	let mut tcp = TcpStream::connect_timeout(
+		&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22007),
+		Duration::from_secs(1),
+	)
+	.unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut buf = vec![0; 1024];
+
+	udp.send_to(b"Hello, World!", (Ipv4Addr::LOCALHOST, 22007))
+		.unwrap();
+	assert!(
+		matches!(udp.recv(&mut buf), Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+	);
+
+	std::fs::write(&config, "--service echo --service time").unwrap();
+	assert!(
+		Command::new("kill")
+			.args(["-s", "SIGHUP", &server.id().to_string()])
+			.status()
+			.unwrap()
+			.success()
+	);
+	thread::sleep(Duration::from_secs(1));
+
+	// The connection accepted before the reload is still served ...
+	write!(tcp, "Hello, World!").unwrap();
+	let n = tcp.read(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	// ... the new UDP limits apply ...
+	udp.send_to(b"Hello, World!", (Ipv4Addr::LOCALHOST, 22007))
+		.unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	// ... daytime is stopped, and time is started
+	assert!(
+		TcpStream::connect_timeout(
+			&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22013),
+			Duration::from_secs(1),
+		)
+		.is_err()
+	);
+	assert!(
+		TcpStream::connect_timeout(
+			&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22037),
+			Duration::from_secs(1),
+		)
+		.is_ok()
+	);
+
+	// An invalid configuration is rejected, and the previous one kept
+	std::fs::write(&config, "--port echo=invalid").unwrap();
+	Command::new("kill")
+		.args(["-s", "SIGHUP", &server.id().to_string()])
+		.status()
+		.unwrap();
+	thread::sleep(Duration::from_secs(1));
+
+	assert!(server.try_wait().unwrap().is_none());
+	assert!(
+		TcpStream::connect_timeout(
+			&SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 22007),
+			Duration::from_secs(1),
+		)
+		.is_ok()
+	);
+
+	server.kill_gently().unwrap();
+	std::fs::remove_file(&config).unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	assert!(stderr.contains("Configuration reloaded"));
+	assert!(stderr.contains("Couldn't reload the configuration, keeping the previous one"));
+}
+
+#[test]
+fn threads() {
+	let mut server = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.args(["--log", "info"])
+		.args(["--base-port", "23000"])
+		.args(["--service", "echo"])
+		.args(["--threads", "4"])
+		.spawn()
+		.map(KillOnDrop::new)
+		.unwrap();
+
+	thread::sleep(Duration::from_secs(1));
+
+	// Keep every connection open while the others are used
+	let mut clients = (0..16)
+		.map(|_| TcpStream::connect((Ipv4Addr::LOCALHOST, 23007)).unwrap())
+		.collect::<Vec<_>>();
+
+	for (i, tcp) in clients.iter_mut().enumerate() {
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "Hello, World! {i}").unwrap();
+	}
+
+	for (i, tcp) in clients.iter_mut().enumerate() {
+		let expected = format!("Hello, World! {i}");
+		let mut buf = vec![0; expected.len()];
+		tcp.read_exact(&mut buf).unwrap();
+		assert_eq!(buf, expected.as_bytes());
+	}
+
+	drop(clients);
+	server.kill_gently().unwrap();
+
+	let output = server.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&output.stderr);
+
+	dbg!(&stderr);
+
+	for thread in 0..4 {
+		assert!(stderr.contains(&format!("Worker thread {thread} polled tasks")));
+	}
+	assert!(!stderr.contains("Worker thread 4 "));
+}
+
+#[test]
+fn zero_threads() {
+	let output = Command::new("./target/debug/simple-protocols")
+		.env_remove("SIMPLE_PROTOCOLS_LOG")
+		.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+		.args(["--log", "info"])
+		.args(["--base-port", "23000"])
+		.args(["--threads", "0"])
+		.output()
+		.unwrap();
+
+	assert!(!output.status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn handover() {
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	let path =
+		std::env::temp_dir().join(format!("simple-protocols-{}-upgrade", std::process::id()));
+	let path = path.to_str().unwrap();
+	let socket = std::env::temp_dir().join(format!(
+		"simple-protocols-{}-upgrade.sock",
+		std::process::id()
+	));
+	let unix = format!("echo={}", socket.display());
+
+	let server = |args: &[&str]| {
+		Command::new("./target/debug/simple-protocols")
+			.env_remove("SIMPLE_PROTOCOLS_LOG")
+			.env_remove("SIMPLE_PROTOCOLS_LOG_STYLE")
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			// Connections aren't logged, so the unread pipes don't fill up
+			.args(["--log", "info,simple_protocols::services=warn"])
+			.args(["--base-port", "24000"])
+			.args(["--service", "echo"])
+			.args(["--handover", path])
+			.args(["--unix", &unix])
+			.args(args)
+			.spawn()
+			.map(KillOnDrop::new)
+			.unwrap()
+	};
+
+	let echo = |tcp: &mut TcpStream| {
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		tcp.write_all(b"Hello, World!").unwrap();
+		let mut buf = [0; 13];
+		tcp.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"Hello, World!");
+	};
+
+	let mut old = server(&[]);
+	thread::sleep(Duration::from_secs(1));
+
+	let mut open = TcpStream::connect((Ipv4Addr::LOCALHOST, 24007)).unwrap();
+	echo(&mut open);
+
+	// Keep connecting throughout the upgrade, none of which may be refused
+	let done = AtomicBool::new(false);
+	let new = thread::scope(|s| {
+		s.spawn(|| {
+			while !done.load(Ordering::Relaxed) {
+				echo(&mut TcpStream::connect((Ipv4Addr::LOCALHOST, 24007)).unwrap());
+			}
+		});
+
+		let new = server(&["--takeover", path]);
+		thread::sleep(Duration::from_secs(2));
+		done.store(true, Ordering::Relaxed);
+		new
+	});
+
+	// The old server keeps serving its open connection until it's closed
+	echo(&mut open);
+	assert!(old.try_wait().unwrap().is_none());
+	drop(open);
+	thread::sleep(Duration::from_secs(1));
+	assert!(old.try_wait().unwrap().is_some());
+
+	echo(&mut TcpStream::connect((Ipv4Addr::LOCALHOST, 24007)).unwrap());
+
+	// The old server leaves the socket file to the new one
+	let mut stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+	stream
+		.set_read_timeout(Some(Duration::from_secs(1)))
+		.unwrap();
+	stream.write_all(b"Hello, World!").unwrap();
+	let mut buf = [0; 13];
+	stream.read_exact(&mut buf).unwrap();
+	assert_eq!(&buf, b"Hello, World!");
+
+	let mut new = new;
+	new.kill_gently().unwrap();
+
+	let old = old.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&old.stderr);
+	dbg!(&stderr);
+	assert!(stderr.contains("The new server is listening, stopping"));
+
+	let new = new.into_child().wait_with_output().unwrap();
+	let stderr = String::from_utf8_lossy(&new.stderr);
+	dbg!(&stderr);
+	assert!(stderr.contains("Received 5 listening sockets from the running server"));
+	assert!(!socket.exists());
+}
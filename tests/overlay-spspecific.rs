@@ -0,0 +1,51 @@
+use simple_protocols::{Entry, FsError, Overlay, Quota, Root, Session, Visibility};
+use smol::block_on;
+
+fn contents(session: &Session<'_>, path: &[u8]) -> Vec<u8> {
+	match block_on(session.read(path)).unwrap() {
+		Entry::File { contents, .. } => contents.bytes().to_vec(),
+		Entry::Directory { .. } => panic!("not a file"),
+	}
+}
+
+#[test]
+fn global() {
+	let overlay = Overlay::new(Root::Embedded, Quota::default(), Visibility::Global);
+	let writer = overlay.session();
+	let reader = overlay.session();
+
+	block_on(writer.create_dir(b"/uploads")).unwrap();
+	block_on(writer.write(b"/uploads/notes.txt", b"hello")).unwrap();
+	assert_eq!(contents(&reader, b"/uploads/notes.txt"), b"hello");
+
+	block_on(reader.remove(b"/uploads/notes.txt")).unwrap();
+	assert!(matches!(
+		block_on(writer.read(b"/uploads/notes.txt")),
+		Err(FsError::NotFound(_))
+	));
+	assert!(matches!(
+		block_on(Root::Embedded.read(b"/uploads")),
+		Err(FsError::NotFound(_))
+	));
+}
+
+#[test]
+fn session() {
+	let quota = Quota {
+		bytes: 8,
+		entries: 10,
+	};
+	let overlay = Overlay::new(Root::Embedded, quota, Visibility::Session);
+	let writer = overlay.session();
+	let reader = overlay.session();
+
+	block_on(writer.write(b"/notes.txt", b"hello")).unwrap();
+	assert_eq!(contents(&writer, b"/notes.txt"), b"hello");
+	assert!(matches!(
+		block_on(reader.read(b"/notes.txt")),
+		Err(FsError::NotFound(_))
+	));
+
+	let err = block_on(writer.write(b"/more.txt", b"hello")).unwrap_err();
+	assert_eq!(err.kind(), "quota_exceeded");
+}
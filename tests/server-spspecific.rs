@@ -0,0 +1,822 @@
+use std::{
+	io::{ErrorKind, Read, Write},
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+	time::Duration,
+};
+
+use simple_protocols::{Running, Server};
+
+#[test]
+fn ephemeral_ports() {
+	let server = Server::new()
+		.service("echo")
+		.service("time")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.start()
+		.unwrap();
+
+	let tcp_addr = server.tcp_addrs("echo")[0];
+	let udp_addr = server.udp_addrs("echo")[0];
+
+	assert_eq!(server.tcp_addrs("echo").len(), 1);
+	assert_eq!(tcp_addr.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+	assert_ne!(tcp_addr.port(), 0);
+	assert_ne!(server.tcp_addrs("time")[0], tcp_addr);
+	assert!(server.tcp_addrs("qotd").is_empty());
+
+	let mut tcp = TcpStream::connect_timeout(&tcp_addr, Duration::from_secs(1)).unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut buf = vec![0; 1024];
+
+	write!(tcp, "Hello, World!").unwrap();
+	let n = tcp.read(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+	udp.send_to(b"Hello, World!", udp_addr).unwrap();
+	let n = udp.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	server.shutdown_blocking();
+
+	assert!(
+		matches!(TcpStream::connect_timeout(&tcp_addr, Duration::from_secs(1)), Err(e) if e.kind() == ErrorKind::ConnectionRefused)
+	);
+}
+
+#[test]
+fn all_addresses() {
+	let server = Server::new()
+		.service("daytime")
+		.port("daytime", 0)
+		.start()
+		.unwrap();
+
+	let addrs = server.tcp_addrs("daytime");
+	assert_eq!(addrs.len(), 2);
+	assert!(addrs[0].is_ipv4());
+	assert!(addrs[1].is_ipv6());
+	assert_eq!(addrs[0].port(), addrs[1].port());
+
+	let mut tcp = TcpStream::connect_timeout(
+		&SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addrs[1].port()),
+		Duration::from_secs(1),
+	)
+	.unwrap();
+
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut buf = Vec::new();
+	tcp.read_to_end(&mut buf).unwrap();
+	assert!(!buf.is_empty());
+}
+
+#[test]
+fn async_shutdown() {
+	smol::block_on(async {
+		let server = Server::new()
+			.service("discard")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports()
+			.start()
+			.unwrap();
+
+		let addr = server.udp_addrs("discard")[0];
+		server.shutdown().await;
+
+		// The port is free again
+		UdpSocket::bind(addr).unwrap();
+	});
+}
+
+#[test]
+fn start_errors() {
+	assert!(
+		Server::new()
+			.service("gopher")
+			.ephemeral_ports()
+			.start()
+			.is_err()
+	);
+
+	let server = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.start()
+		.unwrap();
+
+	assert_eq!(server.tcp_addrs("gopher").len(), 1);
+	assert!(server.udp_addrs("gopher").is_empty());
+
+	assert!(
+		Server::new()
+			.service("nonexistent")
+			.ephemeral_ports()
+			.start()
+			.is_err()
+	);
+}
+
+#[test]
+fn reload() {
+	smol::block_on(async {
+		let mut server = Server::new()
+			.service("echo")
+			.service("daytime")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports()
+			.start()
+			.unwrap();
+
+		let echo_addr = server.tcp_addrs("echo")[0];
+		let daytime_addr = server.tcp_addrs("daytime")[0];
+
+		let mut tcp = TcpStream::connect_timeout(&echo_addr, Duration::from_secs(1)).unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		let mut buf = vec![0; 1024];
+
+		let reloaded = Server::new()
+			.service("echo")
+			.service("time")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports();
+		server.reload(reloaded).await.unwrap();
+
+		// Echo keeps its address, and already accepted connections stay open ...
+		assert_eq!(server.tcp_addrs("echo"), [echo_addr]);
+		write!(tcp, "Hello, World!").unwrap();
+		let n = tcp.read(&mut buf).unwrap();
+		assert_eq!(&buf[..n], b"Hello, World!");
+
+		// ... while new connections are accepted by the restarted service
+		let mut tcp = TcpStream::connect_timeout(&echo_addr, Duration::from_secs(1)).unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "Hello again!").unwrap();
+		let n = tcp.read(&mut buf).unwrap();
+		assert_eq!(&buf[..n], b"Hello again!");
+
+		// Daytime is stopped, and time is started
+		assert!(server.tcp_addrs("daytime").is_empty());
+		assert!(
+			matches!(TcpStream::connect_timeout(&daytime_addr, Duration::from_secs(1)), Err(e) if e.kind() == ErrorKind::ConnectionRefused)
+		);
+
+		let mut tcp =
+			TcpStream::connect_timeout(&server.tcp_addrs("time")[0], Duration::from_secs(1))
+				.unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		let mut buf = Vec::new();
+		tcp.read_to_end(&mut buf).unwrap();
+		assert_eq!(buf.len(), 4);
+
+		// A configuration that can't start leaves the previous one running
+		assert!(
+			server
+				.reload(Server::new().service("nonexistent").ephemeral_ports())
+				.await
+				.is_err()
+		);
+		assert_eq!(server.tcp_addrs("echo"), [echo_addr]);
+		assert!(TcpStream::connect_timeout(&echo_addr, Duration::from_secs(1)).is_ok());
+
+		server.shutdown().await;
+	});
+}
+
+#[cfg(unix)]
+#[test]
+fn unix_sockets() {
+	use std::os::unix::{
+		fs::{FileTypeExt, PermissionsExt},
+		net::{UnixDatagram, UnixStream},
+	};
+
+	let dir = std::env::temp_dir();
+	let id = std::process::id();
+	let stream_path = dir.join(format!("simple-protocols-{id}-echo.sock"));
+	let dgram_path = dir.join(format!("simple-protocols-{id}-echo-dgram.sock"));
+	let client_path = dir.join(format!("simple-protocols-{id}-client.sock"));
+
+	// A stale socket file is replaced
+	drop(std::os::unix::net::UnixListener::bind(&stream_path).unwrap());
+
+	let server = Server::new()
+		.service("echo")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.unix("echo", stream_path.to_str().unwrap())
+		.unix_dgram("echo", dgram_path.to_str().unwrap())
+		.unix_mode(0o600)
+		.start()
+		.unwrap();
+
+	let meta = std::fs::metadata(&stream_path).unwrap();
+	assert!(meta.file_type().is_socket());
+	assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+	let mut stream = UnixStream::connect(&stream_path).unwrap();
+	stream
+		.set_read_timeout(Some(Duration::from_secs(1)))
+		.unwrap();
+	let mut buf = vec![0; 1024];
+
+	write!(stream, "Hello, World!").unwrap();
+	let n = stream.read(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	let _ = std::fs::remove_file(&client_path);
+	let dgram = UnixDatagram::bind(&client_path).unwrap();
+	dgram
+		.set_read_timeout(Some(Duration::from_secs(1)))
+		.unwrap();
+
+	dgram.send_to(b"Hello, World!", &dgram_path).unwrap();
+	let n = dgram.recv(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	// The service is still available over TCP
+	assert_eq!(server.tcp_addrs("echo").len(), 1);
+
+	// Reloading removes the files of sockets no longer listened on, but keeps
+	// the ones still in use
+	let mut server = server;
+	smol::block_on(
+		server.reload(
+			Server::new()
+				.service("echo")
+				.ip(Ipv4Addr::LOCALHOST.into())
+				.ephemeral_ports()
+				.unix("echo", stream_path.to_str().unwrap()),
+		),
+	)
+	.unwrap();
+	assert!(!dgram_path.exists());
+
+	let mut stream = UnixStream::connect(&stream_path).unwrap();
+	stream
+		.set_read_timeout(Some(Duration::from_secs(1)))
+		.unwrap();
+	write!(stream, "Hello again!").unwrap();
+	let n = stream.read(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello again!");
+
+	// Shutting down removes the rest
+	server.shutdown_blocking();
+	assert!(!stream_path.exists());
+
+	std::fs::remove_file(client_path).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unix_abstract() {
+	use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr as UnixAddr};
+
+	let name = format!("simple-protocols-{}-daytime", std::process::id());
+
+	let server = Server::new()
+		.service("daytime")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.unix("daytime", &format!("@{name}"))
+		.start()
+		.unwrap();
+
+	let addr = UnixAddr::from_abstract_name(name.as_bytes()).unwrap();
+	let mut stream = std::os::unix::net::UnixStream::connect_addr(&addr).unwrap();
+	stream
+		.set_read_timeout(Some(Duration::from_secs(1)))
+		.unwrap();
+	let mut buf = Vec::new();
+	stream.read_to_end(&mut buf).unwrap();
+	assert!(!buf.is_empty());
+
+	// Services without a datagram handler can't be served over Unix datagrams
+	assert!(
+		Server::new()
+			.service("gopher")
+			.hostname("localhost")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports()
+			.unix_dgram("gopher", &format!("@{name}-gopher"))
+			.start()
+			.is_err()
+	);
+
+	server.shutdown_blocking();
+}
+
+#[test]
+fn proxy_protocol() {
+	let args = [
+		"--service",
+		"echo",
+		"--ip",
+		"127.0.0.1",
+		"--port",
+		"0",
+		"--proxy-from",
+		"127.0.0.0/8",
+		"--udp-rate-limit",
+		"0.01",
+		"--udp-rate-burst",
+		"1",
+	];
+	let server = Server::from_args(pico_args::Arguments::from_vec(
+		args.iter().map(Into::into).collect(),
+	))
+	.unwrap()
+	.start()
+	.unwrap();
+
+	let mut buf = vec![0; 1024];
+
+	// The header isn't passed on to the service
+	let mut tcp =
+		TcpStream::connect_timeout(&server.tcp_addrs("echo")[0], Duration::from_secs(1)).unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	write!(
+		tcp,
+		"PROXY TCP4 192.0.2.1 127.0.0.1 56324 7\r\nHello, World!"
+	)
+	.unwrap();
+	let n = tcp.read(&mut buf).unwrap();
+	assert_eq!(&buf[..n], b"Hello, World!");
+
+	// Connections from trusted proxies without a header are closed
+	let mut tcp =
+		TcpStream::connect_timeout(&server.tcp_addrs("echo")[0], Duration::from_secs(1)).unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	write!(tcp, "Hello, World! This isn't a PROXY header").unwrap();
+	assert!(matches!(tcp.read(&mut buf), Ok(0) | Err(_)));
+
+	// Datagrams are rate limited by the client address in the header
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_millis(500)))
+		.unwrap();
+
+	let datagram = |client: [u8; 4]| {
+		let mut datagram = b"\r\n\r\n\0\r\nQUIT\n\x21\x12\x00\x0c".to_vec();
+		datagram.extend(client);
+		datagram.extend([127, 0, 0, 1, 0xdc, 0x04, 0, 7]);
+		datagram.extend(b"Hello, World!");
+		datagram
+	};
+
+	for (client, answered) in [
+		([192, 0, 2, 1], true),
+		([192, 0, 2, 1], false),
+		([192, 0, 2, 2], true),
+	] {
+		udp.send_to(&datagram(client), server.udp_addrs("echo")[0])
+			.unwrap();
+
+		match udp.recv(&mut buf) {
+			Ok(n) => {
+				assert!(answered);
+				assert_eq!(&buf[..n], b"Hello, World!");
+			}
+			Err(_) => assert!(!answered),
+		}
+	}
+
+	server.shutdown_blocking();
+}
+
+#[cfg(unix)]
+#[test]
+fn reuse_port() {
+	let server = |port| {
+		Server::new()
+			.service("echo")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.port("echo", port)
+			.reuse_port()
+			.start()
+			.unwrap()
+	};
+
+	let first = server(0);
+	let addr = first.tcp_addrs("echo")[0];
+
+	// Another server can listen on the same port, and keeps serving alone once
+	// the first one stops
+	let second = server(addr.port());
+	assert_eq!(second.tcp_addrs("echo"), [addr]);
+	first.shutdown_blocking();
+
+	for _ in 0..4 {
+		let mut tcp = TcpStream::connect_timeout(&addr, Duration::from_secs(1)).unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		tcp.write_all(b"Hello, World!").unwrap();
+		let mut buf = [0; 13];
+		tcp.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"Hello, World!");
+	}
+
+	second.shutdown_blocking();
+}
+
+#[test]
+fn metrics() {
+	let server = Server::new()
+		.service("qotd")
+		.service("message")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.metrics(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+		.start()
+		.unwrap();
+
+	let metrics_addr = server.metrics_addr().unwrap();
+	assert_ne!(metrics_addr.port(), 0);
+
+	let scrape = |path: &str| {
+		let mut tcp = TcpStream::connect_timeout(&metrics_addr, Duration::from_secs(1)).unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+		let mut res = String::new();
+		tcp.read_to_string(&mut res).unwrap();
+		res
+	};
+
+	let value = |metrics: &str, name: &str| {
+		metrics
+			.lines()
+			.find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+			.map(|value| value.parse::<f64>().unwrap())
+	};
+
+	let mut tcp =
+		TcpStream::connect_timeout(&server.tcp_addrs("qotd")[0], Duration::from_secs(1)).unwrap();
+	tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	let mut quote = Vec::new();
+	tcp.read_to_end(&mut quote).unwrap();
+
+	let udp = UdpSocket::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap();
+	udp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+	udp.send_to(b"X", server.udp_addrs("message")[0]).unwrap();
+
+	// The datagram and closing the connection are handled asynchronously, so
+	// wait for them to be counted
+	let mut metrics = String::new();
+	for _ in 0..50 {
+		metrics = scrape("/metrics");
+		if metrics.contains("kind=\"invalid_message\"")
+			&& metrics.contains(
+				"simple_protocols_connections_closed_total{service=\"qotd\",transport=\"tcp\"} 1",
+			) {
+			break;
+		}
+		std::thread::sleep(Duration::from_millis(20));
+	}
+
+	assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+	assert!(metrics.contains("Content-Type: text/plain; version=0.0.4"));
+
+	let qotd = "{service=\"qotd\",transport=\"tcp\"}";
+	assert_eq!(
+		value(
+			&metrics,
+			&format!("simple_protocols_connections_accepted_total{qotd}")
+		),
+		Some(1.0)
+	);
+	assert_eq!(
+		value(
+			&metrics,
+			&format!("simple_protocols_connections_closed_total{qotd}")
+		),
+		Some(1.0)
+	);
+	assert_eq!(
+		value(
+			&metrics,
+			&format!("simple_protocols_connections_active{qotd}")
+		),
+		Some(0.0)
+	);
+	assert_eq!(
+		value(
+			&metrics,
+			&format!("simple_protocols_sent_bytes_total{qotd}")
+		),
+		Some(quote.len() as f64)
+	);
+	assert_eq!(
+		value(
+			&metrics,
+			"simple_protocols_session_duration_seconds_count{service=\"qotd\",transport=\"tcp\"}"
+		),
+		Some(1.0)
+	);
+
+	let message = "{service=\"message\",transport=\"udp\"}";
+	assert_eq!(
+		value(
+			&metrics,
+			&format!("simple_protocols_datagrams_received_total{message}")
+		),
+		Some(1.0)
+	);
+	assert_eq!(
+		value(
+			&metrics,
+			&format!("simple_protocols_received_bytes_total{message}")
+		),
+		Some(1.0)
+	);
+	assert_eq!(
+		value(
+			&metrics,
+			"simple_protocols_errors_total{service=\"message\",transport=\"udp\",kind=\"\
+			 invalid_message\"}"
+		),
+		Some(1.0)
+	);
+
+	assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn content_dir() {
+	let dir = std::env::temp_dir().join(format!("simple-protocols-{}-content", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(dir.join("docs")).unwrap();
+	std::fs::write(dir.join("docs/hello.txt"), "Hello, Gopher!\r\n").unwrap();
+	std::fs::write(dir.join("docs/data.bin"), b"\0\x01\x02").unwrap();
+
+	assert!(
+		Server::new()
+			.service("gopher")
+			.hostname("localhost")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports()
+			.content_dir(dir.join("nothing"))
+			.start()
+			.is_err()
+	);
+
+	let server = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.content_dir(&dir)
+		.start()
+		.unwrap();
+
+	let select = |selector: &str| {
+		let mut tcp =
+			TcpStream::connect_timeout(&server.tcp_addrs("gopher")[0], Duration::from_secs(1))
+				.unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "{selector}\r\n").unwrap();
+
+		let mut res = Vec::new();
+		tcp.read_to_end(&mut res).unwrap();
+		res
+	};
+
+	let menu = |selector: &str| String::from_utf8(select(selector)).unwrap();
+
+	// Entries are listed with the date they were last modified, and files also
+	// with their size
+	let root = menu("");
+	assert!(root.starts_with("1docs ("));
+	assert!(root.contains("\t/docs\tlocalhost\t"));
+
+	let docs = menu("/docs");
+	let docs = docs.lines().collect::<Vec<_>>();
+	assert_eq!(docs.len(), 3);
+	assert!(docs[0].starts_with("9data.bin (3 B, "));
+	assert!(docs[0].contains("\t/docs/data.bin\tlocalhost\t"));
+	assert!(docs[1].starts_with("0hello.txt (16 B, "));
+	assert!(docs[1].contains("\t/docs/hello.txt\tlocalhost\t"));
+
+	assert_eq!(select("/docs/hello.txt"), b"Hello, Gopher!\r\n.\r\n");
+	assert_eq!(select("/docs/data.bin"), b"\0\x01\x02");
+	assert!(menu("/src/fs.rs").starts_with("3not found\t"));
+	assert!(menu("/docs/../../etc/passwd").starts_with("3not found\t"));
+
+	server.shutdown_blocking();
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn binary_files() {
+	let server = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.start()
+		.unwrap();
+
+	let select = |selector: &str| {
+		let mut tcp =
+			TcpStream::connect_timeout(&server.tcp_addrs("gopher")[0], Duration::from_secs(1))
+				.unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "{selector}\r\n").unwrap();
+
+		let mut res = Vec::new();
+		tcp.read_to_end(&mut res).unwrap();
+		res
+	};
+
+	let data = String::from_utf8(select("/data")).unwrap();
+	assert!(data.lines().any(|line| {
+		line.starts_with("gpixel.gif (") && line.contains("\t/data/pixel.gif\tlocalhost\t")
+	}));
+	assert!(data.lines().any(|line| {
+		line.starts_with("0quotes.txt (") && line.contains("\t/data/quotes.txt\tlocalhost\t")
+	}));
+
+	// Binary files aren't terminated by a period
+	assert_eq!(
+		select("/data/pixel.gif"),
+		include_bytes!("../data/pixel.gif")
+	);
+}
+
+#[test]
+fn status_files() {
+	let server = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.start()
+		.unwrap();
+
+	let select = |selector: &str| {
+		let mut tcp =
+			TcpStream::connect_timeout(&server.tcp_addrs("gopher")[0], Duration::from_secs(1))
+				.unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "{selector}\r\n").unwrap();
+
+		let mut res = String::new();
+		tcp.read_to_string(&mut res).unwrap();
+		res
+	};
+
+	let menu = select("/.status");
+	for name in ["connections", "quote", "services", "uptime"] {
+		assert!(menu.lines().any(|line| {
+			line.starts_with(&format!("0{name} ("))
+				&& line.contains(&format!("\t/.status/{name}\tlocalhost\t"))
+		}));
+	}
+
+	assert!(
+		select("/.status/services")
+			.lines()
+			.any(|line| line == "gopher tcp")
+	);
+
+	// The connection reading the file is open itself
+	let connections = select("/.status/connections");
+	let gopher = connections
+		.lines()
+		.find(|line| line.starts_with("gopher tcp "))
+		.unwrap();
+	assert!(!gopher.starts_with("gopher tcp 0 open"));
+
+	let uptime = select("/.status/uptime");
+	assert!(
+		uptime
+			.trim_end_matches(".\r\n")
+			.trim()
+			.parse::<u64>()
+			.is_ok()
+	);
+	assert!(select("/.status/quote").ends_with("\n.\r\n"));
+	assert!(select("/.status/nothing").starts_with("3not found\t"));
+
+	server.shutdown_blocking();
+}
+
+#[test]
+fn search() {
+	let server = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.start()
+		.unwrap();
+
+	let select = |line: &str| {
+		let mut tcp =
+			TcpStream::connect_timeout(&server.tcp_addrs("gopher")[0], Duration::from_secs(1))
+				.unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "{line}\r\n").unwrap();
+
+		let mut res = String::new();
+		tcp.read_to_string(&mut res).unwrap();
+		res
+	};
+
+	// The search is advertised in the root menu, linking to the port bound
+	let port = server.tcp_addrs("gopher")[0].port();
+	let search = format!("7Search the files\tsearch\tlocalhost\t{port}");
+	assert!(select("").lines().any(|line| line == search));
+
+	let found = select("search\t\"Internet Gopher Protocol\" -qotd");
+	assert!(found.ends_with(".\r\n"));
+	assert!(found.lines().any(|line| {
+		line.starts_with("0/src/services/gopher.rs\t/src/services/gopher.rs\tlocalhost\t")
+	}));
+	assert!(!found.contains("/src/services/qotd.rs"));
+
+	assert_eq!(select("search\tgopher -gopher"), ".\r\n");
+	assert_eq!(select("search"), ".\r\n");
+
+	server.shutdown_blocking();
+}
+
+#[test]
+fn gophermap() {
+	let dir =
+		std::env::temp_dir().join(format!("simple-protocols-{}-gophermap", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	std::fs::create_dir_all(&dir).unwrap();
+	std::fs::write(dir.join("hello.txt"), "Hello, Gopher!\n").unwrap();
+	std::fs::write(
+		dir.join("gophermap"),
+		"Hi there\n0Say hello\thello.txt\n.\n*\n",
+	)
+	.unwrap();
+
+	let embedded = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.start()
+		.unwrap();
+	let content_dir = Server::new()
+		.service("gopher")
+		.hostname("localhost")
+		.ip(Ipv4Addr::LOCALHOST.into())
+		.ephemeral_ports()
+		.content_dir(&dir)
+		.start()
+		.unwrap();
+
+	let select = |server: &Running, selector: &str| {
+		let mut tcp =
+			TcpStream::connect_timeout(&server.tcp_addrs("gopher")[0], Duration::from_secs(1))
+				.unwrap();
+		tcp.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+		write!(tcp, "{selector}\r\n").unwrap();
+
+		let mut res = String::new();
+		tcp.read_to_string(&mut res).unwrap();
+		res
+	};
+
+	let data = select(&embedded, "/data");
+	let data = data.lines().collect::<Vec<_>>();
+	assert!(data[0].starts_with("iSample data of simple-protocols\t\tlocalhost\t"));
+	assert!(data.contains(&"1Floodgap\t/\tgopher.floodgap.com\t70"));
+	assert!(
+		data.iter()
+			.any(|line| line.starts_with("1Sources\t/data/../src\tlocalhost\t"))
+	);
+	// The listing is still there, without the gophermap itself
+	assert!(data.iter().any(|line| {
+		line.starts_with("0quotes.txt (") && line.contains("\t/data/quotes.txt\tlocalhost\t")
+	}));
+	assert!(!data.iter().any(|line| line.contains("/data/gophermap")));
+	assert_eq!(data.last(), Some(&"."));
+
+	// Selectors relative to the directory can be followed
+	assert!(select(&embedded, "/data/../src").contains("\t/data/../src/fs.rs\t"));
+
+	let root = select(&content_dir, "");
+	let root = root.lines().collect::<Vec<_>>();
+	assert_eq!(root.len(), 3);
+	let port = content_dir.tcp_addrs("gopher")[0].port();
+	assert_eq!(root[0], format!("iHi there\t\tlocalhost\t{port}"));
+	assert_eq!(
+		root[1],
+		format!("0Say hello\t/hello.txt\tlocalhost\t{port}")
+	);
+	assert_eq!(
+		select(&content_dir, "/gophermap"),
+		"Hi there\n0Say hello\thello.txt\n.\n*\n.\r\n"
+	);
+
+	embedded.shutdown_blocking();
+	content_dir.shutdown_blocking();
+	std::fs::remove_dir_all(&dir).unwrap();
+}
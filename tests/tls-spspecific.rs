@@ -0,0 +1,234 @@
+#![cfg(feature = "tls")]
+
+use std::{
+	fs,
+	io::Error as IoError,
+	net::{Ipv4Addr, SocketAddr},
+	path::PathBuf,
+	sync::Arc,
+};
+
+use futures_rustls::{
+	TlsConnector,
+	client::TlsStream,
+	rustls::{
+		ClientConfig, DigitallySignedStruct, Error as TlsError, RootCertStore, SignatureScheme,
+		client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+		crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+		pki_types::{CertificateDer, ServerName, UnixTime},
+	},
+};
+use simple_protocols::Server;
+use smol::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+
+/// Accepts any server certificate, for connecting to self-signed servers
+#[derive(Debug)]
+struct AcceptAny(CryptoProvider);
+
+impl ServerCertVerifier for AcceptAny {
+	fn verify_server_cert(
+		&self,
+		_: &CertificateDer<'_>,
+		_: &[CertificateDer<'_>],
+		_: &ServerName<'_>,
+		_: &[u8],
+		_: UnixTime,
+	) -> Result<ServerCertVerified, TlsError> {
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, TlsError> {
+		verify_tls12_signature(
+			message,
+			cert,
+			dss,
+			&self.0.signature_verification_algorithms,
+		)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, TlsError> {
+		verify_tls13_signature(
+			message,
+			cert,
+			dss,
+			&self.0.signature_verification_algorithms,
+		)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.0.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+/// Generate a self-signed certificate for `name`, returning the certificate
+/// and the paths of its PEM certificate and key files
+fn generate(name: &str) -> (CertificateDer<'static>, PathBuf, PathBuf) {
+	let generated = rcgen::generate_simple_self_signed([name.to_string()]).unwrap();
+	let dir = std::env::temp_dir();
+	let prefix = format!("simple-protocols-{}-{name}", std::process::id());
+	let cert = dir.join(format!("{prefix}.crt"));
+	let key = dir.join(format!("{prefix}.key"));
+
+	fs::write(&cert, generated.cert.pem()).unwrap();
+	fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+	(generated.cert.der().clone(), cert, key)
+}
+
+async fn connect(
+	addr: SocketAddr,
+	server_name: &str,
+	root: Option<&CertificateDer<'static>>,
+	alpn: &[&str],
+) -> Result<TlsStream<TcpStream>, IoError> {
+	let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.unwrap();
+
+	let mut config = match root {
+		Some(root) => {
+			let mut roots = RootCertStore::empty();
+			roots.add(root.clone()).unwrap();
+			builder.with_root_certificates(roots)
+		}
+		None => builder
+			.dangerous()
+			.with_custom_certificate_verifier(Arc::new(AcceptAny(ring::default_provider()))),
+	}
+	.with_no_client_auth();
+
+	config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+
+	TlsConnector::from(Arc::new(config))
+		.connect(
+			ServerName::try_from(server_name.to_string()).unwrap(),
+			TcpStream::connect(addr).await?,
+		)
+		.await
+}
+
+#[test]
+fn sni_and_alpn() {
+	smol::block_on(async {
+		let (localhost, localhost_cert, localhost_key) = generate("localhost");
+		let (example, example_cert, example_key) = generate("example.test");
+
+		let server = Server::new()
+			.service("echo")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports()
+			.tls_port("echo", 0)
+			.tls_cert(None, &localhost_cert, &localhost_key)
+			.tls_cert(Some("example.test"), &example_cert, &example_key)
+			.tls_alpn("echo", &["echo", "x-test"])
+			.start()
+			.unwrap();
+
+		let addr = server.tls_addrs("echo")[0];
+		assert_ne!(addr, server.tcp_addrs("echo")[0]);
+
+		let mut tls = connect(addr, "localhost", Some(&localhost), &["echo"])
+			.await
+			.unwrap();
+		assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"echo"[..]));
+
+		let mut buf = vec![0; 1024];
+		tls.write_all(b"Hello, World!").await.unwrap();
+		tls.flush().await.unwrap();
+		let n = tls.read(&mut buf).await.unwrap();
+		assert_eq!(&buf[..n], b"Hello, World!");
+
+		// The certificate is selected by the requested server name
+		assert!(
+			connect(addr, "example.test", Some(&example), &[])
+				.await
+				.is_ok()
+		);
+		assert!(
+			connect(addr, "example.test", Some(&localhost), &[])
+				.await
+				.is_err()
+		);
+		assert!(
+			connect(addr, "other.test", Some(&localhost), &[])
+				.await
+				.is_err()
+		);
+
+		// Clients only supporting other protocols are rejected
+		assert!(connect(addr, "localhost", None, &["h2"]).await.is_err());
+
+		server.shutdown().await;
+
+		for path in [localhost_cert, localhost_key, example_cert, example_key] {
+			fs::remove_file(path).unwrap();
+		}
+	});
+}
+
+#[test]
+fn self_signed() {
+	smol::block_on(async {
+		let server = Server::new()
+			.service("daytime")
+			.service("gopher")
+			.hostname("localhost")
+			.ip(Ipv4Addr::LOCALHOST.into())
+			.ephemeral_ports()
+			.tls_port("daytime", 0)
+			.tls_port("gopher", 0)
+			.start()
+			.unwrap();
+
+		let mut tls = connect(server.tls_addrs("daytime")[0], "localhost", None, &[])
+			.await
+			.unwrap();
+
+		let mut buf = Vec::new();
+		tls.read_to_end(&mut buf).await.unwrap();
+		assert!(!buf.is_empty());
+
+		let mut tls = connect(server.tls_addrs("gopher")[0], "localhost", None, &[])
+			.await
+			.unwrap();
+
+		let mut buf = Vec::new();
+		tls.write_all(b"\r\n").await.unwrap();
+		tls.flush().await.unwrap();
+		tls.read_to_end(&mut buf).await.unwrap();
+		assert!(buf.ends_with(b".\r\n"));
+
+		// Menus served over TLS link to the TLS port
+		let port = server.tls_addrs("gopher")[0].port();
+		assert_ne!(port, server.tcp_addrs("gopher")[0].port());
+		let menu = String::from_utf8(buf).unwrap();
+		let items = menu
+			.lines()
+			.filter(|line| line.contains('\t'))
+			.collect::<Vec<_>>();
+		assert!(!items.is_empty());
+		assert!(
+			items
+				.iter()
+				.all(|line| line.ends_with(&format!("\tlocalhost\t{port}")))
+		);
+
+		// Services without a TLS port aren't served over TLS
+		assert!(server.tls_addrs("echo").is_empty());
+
+		server.shutdown().await;
+	});
+}